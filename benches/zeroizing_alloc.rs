@@ -0,0 +1,40 @@
+//! Benchmarks of large deallocations with and without discarding of memory
+//! pages. Requires the `nightly` crate feature (and a nightly compiler).
+#![cfg_attr(feature = "nightly", feature(test))]
+#![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+
+#[cfg(feature = "nightly")]
+mod benches {
+    extern crate test;
+
+    use secmem_alloc::allocator_api::Vec;
+    use secmem_alloc::zeroizing_alloc::ZeroizeAlloc;
+    use std::alloc::System;
+    use test::Bencher;
+
+    /// Size of the benchmarked allocations: 64 MiB.
+    const SIZE: usize = 64 << 20;
+
+    fn bench_vec_dealloc(b: &mut Bencher, allocator: &ZeroizeAlloc<System>) {
+        b.iter(|| {
+            let mut vec = Vec::<u8, _>::with_capacity_in(SIZE, allocator);
+            vec.resize(SIZE, 0xAF);
+            test::black_box(&vec);
+            // drop `vec`
+        });
+    }
+
+    #[bench]
+    fn vec_64mib_zeroize(b: &mut Bencher) {
+        let allocator = ZeroizeAlloc::new(System);
+        bench_vec_dealloc(b, &allocator);
+    }
+
+    #[bench]
+    fn vec_64mib_page_discard(b: &mut Bencher) {
+        // SAFETY: the system allocator hands out private anonymous memory for large
+        // allocations
+        let allocator = unsafe { ZeroizeAlloc::new(System).with_page_discard(true) };
+        bench_vec_dealloc(b, &allocator);
+    }
+}
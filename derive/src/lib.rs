@@ -0,0 +1,56 @@
+//! Derive macros for the [`secmem-alloc`](https://crates.io/crates/secmem-alloc)
+//! crate. Use them through the `derive` feature of `secmem-alloc`, rather than
+//! depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, parse_macro_input, parse_quote};
+
+/// Derive `secmem_alloc::Zeroable` for a struct or union.
+///
+/// The all zeros bit pattern is a valid value of a struct or union iff it is
+/// so for all it's fields. Therefore, the generated implementation requires
+/// all field types to implement `Zeroable`. Enums are not supported.
+#[proc_macro_derive(Zeroable)]
+pub fn derive_zeroable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match derive_zeroable_impl(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn derive_zeroable_impl(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields: Vec<syn::Field> = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().cloned().collect(),
+            Fields::Unnamed(fields) => fields.unnamed.iter().cloned().collect(),
+            Fields::Unit => Vec::new(),
+        },
+        Data::Union(data) => data.fields.named.iter().cloned().collect(),
+        Data::Enum(_) => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "`Zeroable` can not be derived for enums",
+            ));
+        },
+    };
+
+    // require all field types to be `Zeroable`
+    let where_clause = input.generics.make_where_clause();
+    for field in fields {
+        let ty = field.ty;
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::secmem_alloc::Zeroable));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        // SAFETY: all fields are `Zeroable`, so the all zeros bit pattern is a valid
+        // value of the type
+        unsafe impl #impl_generics ::secmem_alloc::Zeroable for #ident #ty_generics #where_clause {}
+    })
+}
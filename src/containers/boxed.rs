@@ -0,0 +1,188 @@
+//! Owned secret value on the heap.
+
+use super::{ConstantTimeEq, DefaultSecAlloc, Redacted};
+use crate::zeroize::zeroize_mem;
+use alloc::alloc::handle_alloc_error;
+use allocator_api2::alloc::{AllocError, Allocator};
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// An owned secret value of type `T`, allocated in the allocator `A`.
+///
+/// See the [module level documentation](super) for the security properties.
+/// The memory is zeroized on drop, after the value is dropped.
+pub struct SecBox<T, A: Allocator = DefaultSecAlloc> {
+    /// Pointer to the value; dangling if `T` is zero sized.
+    ptr: NonNull<T>,
+    /// Allocator used to allocate the value.
+    alloc: A,
+    /// This type owns a `T`.
+    _phantom_val: PhantomData<T>,
+}
+
+// SAFETY: `SecBox` owns it's value and allocator like a `Box`
+unsafe impl<T: Send, A: Allocator + Send> Send for SecBox<T, A> {}
+// SAFETY: `SecBox` owns it's value and allocator like a `Box`
+unsafe impl<T: Sync, A: Allocator + Sync> Sync for SecBox<T, A> {}
+
+impl<T, A: Allocator> SecBox<T, A> {
+    /// Move `val` to memory allocated in `alloc`.
+    ///
+    /// # Errors
+    /// Returns an error if the allocation fails.
+    pub fn try_new_in(val: T, alloc: A) -> Result<Self, AllocError> {
+        let layout = Layout::new::<T>();
+        let ptr: NonNull<T> = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            alloc.allocate(layout)?.cast()
+        };
+        // SAFETY: `ptr` is valid for writes of a `T` (no-op if zero sized) and
+        // properly aligned since it was allocated with the layout of `T`
+        unsafe { ptr.as_ptr().write(val) };
+        Ok(Self {
+            ptr,
+            alloc,
+            _phantom_val: PhantomData,
+        })
+    }
+
+    /// Move `val` to memory allocated in `alloc`.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`] if the allocation fails.
+    pub fn new_in(val: T, alloc: A) -> Self {
+        match Self::try_new_in(val, alloc) {
+            Ok(sec_box) => sec_box,
+            Err(AllocError) => handle_alloc_error(Layout::new::<T>()),
+        }
+    }
+
+    /// Returns a reference to the allocator of the box.
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Move the value out of the box, zeroizing and deallocating the memory.
+    pub fn into_inner(self) -> T {
+        let this = core::mem::ManuallyDrop::new(self);
+        // SAFETY: `this.ptr` points to a valid `T`, which is not used afterwards
+        let val = unsafe { this.ptr.as_ptr().read() };
+        // SAFETY: the value has been moved out, and `this` is not used afterwards
+        unsafe { this.zeroize_dealloc() };
+        // SAFETY: `this.alloc` is valid and not used afterwards
+        drop(unsafe { core::ptr::read(&this.alloc) });
+        val
+    }
+
+    /// Zeroize and deallocate the memory of the box.
+    ///
+    /// # Safety
+    /// The value must already be dropped or moved out, and the memory must not
+    /// be used afterwards.
+    unsafe fn zeroize_dealloc(&self) {
+        let layout = Layout::new::<T>();
+        if layout.size() != 0 {
+            // SAFETY: `self.ptr` is valid for writes of `layout.size()` bytes, and the
+            // value is dropped so the memory is unused
+            unsafe { zeroize_mem(self.ptr.as_ptr().cast(), layout.size()) };
+            // SAFETY: `self.ptr` was allocated with `self.alloc` and `layout`
+            unsafe { self.alloc.deallocate(self.ptr.cast(), layout) };
+        }
+    }
+}
+
+impl<T, A: Allocator + Default> SecBox<T, A> {
+    /// Move `val` to memory allocated in the default allocator of type `A`.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`] if the allocation fails.
+    pub fn new(val: T) -> Self {
+        Self::new_in(val, A::default())
+    }
+}
+
+impl<T, A: Allocator> Drop for SecBox<T, A> {
+    fn drop(&mut self) {
+        // SAFETY: `self.ptr` points to a valid `T`, which is not used afterwards
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        // SAFETY: the value is dropped, and the memory is not used afterwards
+        unsafe { self.zeroize_dealloc() };
+    }
+}
+
+impl<T, A: Allocator> Deref for SecBox<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: `self.ptr` points to a valid `T` owned by `self`
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, A: Allocator> DerefMut for SecBox<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: `self.ptr` points to a valid `T` owned by `self`
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: Clone, A: Allocator + Clone> Clone for SecBox<T, A> {
+    fn clone(&self) -> Self {
+        Self::new_in(T::clone(self), self.alloc.clone())
+    }
+}
+
+impl<T, A: Allocator> fmt::Debug for SecBox<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecBox").field(&Redacted).finish()
+    }
+}
+
+impl<T: ConstantTimeEq, A: Allocator, B: Allocator> PartialEq<SecBox<T, B>> for SecBox<T, A> {
+    fn eq(&self, other: &SecBox<T, B>) -> bool {
+        T::ct_eq(self, other)
+    }
+}
+
+impl<T: ConstantTimeEq, A: Allocator> Eq for SecBox<T, A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_alloc::SecStackSinglePageAlloc;
+    use std::format;
+
+    #[test]
+    fn box_new_default_alloc() {
+        let mut key: SecBox<[u8; 32]> = SecBox::new([1; 32]);
+        key[3] = 7;
+        assert_eq!(key[..4], [1, 1, 1, 7]);
+    }
+
+    #[test]
+    fn box_zst() {
+        let unit: SecBox<()> = SecBox::new(());
+        assert_eq!(unit.into_inner(), ());
+    }
+
+    #[test]
+    fn box_clone_eq_sec_alloc() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let key = SecBox::new_in([0xAF_u8; 32], &allocator);
+        let key2 = key.clone();
+        assert_eq!(key, key2);
+        let key3 = SecBox::new_in([0xAE_u8; 32], &allocator);
+        assert_ne!(key, key3);
+        assert_eq!(key3.into_inner(), [0xAE; 32]);
+    }
+
+    #[test]
+    fn box_debug_redacted() {
+        let key: SecBox<u64> = SecBox::new(0x1234_5678);
+        assert_eq!(format!("{key:?}"), "SecBox(<redacted>)");
+    }
+}
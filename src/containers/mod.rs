@@ -0,0 +1,123 @@
+//! Owned container types for secret data.
+//!
+//! This module contains the containers [`SecBox`], [`SecVec`] and
+//! [`SecString`], which are parameterised over an allocator, defaulting to
+//! [`DefaultSecAlloc`]. In addition to what the allocator provides, these
+//! containers:
+//! - zeroize their whole capacity on drop, including memory which is no longer
+//!   (or not yet) initialised, and memory that is released on reallocation,
+//!   independent of the allocator used;
+//! - redact their contents in their [`Debug`](core::fmt::Debug)
+//!   implementations, and do not implement [`Display`](core::fmt::Display);
+//! - compare in constant time (with respect to the contents) in their
+//!   [`PartialEq`] implementations, see [`ConstantTimeEq`];
+//! - allocate clones in (a clone of) the same allocator.
+//!
+//! To keep secrets out of swap, use the containers with a
+//! [`SecStackSinglePageAlloc`](crate::sec_alloc::SecStackSinglePageAlloc).
+
+use crate::zeroizing_alloc::ZeroizeAlloc;
+use allocator_api2::alloc::Global;
+use core::fmt;
+
+mod boxed;
+mod string;
+mod vec;
+
+pub use boxed::SecBox;
+pub use string::SecString;
+pub use vec::SecVec;
+
+/// Allocator used by the containers in this module by default.
+pub type DefaultSecAlloc = ZeroizeAlloc<Global>;
+
+/// Equality comparison which runs in constant time with respect to the
+/// compared values.
+///
+/// The running time may depend on the lengths of compared slices, but not on
+/// their contents.
+pub trait ConstantTimeEq {
+    /// Returns `true` iff `self` and `other` are equal, in constant time.
+    fn ct_eq(&self, other: &Self) -> bool;
+}
+
+macro_rules! impl_ct_eq_int {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl ConstantTimeEq for $ty {
+                fn ct_eq(&self, other: &Self) -> bool {
+                    // a comparison with zero compiles to a branchless `setcc` or
+                    // equivalent on all relevant architectures
+                    core::hint::black_box(self ^ other) == 0
+                }
+            }
+        )*
+    };
+}
+
+impl_ct_eq_int!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize,
+);
+
+impl ConstantTimeEq for bool {
+    fn ct_eq(&self, other: &Self) -> bool {
+        u8::from(*self).ct_eq(&u8::from(*other))
+    }
+}
+
+impl ConstantTimeEq for char {
+    fn ct_eq(&self, other: &Self) -> bool {
+        u32::from(*self).ct_eq(&u32::from(*other))
+    }
+}
+
+impl<T: ConstantTimeEq> ConstantTimeEq for [T] {
+    fn ct_eq(&self, other: &Self) -> bool {
+        // the lengths are not secret
+        if self.len() != other.len() {
+            return false;
+        }
+        // accumulate the differences without early return; `black_box` prevents
+        // the compiler from introducing one
+        let mut diff: u8 = 0;
+        for (a, b) in self.iter().zip(other) {
+            diff = core::hint::black_box(diff | u8::from(!a.ct_eq(b)));
+        }
+        diff == 0
+    }
+}
+
+impl<T: ConstantTimeEq, const N: usize> ConstantTimeEq for [T; N] {
+    fn ct_eq(&self, other: &Self) -> bool {
+        self[..].ct_eq(&other[..])
+    }
+}
+
+impl ConstantTimeEq for str {
+    fn ct_eq(&self, other: &Self) -> bool {
+        self.as_bytes().ct_eq(other.as_bytes())
+    }
+}
+
+/// Placeholder printed instead of secret contents in `Debug` implementations.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ct_eq_slices() {
+        assert!([1u8, 2, 3].ct_eq(&[1, 2, 3]));
+        assert!(![1u8, 2, 3].ct_eq(&[1, 2, 4]));
+        assert!(![1u64, 2][..].ct_eq(&[1, 2, 3][..]));
+        assert!("secret".ct_eq("secret"));
+        assert!(!"secret".ct_eq("Secret"));
+    }
+}
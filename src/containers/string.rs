@@ -0,0 +1,280 @@
+//! Growable UTF-8 string for secret text.
+
+use super::{ConstantTimeEq, DefaultSecAlloc, Redacted, SecVec};
+use allocator_api2::alloc::{AllocError, Allocator};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// A growable UTF-8 encoded secret string, allocated in the allocator `A`.
+///
+/// See the [module level documentation](super) for the security properties.
+/// This is a thin wrapper around a [`SecVec<u8, A>`], so memory released by
+/// reallocation, removed characters and the whole capacity on drop are
+/// zeroized.
+pub struct SecString<A: Allocator = DefaultSecAlloc> {
+    /// UTF-8 encoded contents of the string.
+    vec: SecVec<u8, A>,
+}
+
+impl<A: Allocator> SecString<A> {
+    /// Create a new, empty string in the allocator `alloc`.
+    ///
+    /// The string will not allocate until text is pushed onto it.
+    pub const fn new_in(alloc: A) -> Self {
+        Self {
+            vec: SecVec::new_in(alloc),
+        }
+    }
+
+    /// Create a new, empty string in the allocator `alloc` with space for at
+    /// least `capacity` bytes.
+    ///
+    /// # Panics
+    /// Panics if the capacity exceeds `isize::MAX` bytes, and aborts using
+    /// [`handle_alloc_error`](alloc::alloc::handle_alloc_error) if the
+    /// allocation fails.
+    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
+        Self {
+            vec: SecVec::with_capacity_in(capacity, alloc),
+        }
+    }
+
+    /// Create a string in the allocator `alloc` containing a copy of `s`.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`](alloc::alloc::handle_alloc_error)
+    /// if the allocation fails.
+    pub fn from_str_in(s: &str, alloc: A) -> Self {
+        let mut string = Self::with_capacity_in(s.len(), alloc);
+        string.push_str(s);
+        string
+    }
+
+    /// Returns a reference to the allocator of the string.
+    pub fn allocator(&self) -> &A {
+        self.vec.allocator()
+    }
+
+    /// Returns the length of the string in bytes.
+    pub const fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// Returns `true` iff the string is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Returns the capacity of the string in bytes.
+    pub const fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    /// Extracts a string slice containing the entire string.
+    pub fn as_str(&self) -> &str {
+        // SAFETY: `self.vec` always contains valid UTF-8
+        unsafe { core::str::from_utf8_unchecked(self.vec.as_slice()) }
+    }
+
+    /// Extracts a mutable string slice containing the entire string.
+    pub fn as_mut_str(&mut self) -> &mut str {
+        // SAFETY: `self.vec` always contains valid UTF-8
+        unsafe { core::str::from_utf8_unchecked_mut(self.vec.as_mut_slice()) }
+    }
+
+    /// Returns the bytes of the string.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.vec.as_slice()
+    }
+
+    /// Reserve capacity for at least `additional` more bytes.
+    ///
+    /// # Errors
+    /// Returns an error if the required capacity exceeds `isize::MAX` bytes or
+    /// if the allocation fails. The string is unchanged in this case.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        self.vec.try_reserve(additional)
+    }
+
+    /// Reserve capacity for at least `additional` more bytes.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`](alloc::alloc::handle_alloc_error) if the
+    /// allocation fails.
+    pub fn reserve(&mut self, additional: usize) {
+        self.vec.reserve(additional);
+    }
+
+    /// Shrink the capacity of the string as much as possible.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`](alloc::alloc::handle_alloc_error)
+    /// if the reallocation fails.
+    pub fn shrink_to_fit(&mut self) {
+        self.vec.shrink_to_fit();
+    }
+
+    /// Append the string slice `s` to the back of the string.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`](alloc::alloc::handle_alloc_error) if the
+    /// allocation fails.
+    pub fn push_str(&mut self, s: &str) {
+        self.vec.extend_from_slice(s.as_bytes());
+    }
+
+    /// Append the character `ch` to the back of the string.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`](alloc::alloc::handle_alloc_error) if the
+    /// allocation fails.
+    pub fn push(&mut self, ch: char) {
+        let mut buf = [0; 4];
+        self.push_str(ch.encode_utf8(&mut buf));
+        crate::zeroize_val(&mut buf);
+    }
+
+    /// Remove the last character from the string and return it, or `None` if
+    /// it is empty.
+    ///
+    /// The memory of the removed character is zeroized.
+    pub fn pop(&mut self) -> Option<char> {
+        let ch = self.as_str().chars().next_back()?;
+        self.vec.truncate(self.len() - ch.len_utf8());
+        Some(ch)
+    }
+
+    /// Shorten the string to `len` bytes, zeroizing the rest.
+    ///
+    /// Has no effect if `len` is larger than the current length.
+    ///
+    /// # Panics
+    /// Panics if `len` does not lie on a `char` boundary.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            assert!(
+                self.as_str().is_char_boundary(len),
+                "new length does not lie on a char boundary"
+            );
+            self.vec.truncate(len);
+        }
+    }
+
+    /// Remove all contents of the string, zeroizing them.
+    ///
+    /// The capacity is unchanged.
+    pub fn clear(&mut self) {
+        self.vec.clear();
+    }
+}
+
+impl<A: Allocator + Default> SecString<A> {
+    /// Create a new, empty string in the default allocator of type `A`.
+    ///
+    /// The string will not allocate until text is pushed onto it.
+    pub fn new() -> Self {
+        Self::new_in(A::default())
+    }
+}
+
+impl<A: Allocator + Default> From<&str> for SecString<A> {
+    /// Create a string in the default allocator of type `A` containing a copy
+    /// of `s`.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`](alloc::alloc::handle_alloc_error)
+    /// if the allocation fails.
+    fn from(s: &str) -> Self {
+        Self::from_str_in(s, A::default())
+    }
+}
+
+impl<A: Allocator + Default> Default for SecString<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Allocator> Deref for SecString<A> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<A: Allocator> DerefMut for SecString<A> {
+    fn deref_mut(&mut self) -> &mut str {
+        self.as_mut_str()
+    }
+}
+
+impl<A: Allocator + Clone> Clone for SecString<A> {
+    fn clone(&self) -> Self {
+        Self {
+            vec: self.vec.clone(),
+        }
+    }
+}
+
+impl<A: Allocator> fmt::Debug for SecString<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SecString").field(&Redacted).finish()
+    }
+}
+
+impl<A: Allocator, B: Allocator> PartialEq<SecString<B>> for SecString<A> {
+    fn eq(&self, other: &SecString<B>) -> bool {
+        self.as_str().ct_eq(other.as_str())
+    }
+}
+
+impl<A: Allocator> Eq for SecString<A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_alloc::SecStackSinglePageAlloc;
+    use std::format;
+
+    #[test]
+    fn string_push_pop() {
+        let mut pw: SecString = SecString::from("hunter");
+        pw.push('2');
+        pw.push('€');
+        assert_eq!(pw.as_str(), "hunter2€");
+        assert_eq!(pw.pop(), Some('€'));
+        pw.truncate(6);
+        assert_eq!(&*pw, "hunter");
+        pw.clear();
+        assert_eq!(pw.pop(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn string_truncate_non_char_boundary() {
+        let mut s: SecString = SecString::from("€");
+        s.truncate(1);
+    }
+
+    #[test]
+    fn string_clone_eq_sec_alloc() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let pw = SecString::from_str_in("correct horse battery staple", &allocator);
+        let pw2 = pw.clone();
+        assert_eq!(pw, pw2);
+        assert_ne!(
+            pw,
+            SecString::from_str_in("correct horse battery stapler", &allocator)
+        );
+    }
+
+    #[test]
+    fn string_debug_redacted() {
+        let pw: SecString = SecString::from("hunter2");
+        assert_eq!(format!("{pw:?}"), "SecString(<redacted>)");
+    }
+}
@@ -0,0 +1,445 @@
+//! Growable vector of secret values.
+
+use super::{ConstantTimeEq, DefaultSecAlloc, Redacted};
+use crate::zeroize::zeroize_mem;
+use alloc::alloc::handle_alloc_error;
+use allocator_api2::alloc::{AllocError, Allocator};
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// A contiguous growable array of secret values of type `T`, allocated in the
+/// allocator `A`.
+///
+/// See the [module level documentation](super) for the security properties.
+/// Unlike [`Vec`](alloc::vec::Vec), the old allocation is zeroized when the
+/// vector grows or shrinks, and the whole capacity is zeroized on drop.
+/// Elements removed from the vector (e.g. by [`pop`](Self::pop) or
+/// [`truncate`](Self::truncate)) are zeroized immediately.
+pub struct SecVec<T, A: Allocator = DefaultSecAlloc> {
+    /// Pointer to the allocation; dangling if the capacity is zero or `T` is
+    /// zero sized.
+    ptr: NonNull<T>,
+    /// Capacity of the allocation in elements; `usize::MAX` if `T` is zero
+    /// sized.
+    cap: usize,
+    /// Number of initialised elements.
+    len: usize,
+    /// Allocator used to allocate the elements.
+    alloc: A,
+    /// This type owns `T`s.
+    _phantom_val: PhantomData<T>,
+}
+
+// SAFETY: `SecVec` owns it's elements and allocator like a `Vec`
+unsafe impl<T: Send, A: Allocator + Send> Send for SecVec<T, A> {}
+// SAFETY: `SecVec` owns it's elements and allocator like a `Vec`
+unsafe impl<T: Sync, A: Allocator + Sync> Sync for SecVec<T, A> {}
+
+impl<T, A: Allocator> SecVec<T, A> {
+    const IS_ZST: bool = size_of::<T>() == 0;
+
+    /// Create a new, empty vector in the allocator `alloc`.
+    ///
+    /// The vector will not allocate until elements are pushed onto it.
+    pub const fn new_in(alloc: A) -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            cap: if Self::IS_ZST { usize::MAX } else { 0 },
+            len: 0,
+            alloc,
+            _phantom_val: PhantomData,
+        }
+    }
+
+    /// Create a new, empty vector in the allocator `alloc` with space for at
+    /// least `capacity` elements.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn with_capacity_in(capacity: usize, alloc: A) -> Self {
+        let mut vec = Self::new_in(alloc);
+        vec.reserve_exact(capacity);
+        vec
+    }
+
+    /// Returns a reference to the allocator of the vector.
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Returns the number of elements in the vector.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` iff the vector contains no elements.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of elements the vector can hold without reallocating.
+    pub const fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Extracts a slice containing the entire vector.
+    pub fn as_slice(&self) -> &[T] {
+        // SAFETY: `self.ptr` is valid for reads of `self.len` initialised elements
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Extracts a mutable slice containing the entire vector.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // SAFETY: `self.ptr` is valid for reads and writes of `self.len` initialised
+        // elements
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Reserve capacity for at least `additional` more elements.
+    ///
+    /// # Errors
+    /// Returns an error if the required capacity exceeds `isize::MAX` bytes or
+    /// if the allocation fails. The vector is unchanged in this case.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        let required = self.len.checked_add(additional).ok_or(AllocError)?;
+        if required <= self.cap {
+            return Ok(());
+        }
+        // amortised growth, like `Vec`
+        let new_cap = required.max(self.cap.saturating_mul(2)).max(4);
+        self.try_realloc(new_cap)
+    }
+
+    /// Reserve capacity for at least `additional` more elements.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn reserve(&mut self, additional: usize) {
+        if self.try_reserve(additional).is_err() {
+            self.handle_reserve_error(additional);
+        }
+    }
+
+    /// Reserve capacity for exactly `additional` more elements.
+    ///
+    /// # Errors
+    /// Returns an error if the required capacity exceeds `isize::MAX` bytes or
+    /// if the allocation fails. The vector is unchanged in this case.
+    pub fn try_reserve_exact(&mut self, additional: usize) -> Result<(), AllocError> {
+        let required = self.len.checked_add(additional).ok_or(AllocError)?;
+        if required <= self.cap {
+            return Ok(());
+        }
+        self.try_realloc(required)
+    }
+
+    /// Reserve capacity for exactly `additional` more elements.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        if self.try_reserve_exact(additional).is_err() {
+            self.handle_reserve_error(additional);
+        }
+    }
+
+    /// Panic or abort after a failed reservation of `additional` elements.
+    #[cold]
+    fn handle_reserve_error(&self, additional: usize) -> ! {
+        let layout = self
+            .len
+            .checked_add(additional)
+            .and_then(|cap| Layout::array::<T>(cap).ok());
+        match layout {
+            Some(layout) => handle_alloc_error(layout),
+            None => panic!("capacity overflow"),
+        }
+    }
+
+    /// Shrink the capacity of the vector as much as possible.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`] if the reallocation fails.
+    pub fn shrink_to_fit(&mut self) {
+        if self.len < self.cap && self.try_realloc(self.len).is_err() {
+            handle_alloc_error(Layout::array::<T>(self.len).expect("smaller than capacity"));
+        }
+    }
+
+    /// Move the elements to a new allocation of capacity `new_cap`, zeroize
+    /// and deallocate the old allocation.
+    ///
+    /// The vector is unchanged if the allocation fails.
+    ///
+    /// # Panics
+    /// Panics if `new_cap < self.len`.
+    fn try_realloc(&mut self, new_cap: usize) -> Result<(), AllocError> {
+        assert!(new_cap >= self.len);
+        if Self::IS_ZST {
+            // zero sized types have infinite capacity
+            return if new_cap <= self.cap {
+                Ok(())
+            } else {
+                Err(AllocError)
+            };
+        }
+        let new_ptr: NonNull<T> = if new_cap == 0 {
+            NonNull::dangling()
+        } else {
+            let new_layout = Layout::array::<T>(new_cap).map_err(|_| AllocError)?;
+            let new_ptr: NonNull<T> = self.alloc.allocate(new_layout)?.cast();
+            // SAFETY: the old allocation contains `self.len` initialised elements, the
+            // new allocation has space for `new_cap >= self.len` elements, and the
+            // allocations don't overlap
+            unsafe {
+                core::ptr::copy_nonoverlapping(self.ptr.as_ptr(), new_ptr.as_ptr(), self.len);
+            }
+            new_ptr
+        };
+        // SAFETY: the elements are moved to the new allocation, so the old allocation
+        // is unused, and it is not used afterwards
+        unsafe { self.zeroize_dealloc() };
+        self.ptr = new_ptr;
+        self.cap = new_cap;
+        Ok(())
+    }
+
+    /// Zeroize and deallocate the current allocation.
+    ///
+    /// # Safety
+    /// The elements must already be dropped or moved out, and the allocation
+    /// must not be used afterwards.
+    unsafe fn zeroize_dealloc(&self) {
+        if Self::IS_ZST || self.cap == 0 {
+            return;
+        }
+        // the layout was valid when allocating
+        let layout = Layout::array::<T>(self.cap).expect("valid at allocation");
+        // SAFETY: `self.ptr` is valid for writes of `layout.size()` bytes and unused
+        unsafe { zeroize_mem(self.ptr.as_ptr().cast(), layout.size()) };
+        // SAFETY: `self.ptr` was allocated with `self.alloc` and `layout`
+        unsafe { self.alloc.deallocate(self.ptr.cast(), layout) };
+    }
+
+    /// Append `val` to the back of the vector.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn push(&mut self, val: T) {
+        if self.len == self.cap {
+            self.reserve(1);
+        }
+        // SAFETY: `self.len < self.cap`, so the slot is in bounds and unused
+        unsafe { self.ptr.as_ptr().add(self.len).write(val) };
+        self.len += 1;
+    }
+
+    /// Remove the last element from the vector and return it, or `None` if it
+    /// is empty.
+    ///
+    /// The memory of the removed element is zeroized.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        // SAFETY: the slot at `self.len` is initialised and no longer part of the
+        // vector
+        let slot: *mut T = unsafe { self.ptr.as_ptr().add(self.len) };
+        // SAFETY: `slot` points to an initialised element, which is moved out
+        let val = unsafe { slot.read() };
+        // SAFETY: `slot` is valid for writes of a `T`, and unused
+        unsafe { zeroize_mem(slot.cast(), size_of::<T>()) };
+        Some(val)
+    }
+
+    /// Shorten the vector to `len` elements, dropping and zeroizing the rest.
+    ///
+    /// Has no effect if `len` is larger than the current length.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        let old_len = self.len;
+        // set the length first, so the vector remains valid if a drop panics
+        self.len = len;
+        // SAFETY: the elements `len .. old_len` are initialised and no longer part of
+        // the vector
+        let tail: *mut [T] = unsafe {
+            core::ptr::slice_from_raw_parts_mut(self.ptr.as_ptr().add(len), old_len - len)
+        };
+        // SAFETY: the elements in `tail` are initialised and not used afterwards
+        unsafe { tail.drop_in_place() };
+        // SAFETY: `tail` is valid for writes, and its elements are dropped
+        unsafe { zeroize_mem(tail.cast(), (old_len - len) * size_of::<T>()) };
+    }
+
+    /// Remove all elements from the vector, dropping and zeroizing them.
+    ///
+    /// The capacity is unchanged.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<T: Clone, A: Allocator> SecVec<T, A> {
+    /// Clone and append all elements of `other` to the vector.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn extend_from_slice(&mut self, other: &[T]) {
+        self.reserve(other.len());
+        for val in other {
+            self.push(val.clone());
+        }
+    }
+}
+
+impl<T, A: Allocator + Default> SecVec<T, A> {
+    /// Create a new, empty vector in the default allocator of type `A`.
+    ///
+    /// The vector will not allocate until elements are pushed onto it.
+    pub fn new() -> Self {
+        Self::new_in(A::default())
+    }
+
+    /// Create a new, empty vector in the default allocator of type `A` with
+    /// space for at least `capacity` elements.
+    ///
+    /// # Panics
+    /// Panics if the required capacity exceeds `isize::MAX` bytes, and aborts
+    /// using [`handle_alloc_error`] if the allocation fails.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_in(capacity, A::default())
+    }
+}
+
+impl<T, A: Allocator + Default> Default for SecVec<T, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: Allocator> Drop for SecVec<T, A> {
+    fn drop(&mut self) {
+        self.clear();
+        // SAFETY: all elements are dropped, and the allocation is not used afterwards
+        unsafe { self.zeroize_dealloc() };
+    }
+}
+
+impl<T, A: Allocator> Deref for SecVec<T, A> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, A: Allocator> DerefMut for SecVec<T, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: Clone, A: Allocator + Clone> Clone for SecVec<T, A> {
+    fn clone(&self) -> Self {
+        let mut vec = Self::with_capacity_in(self.len, self.alloc.clone());
+        vec.extend_from_slice(self);
+        vec
+    }
+}
+
+impl<T, A: Allocator> fmt::Debug for SecVec<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecVec")
+            .field("len", &self.len)
+            .field("data", &Redacted)
+            .finish()
+    }
+}
+
+impl<T: ConstantTimeEq, A: Allocator, B: Allocator> PartialEq<SecVec<T, B>> for SecVec<T, A> {
+    fn eq(&self, other: &SecVec<T, B>) -> bool {
+        self.as_slice().ct_eq(other.as_slice())
+    }
+}
+
+impl<T: ConstantTimeEq, A: Allocator> Eq for SecVec<T, A> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sec_alloc::SecStackSinglePageAlloc;
+    use std::format;
+
+    #[test]
+    fn vec_push_pop() {
+        let mut vec: SecVec<u64> = SecVec::new();
+        for i in 0..100 {
+            vec.push(i);
+        }
+        assert_eq!(vec.len(), 100);
+        assert_eq!(vec.pop(), Some(99));
+        assert_eq!(vec[..3], [0, 1, 2]);
+        vec.truncate(10);
+        vec.shrink_to_fit();
+        assert_eq!(vec.capacity(), 10);
+        vec.clear();
+        assert!(vec.is_empty());
+        assert_eq!(vec.pop(), None);
+    }
+
+    #[test]
+    fn vec_zst() {
+        let mut vec: SecVec<()> = SecVec::new();
+        vec.push(());
+        vec.push(());
+        assert_eq!(vec.len(), 2);
+        assert_eq!(vec.pop(), Some(()));
+    }
+
+    #[test]
+    fn vec_sec_alloc_zeroized() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        {
+            let mut vec = SecVec::new_in(&allocator);
+            vec.extend_from_slice(&[0xAF_u8; 37]);
+            // forces several reallocations
+            vec.extend_from_slice(&[0xAE_u8; 300]);
+            let vec2 = vec.clone();
+            assert_eq!(vec, vec2);
+            vec.truncate(3);
+            assert_ne!(vec, vec2);
+        }
+        // in debug mode the allocator checks on drop that the page is zeroized
+        drop(allocator);
+    }
+
+    #[test]
+    fn vec_drops_elements() {
+        let rc = std::rc::Rc::new(());
+        let mut vec: SecVec<std::rc::Rc<()>> = SecVec::new();
+        vec.push(rc.clone());
+        vec.push(rc.clone());
+        vec.truncate(1);
+        assert_eq!(std::rc::Rc::strong_count(&rc), 2);
+        drop(vec);
+        assert_eq!(std::rc::Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn vec_debug_redacted() {
+        let mut vec: SecVec<u8> = SecVec::new();
+        vec.extend_from_slice(b"secret");
+        assert_eq!(format!("{vec:?}"), "SecVec { len: 6, data: <redacted> }");
+    }
+}
@@ -1,28 +1,216 @@
 //! Helper functions for allocating memory and working with memory pages.
 
+use crate::page_provider::{OsPageProvider, PageProvider, Protection};
 use core::ptr::NonNull;
 
-/// An single allocated page of memory.
-pub struct Page {
+/// An single allocated and locked page of memory (or a region of several
+/// pages, see [`Page::alloc_new_lock_len_in`]), allocated by the page provider
+/// `P`.
+pub struct Page<P: PageProvider = OsPageProvider> {
     /// Pointer to the start of the page.
     page_ptr: NonNull<u8>,
-    /// Size of a memory page.
+    /// Size of a memory page, or the length of the region of several pages.
     ///
-    /// It is not strictly necessary to store this as it is constant during the
-    /// entire execution of a process. This will therefore at all times
-    /// equal the result of `page_size`.
+    /// For single pages, it is not strictly necessary to store this as it is
+    /// constant during the entire execution of a process. This will therefore
+    /// at all times equal the result of `provider.page_size()`.
     page_size: usize,
-    /// This type owns a page of memory as raw bytes
-    _phantom_pagemem: core::marker::PhantomData<[u8]>,
+    /// Length of the locked part at the start of the region; equal to
+    /// `page_size` unless the region is reserved using
+    /// [`Page::alloc_new_reserve_len_in`].
+    locked_len: usize,
+    /// Length of the inaccessible guard pages directly before and after the
+    /// region, or 0 if the region has no guard pages.
+    guard_len: usize,
+    /// Page provider the page is allocated by.
+    provider: P,
 }
 
-impl Page {
-    /// Get [`NonNull`] pointer to the page.
-    pub fn page_ptr_nonnull(&self) -> NonNull<u8> {
-        self.page_ptr
+// SAFETY: `Page` uniquely owns it's memory page, like a `Box<[u8]>` owns it's
+// memory; memory mappings (and locks) are process wide, so the page can be
+// used and released from any thread when the provider can be sent
+unsafe impl<P: PageProvider + Send> Send for Page<P> {}
+
+impl<P: PageProvider> Page<P> {
+    /// Allocate a new page of memory using `provider` and lock it.
+    ///
+    /// When this function returns successfully then the memory page is
+    /// guarantied to be backed by physical memory, i.e. not (only) swapped
+    /// (as far as the provider allows). The page is registered in the
+    /// [registry](crate::registry) when it is enabled.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if allocating or
+    /// locking the page fails.
+    pub fn alloc_new_lock_in(provider: P) -> Result<Self, P::Error> {
+        let page_size = provider.page_size();
+        Self::alloc_new_lock_len_in(provider, page_size, false)
+    }
+
+    /// Allocate a new region of `len` bytes of memory pages using `provider`
+    /// and lock it, like [`Self::alloc_new_lock_in`]. `len` must be a non-zero
+    /// multiple of the page size of `provider`.
+    ///
+    /// If `guard_pages` is `true`, the region is surrounded by inaccessible
+    /// guard pages, see [`Self::alloc_guarded`].
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if allocating,
+    /// protecting or locking the pages fails.
+    ///
+    /// # Panics
+    /// Panics if adding the guard pages to `len` overflows.
+    pub fn alloc_new_lock_len_in(
+        provider: P,
+        len: usize,
+        guard_pages: bool,
+    ) -> Result<Self, P::Error> {
+        debug_assert!(len != 0 && len.is_multiple_of(provider.page_size()));
+        let page_size = len;
+        let (page_ptr, guard_len) = Self::alloc_guarded(&provider, page_size, guard_pages)?;
+        // SAFETY: `page_ptr` is allocated by `provider` with length `page_size`
+        if let Err(e) = unsafe { provider.lock(page_ptr, page_size) } {
+            // SAFETY: `page_ptr` is allocated by `alloc_guarded` with these lengths and
+            // not used afterwards
+            unsafe { Self::release_guarded(&provider, page_ptr, page_size, guard_len) };
+            return Err(e);
+        }
+        crate::registry::register(page_ptr.as_ptr(), page_size);
+        Ok(Self {
+            page_ptr,
+            page_size,
+            locked_len: page_size,
+            guard_len,
+            provider,
+        })
+    }
+
+    /// Reserve a region of `len` bytes of memory pages using `provider`,
+    /// without locking it. `len` must be a non-zero multiple of the page size
+    /// of `provider`.
+    ///
+    /// The region must be locked using [`Self::lock_on_fault_to`] before it
+    /// is used. Only the locked part of the region is registered in the
+    /// [registry](crate::registry).
+    ///
+    /// If `guard_pages` is `true`, the region is surrounded by inaccessible
+    /// guard pages, see [`Self::alloc_guarded`].
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if allocating or
+    /// protecting the pages fails.
+    ///
+    /// # Panics
+    /// Panics if adding the guard pages to `len` overflows.
+    pub fn alloc_new_reserve_len_in(
+        provider: P,
+        len: usize,
+        guard_pages: bool,
+    ) -> Result<Self, P::Error> {
+        debug_assert!(len != 0 && len.is_multiple_of(provider.page_size()));
+        let (page_ptr, guard_len) = Self::alloc_guarded(&provider, len, guard_pages)?;
+        crate::registry::register(page_ptr.as_ptr(), 0);
+        Ok(Self {
+            page_ptr,
+            page_size: len,
+            locked_len: 0,
+            guard_len,
+            provider,
+        })
+    }
+
+    /// Allocate a region of `len` bytes of memory pages using `provider`.
+    /// Returns a pointer to the region and the length of the guard pages.
+    ///
+    /// If `guard_pages` is `true`, a page is allocated directly before and
+    /// after the region, which is made inaccessible using
+    /// [`Protection::NoAccess`]. Out of bounds accesses right before or after
+    /// the region then crash the process, instead of reading or overwriting
+    /// the secrets in the region (or other memory). The guard pages are not
+    /// locked, so they only use address space.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if allocating or
+    /// protecting the pages fails.
+    ///
+    /// # Panics
+    /// Panics if adding the guard pages to `len` overflows.
+    fn alloc_guarded(
+        provider: &P,
+        len: usize,
+        guard_pages: bool,
+    ) -> Result<(NonNull<u8>, usize), P::Error> {
+        if !guard_pages {
+            return Ok((provider.allocate(len)?, 0));
+        }
+        let guard_len = provider.page_size();
+        let total_len = guard_len
+            .checked_mul(2)
+            .and_then(|guards| guards.checked_add(len))
+            .expect("capacity overflow");
+        let start = provider.allocate(total_len)?;
+        // SAFETY: `guard_len + len < total_len`, so both pointers lie in the
+        // allocated region
+        let (page_ptr, tail) = unsafe { (start.add(guard_len), start.add(guard_len + len)) };
+        for guard in [start, tail] {
+            // SAFETY: the guard page lies in the region allocated by `provider`, and
+            // is never accessed
+            if let Err(e) = unsafe { provider.protect(guard, guard_len, Protection::NoAccess) } {
+                // SAFETY: `start` is allocated by `provider` with length `total_len`
+                // and not used afterwards
+                unsafe { provider.release(start, total_len) };
+                return Err(e);
+            }
+        }
+        Ok((page_ptr, guard_len))
     }
 
-    /// Get the page size of the memory page.
+    /// Release the region `page_ptr .. page_ptr + len` allocated by
+    /// [`Self::alloc_guarded`], including it's guard pages of `guard_len`
+    /// bytes.
+    ///
+    /// # Safety
+    /// The region must be allocated by [`Self::alloc_guarded`] using
+    /// `provider`, with these lengths, and must not be used afterwards.
+    unsafe fn release_guarded(provider: &P, page_ptr: NonNull<u8>, len: usize, guard_len: usize) {
+        // SAFETY: the region including the guard pages is allocated by `provider`,
+        // by the safety contract
+        unsafe { provider.release(page_ptr.sub(guard_len), len + 2 * guard_len) };
+    }
+
+    /// Lock the region up to `len` bytes using
+    /// [`PageProvider::lock_on_fault`], so the pages are locked when they are
+    /// first accessed. `len` must be a multiple of the page size of the
+    /// provider, and at most the length of the region. Does nothing if the
+    /// region is already locked up to `len` bytes.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if locking the pages
+    /// fails. The locked length is unchanged in that case.
+    pub fn lock_on_fault_to(&mut self, len: usize) -> Result<(), P::Error> {
+        debug_assert!(len <= self.page_size && len.is_multiple_of(self.provider.page_size()));
+        if len <= self.locked_len {
+            return Ok(());
+        }
+        // SAFETY: `self.locked_len` is a multiple of the page size at most `len`,
+        // so the range lies within the pages allocated by `self.provider`
+        let ptr = unsafe { self.page_ptr.add(self.locked_len) };
+        // SAFETY: the pages are allocated by `self.provider` and not yet released
+        unsafe { self.provider.lock_on_fault(ptr, len - self.locked_len) }?;
+        self.locked_len = len;
+        crate::registry::update_len(self.as_ptr_mut(), len);
+        Ok(())
+    }
+
+    /// Get the length of the locked part at the start of the region. Equals
+    /// [`Self::page_size`] unless the region is reserved using
+    /// [`Self::alloc_new_reserve_len_in`].
+    pub fn locked_len(&self) -> usize {
+        self.locked_len
+    }
+
+    /// Get the page size of the memory page (the length of the region for
+    /// regions of several pages).
     pub fn page_size(&self) -> usize {
         self.page_size
     }
@@ -36,17 +224,179 @@ impl Page {
     pub fn as_ptr(&self) -> *const u8 {
         self.page_ptr.as_ptr() as *const u8
     }
+
+    /// Get the page provider the page is allocated by.
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+}
+
+impl<P: PageProvider> Drop for Page<P> {
+    fn drop(&mut self) {
+        crate::registry::unregister(self.as_ptr_mut());
+        // SAFETY: `self.page_ptr` is allocated by `alloc_guarded` using
+        // `self.provider` with these lengths, and not used afterwards
+        unsafe {
+            Self::release_guarded(
+                &self.provider,
+                self.page_ptr,
+                self.page_size,
+                self.guard_len,
+            );
+        };
+    }
 }
 
 cfg_if::cfg_if! {
     if #[cfg(miri)] {
         mod miri;
-        pub use miri::PageAllocError;
+        use miri as backend;
     } else if #[cfg(unix)] {
         mod unix;
-        pub use unix::PageAllocError;
+        use unix as backend;
     } else if #[cfg(windows)] {
         mod windows;
-        pub use windows::PageAllocError;
+        use windows as backend;
+    } else {
+        mod unsupported;
+        use unsupported as backend;
+    }
+}
+
+#[cfg(feature = "std")]
+pub use backend::unlock_range;
+pub use backend::{PageAllocError, page_size};
+
+cfg_if::cfg_if! {
+    if #[cfg(miri)] {
+        pub use backend::check_access;
+        #[cfg(feature = "testing")]
+        pub use backend::set_lock_budget;
+    } else {
+        /// Check that the memory range `ptr .. ptr + len` can be accessed. Only
+        /// checked by the miri shims; the OS checks accesses itself.
+        #[inline(always)]
+        pub fn check_access(_ptr: *const u8, _len: usize, _write: bool) {}
+
+        /// Set the locked memory budget of the miri shims; no-op outside miri.
+        #[cfg(feature = "testing")]
+        pub fn set_lock_budget(_bytes: Option<usize>) {}
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "testing")] {
+        mod fault_injection;
+        pub use fault_injection::{alloc_pages, lock_pages, protect_pages, release_pages};
+        #[cfg(feature = "std")]
+        pub use fault_injection::lock_range;
+    } else {
+        pub use backend::{alloc_pages, lock_pages, protect_pages, release_pages};
+        #[cfg(feature = "std")]
+        pub use backend::lock_range;
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(all(not(miri), any(target_os = "linux", target_os = "android")))] {
+        #[cfg(feature = "testing")]
+        pub use fault_injection::lock_pages_on_fault;
+        #[cfg(not(feature = "testing"))]
+        pub use backend::lock_pages_on_fault;
+    } else {
+        /// Locking memory pages on first access is not supported on this
+        /// platform; locks the pages `ptr .. ptr + len` up front using
+        /// [`lock_pages`].
+        ///
+        /// # Safety
+        /// Identical to [`lock_pages`].
+        pub unsafe fn lock_pages_on_fault(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { lock_pages(ptr, len) }
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(all(not(miri), any(target_os = "linux", target_os = "android")))] {
+        pub use backend::discard_pages;
+    } else {
+        /// Discarding memory pages is not supported on this platform; always
+        /// returns `false`.
+        ///
+        /// # Safety
+        /// Identical to the safety contract of the supported platform version.
+        pub unsafe fn discard_pages(_ptr: *mut u8, _len: usize, _locked: bool) -> bool {
+            false
+        }
+    }
+}
+
+/// Populate the memory pages spanning `ptr .. ptr + len` for writing, so that
+/// writing to them doesn't cause page faults. The contents of the memory are
+/// unchanged.
+///
+/// On Linux, `madvise(MADV_POPULATE_WRITE)` is used where supported. Otherwise
+/// one byte of every page is written (with it's own value).
+///
+/// # Safety
+/// The memory range must be valid for reads and writes, and not be accessed
+/// concurrently.
+pub unsafe fn populate_pages(ptr: NonNull<u8>, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let page_size = page_size();
+    let start = ptr.addr().get();
+    #[cfg(all(not(miri), any(target_os = "linux", target_os = "android")))]
+    if let Some(page_start) = core::num::NonZeroUsize::new(start & !(page_size - 1)) {
+        let page_len = (start + len).next_multiple_of(page_size) - page_start.get();
+        // SAFETY: the pages spanning the range are mapped and writable, since the
+        // range is valid for writes
+        if unsafe { backend::populate_pages(ptr.with_addr(page_start), page_len) } {
+            return;
+        }
+    }
+    check_access(ptr.as_ptr(), len, true);
+    // the start of the range, and the start of every following page in it
+    let first_page_end = (start + 1).next_multiple_of(page_size);
+    let offsets = core::iter::once(0).chain((first_page_end - start..len).step_by(page_size));
+    for offset in offsets {
+        // SAFETY: `offset < len`, so the byte lies in the memory range, which is
+        // valid for reads and writes by the safety contract
+        unsafe {
+            let byte = ptr.as_ptr().add(offset);
+            byte.write_volatile(byte.read_volatile());
+        }
+    }
+}
+
+/// Returns the `VmFlags` of the memory mapping containing `addr`, from
+/// `/proc/self/smaps`.
+#[cfg(all(test, target_os = "linux", not(miri)))]
+pub(crate) fn smaps_vm_flags(addr: usize) -> std::vec::Vec<std::string::String> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").expect("could not read smaps");
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        let first = line.split_whitespace().next().unwrap_or_default();
+        // mapping header lines start with the address range
+        if let Some((start, end)) = first.split_once('-')
+            && let (Ok(start), Ok(end)) = (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            )
+        {
+            in_mapping = (start..end).contains(&addr);
+            continue;
+        }
+        if let Some(flags) = line.strip_prefix("VmFlags:")
+            && in_mapping
+        {
+            return flags
+                .split_whitespace()
+                .map(std::string::String::from)
+                .collect();
+        }
     }
+    panic!("mapping not found in smaps");
 }
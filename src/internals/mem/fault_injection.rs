@@ -0,0 +1,118 @@
+//! Wrappers around the OS page backend injecting faults programmed using the
+//! [`testing`](crate::testing) module.
+
+use super::{PageAllocError, backend};
+use crate::page_provider::Protection;
+use crate::testing::{PageOp, on_call};
+use core::ptr::NonNull;
+
+/// Error of the backend for a failed call of `op` with error code `errno`.
+fn injected_error(op: PageOp, errno: i32) -> PageAllocError {
+    let _ = errno;
+    cfg_if::cfg_if! {
+        if #[cfg(miri)] {
+            match op {
+                PageOp::Lock => PageAllocError::Lock,
+                _ => PageAllocError::Alloc,
+            }
+        } else if #[cfg(unix)] {
+            let errno = rustix::io::Errno::from_raw_os_error(errno);
+            match op {
+                PageOp::Lock => PageAllocError::Mlock(errno),
+                PageOp::Protect => PageAllocError::Mprotect(errno),
+                _ => PageAllocError::Mmap(errno),
+            }
+        } else if #[cfg(windows)] {
+            let error = windows::core::Error::from_hresult(
+                windows::core::HRESULT::from_win32(errno as u32),
+            );
+            match op {
+                PageOp::Lock => PageAllocError::VirtualLock(error),
+                PageOp::Protect => PageAllocError::VirtualProtect(error),
+                _ => PageAllocError::VirtualAlloc,
+            }
+        } else {
+            let _ = op;
+            PageAllocError::Unsupported
+        }
+    }
+}
+
+/// See the backend `alloc_pages`; fails if a [`PageOp::Map`] fault is due.
+pub fn alloc_pages(len: usize, hint: *mut u8) -> Result<NonNull<u8>, PageAllocError> {
+    match on_call(PageOp::Map) {
+        Some(errno) => Err(injected_error(PageOp::Map, errno)),
+        None => backend::alloc_pages(len, hint),
+    }
+}
+
+/// See the backend `lock_pages`; fails if a [`PageOp::Lock`] fault is due.
+///
+/// # Safety
+/// Identical to the backend `lock_pages`.
+pub unsafe fn lock_pages(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    match on_call(PageOp::Lock) {
+        Some(errno) => Err(injected_error(PageOp::Lock, errno)),
+        // SAFETY: the caller must uphold the safety contract
+        None => unsafe { backend::lock_pages(ptr, len) },
+    }
+}
+
+/// See the backend `lock_pages_on_fault`; fails if a [`PageOp::Lock`] fault
+/// is due.
+///
+/// # Safety
+/// Identical to the backend `lock_pages_on_fault`.
+#[cfg(all(not(miri), any(target_os = "linux", target_os = "android")))]
+pub unsafe fn lock_pages_on_fault(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    match on_call(PageOp::Lock) {
+        Some(errno) => Err(injected_error(PageOp::Lock, errno)),
+        // SAFETY: the caller must uphold the safety contract
+        None => unsafe { backend::lock_pages_on_fault(ptr, len) },
+    }
+}
+
+/// See the backend `lock_range`; fails if a [`PageOp::Lock`] fault is due.
+///
+/// # Safety
+/// Identical to the backend `lock_range`.
+#[cfg(feature = "std")]
+pub unsafe fn lock_range(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    match on_call(PageOp::Lock) {
+        Some(errno) => Err(injected_error(PageOp::Lock, errno)),
+        // SAFETY: the caller must uphold the safety contract
+        None => unsafe { backend::lock_range(ptr, len) },
+    }
+}
+
+/// See the backend `protect_pages`; fails if a [`PageOp::Protect`] fault is
+/// due.
+///
+/// # Safety
+/// Identical to the backend `protect_pages`.
+pub unsafe fn protect_pages(
+    ptr: NonNull<u8>,
+    len: usize,
+    protection: Protection,
+) -> Result<(), PageAllocError> {
+    match on_call(PageOp::Protect) {
+        Some(errno) => Err(injected_error(PageOp::Protect, errno)),
+        // SAFETY: the caller must uphold the safety contract
+        None => unsafe { backend::protect_pages(ptr, len, protection) },
+    }
+}
+
+/// See the backend `release_pages`; panics if a [`PageOp::Unmap`] fault is due,
+/// like the backend does when unmapping fails. The pages are released anyway,
+/// so tests don't leak memory.
+///
+/// # Safety
+/// Identical to the backend `release_pages`.
+pub unsafe fn release_pages(ptr: NonNull<u8>, len: usize) {
+    let fault = on_call(PageOp::Unmap);
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { backend::release_pages(ptr, len) };
+    if let Some(errno) = fault {
+        panic!("could not unmap memory pages: injected error code {errno}");
+    }
+}
@@ -1,6 +1,11 @@
 //! Miri shims for memory management. Not accurate, but better than nothing.
+//!
+//! The shims model the state the real unix backend relies on: a budget of
+//! lockable memory (like `RLIMIT_MEMLOCK`), and the protection of every page.
+//! Accesses by this crate to pages which don't allow them are reported by
+//! [`check_access`].
 
-use super::Page;
+use crate::page_provider::Protection;
 use core::ptr::NonNull;
 
 /// Page size shim for miri.
@@ -15,68 +20,261 @@ pub enum PageAllocError {
     Layout(std::alloc::LayoutError),
     #[error("could not allocate memory")]
     Alloc,
-    #[error("could not lock memory")]
+    #[error("could not lock memory: locked memory budget exceeded")]
     Lock,
 }
 
-#[cfg(not(tarpaulin_include))]
-impl Page {
-    fn alloc_new() -> Result<Self, PageAllocError> {
+/// Default budget of lockable memory in bytes, the default `RLIMIT_MEMLOCK` on
+/// current Linux distributions.
+const DEFAULT_LOCK_BUDGET: usize = 8 * 1024 * 1024;
+
+/// Shim state of a mapped memory page.
+#[derive(Debug, Clone, Copy)]
+struct PageState {
+    /// Address of the page.
+    addr: usize,
+    /// `true` iff the page is locked.
+    locked: bool,
+    /// Access protection of the page.
+    protection: Protection,
+}
+
+/// Shim state of all mapped memory pages.
+struct Pages {
+    /// Mapped pages, in no particular order.
+    pages: std::vec::Vec<PageState>,
+    /// Number of locked bytes.
+    locked: usize,
+    /// Maximal number of locked bytes, or `None` for the default budget.
+    lock_budget: Option<usize>,
+    /// Addresses of locked pages not mapped by [`alloc_pages`], see
+    /// [`lock_range`].
+    locked_foreign: std::vec::Vec<usize>,
+}
+
+static PAGES: std::sync::Mutex<Pages> = std::sync::Mutex::new(Pages {
+    pages: std::vec::Vec::new(),
+    locked_foreign: std::vec::Vec::new(),
+    locked: 0,
+    lock_budget: None,
+});
+
+/// Run `f` on the shim state.
+fn with_pages<R>(f: impl FnOnce(&mut Pages) -> R) -> R {
+    let mut pages = PAGES
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    f(&mut pages)
+}
+
+impl Pages {
+    /// Returns the index of the state of the page at `addr`.
+    ///
+    /// # Panics
+    /// Panics if no page is mapped at `addr`, like `munmap`, `mlock` and
+    /// `mprotect` would fail on unmapped memory.
+    fn index(&self, addr: usize) -> usize {
+        self.pages
+            .iter()
+            .position(|page| page.addr == addr)
+            .expect("miri page shim: memory page is not mapped")
+    }
+
+    /// Returns the addresses of the pages in the memory range `ptr .. ptr +
+    /// len`.
+    fn page_addrs(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
         let page_size = page_size();
+        let start = ptr.addr() & !(page_size - 1);
+        (start..ptr.addr() + len).step_by(page_size)
+    }
+}
 
-        //libc::mmap(_addr, page_size, _prot, _flags, _fd, _offset)
-        let layout = std::alloc::Layout::from_size_align(page_size, page_size)
-            .map_err(|e| PageAllocError::Layout(e))?;
-        let page_ptr: *mut u8 = unsafe { std::alloc::alloc_zeroed(layout) };
-
-        if page_ptr.is_null() {
-            Err(PageAllocError::Alloc)
-        } else {
-            let page_ptr = unsafe {
-                // SAFETY: we just checked that `page_ptr` is non-null
-                NonNull::new_unchecked(page_ptr as *mut u8)
+/// Set the maximal number of bytes which can be locked by [`lock_pages`], or
+/// `None` to restore the default of 8 MiB.
+#[cfg(feature = "testing")]
+pub fn set_lock_budget(bytes: Option<usize>) {
+    with_pages(|pages| pages.lock_budget = bytes);
+}
+
+/// Check that the memory range `ptr .. ptr + len` can be read (and written if
+/// `write` is `true`) according to the protection of the memory pages mapped
+/// by [`alloc_pages`]. Memory not mapped by [`alloc_pages`] is not checked.
+///
+/// # Panics
+/// Panics if the range overlaps a page which doesn't allow the access, where
+/// the real backend would raise `SIGSEGV`.
+pub fn check_access(ptr: *const u8, len: usize, write: bool) {
+    if len == 0 {
+        return;
+    }
+    with_pages(|pages| {
+        for addr in Pages::page_addrs(ptr, len) {
+            let Some(page) = pages.pages.iter().find(|page| page.addr == addr) else {
+                continue;
             };
-            Ok(Self {
-                page_ptr,
-                page_size,
-                _phantom_pagemem: core::marker::PhantomData,
-            })
+            match page.protection {
+                Protection::NoAccess => {
+                    panic!("miri page shim: access to a no-access memory page")
+                },
+                Protection::ReadOnly if write => {
+                    panic!("miri page shim: write to a read-only memory page")
+                },
+                _ => {},
+            }
         }
-    }
+    });
+}
+
+/// Allocate `len` bytes of zeroed, page aligned memory using the global
+/// allocator, as a shim for `mmap`. The placement `hint` is ignored.
+#[cfg(not(tarpaulin_include))]
+pub fn alloc_pages(len: usize, _hint: *mut u8) -> Result<NonNull<u8>, PageAllocError> {
+    let layout =
+        std::alloc::Layout::from_size_align(len, page_size()).map_err(PageAllocError::Layout)?;
+    // SAFETY: `len` is a non-zero multiple of the page size
+    let page_ptr: *mut u8 = unsafe { std::alloc::alloc_zeroed(layout) };
+    let page_ptr = NonNull::new(page_ptr).ok_or(PageAllocError::Alloc)?;
+    with_pages(|pages| {
+        pages.pages.extend(
+            Pages::page_addrs(page_ptr.as_ptr(), len).map(|addr| PageState {
+                addr,
+                locked: false,
+                protection: Protection::ReadWrite,
+            }),
+        );
+    });
+    Ok(page_ptr)
+}
 
-    fn mlock(&mut self) -> Result<(), PageAllocError> {
-        let res = {
-            //libc::mlock(self.as_c_ptr_mut(), self.page_size())
-            let _ptr = self.as_ptr_mut();
-            let _ps = self.page_size();
-            0
-        };
-
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(PageAllocError::Lock)
+/// Shim for `mlock`: locks the pages if they fit the locked memory budget,
+/// see [`set_lock_budget`].
+///
+/// # Errors
+/// Returns `PageAllocError::Lock` if locking the pages would exceed the
+/// budget.
+///
+/// # Safety
+/// The memory range must be allocated by [`alloc_pages`] and not yet released.
+#[cfg(not(tarpaulin_include))]
+pub unsafe fn lock_pages(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    with_pages(|pages| {
+        let indices: std::vec::Vec<usize> = Pages::page_addrs(ptr.as_ptr(), len)
+            .map(|addr| pages.index(addr))
+            .collect();
+        let new_locked = indices.iter().filter(|&&i| !pages.pages[i].locked).count() * page_size();
+        let budget = pages.lock_budget.unwrap_or(DEFAULT_LOCK_BUDGET);
+        if pages.locked + new_locked > budget {
+            return Err(PageAllocError::Lock);
         }
-    }
+        pages.locked += new_locked;
+        for i in indices {
+            pages.pages[i].locked = true;
+        }
+        Ok(())
+    })
+}
 
-    pub fn alloc_new_lock() -> Result<Self, PageAllocError> {
-        let mut page = Self::alloc_new()?;
-        // if this fails then `page` is deallocated by it's drop implementation
-        page.mlock()?;
-        Ok(page)
-    }
+/// Shim for `mlock` on any memory: locks the pages if they fit the locked
+/// memory budget, like [`lock_pages`]. Pages not mapped by [`alloc_pages`] are
+/// tracked separately.
+///
+/// # Errors
+/// Returns `PageAllocError::Lock` if locking the pages would exceed the
+/// budget.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size.
+#[cfg(feature = "std")]
+#[cfg(not(tarpaulin_include))]
+pub unsafe fn lock_range(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    with_pages(|pages| {
+        let is_locked =
+            |pages: &Pages, addr: usize| match pages.pages.iter().find(|page| page.addr == addr) {
+                Some(page) => page.locked,
+                None => pages.locked_foreign.contains(&addr),
+            };
+        let new_locked = Pages::page_addrs(ptr.as_ptr(), len)
+            .filter(|&addr| !is_locked(pages, addr))
+            .count()
+            * page_size();
+        let budget = pages.lock_budget.unwrap_or(DEFAULT_LOCK_BUDGET);
+        if pages.locked + new_locked > budget {
+            return Err(PageAllocError::Lock);
+        }
+        pages.locked += new_locked;
+        for addr in Pages::page_addrs(ptr.as_ptr(), len) {
+            match pages.pages.iter_mut().find(|page| page.addr == addr) {
+                Some(page) => page.locked = true,
+                None if !pages.locked_foreign.contains(&addr) => pages.locked_foreign.push(addr),
+                None => {},
+            }
+        }
+        Ok(())
+    })
 }
 
+/// Shim for `munlock`: unlocks the pages locked by [`lock_range`] (or
+/// [`lock_pages`]).
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size.
+#[cfg(feature = "std")]
 #[cfg(not(tarpaulin_include))]
-impl Drop for Page {
-    fn drop(&mut self) {
-        let ptr = self.as_ptr_mut();
-        let page_size = self.page_size();
-
-        //libc::munmap(ptr, self.page_size());
-        let layout = std::alloc::Layout::from_size_align(page_size, page_size).unwrap();
-        // SAFETY: we allocated this page in the constructor so it is safe to deallocate
-        // now.
-        unsafe { std::alloc::dealloc(ptr, layout) };
-    }
+pub unsafe fn unlock_range(ptr: NonNull<u8>, len: usize) {
+    with_pages(|pages| {
+        for addr in Pages::page_addrs(ptr.as_ptr(), len) {
+            let was_locked = match pages.pages.iter_mut().find(|page| page.addr == addr) {
+                Some(page) => core::mem::replace(&mut page.locked, false),
+                None => {
+                    let len_before = pages.locked_foreign.len();
+                    pages.locked_foreign.retain(|&locked| locked != addr);
+                    pages.locked_foreign.len() != len_before
+                },
+            };
+            if was_locked {
+                pages.locked -= page_size();
+            }
+        }
+    });
+}
+
+/// Shim for `mprotect`: records the new protection of the pages, which is
+/// enforced by [`check_access`].
+///
+/// # Safety
+/// The memory range must be allocated by [`alloc_pages`] and not yet released.
+#[cfg(not(tarpaulin_include))]
+pub unsafe fn protect_pages(
+    ptr: NonNull<u8>,
+    len: usize,
+    protection: Protection,
+) -> Result<(), PageAllocError> {
+    with_pages(|pages| {
+        for addr in Pages::page_addrs(ptr.as_ptr(), len) {
+            let i = pages.index(addr);
+            pages.pages[i].protection = protection;
+        }
+    });
+    Ok(())
+}
+
+/// Deallocate memory allocated by [`alloc_pages`], as a shim for `munmap`.
+/// Locked pages are unlocked.
+///
+/// # Safety
+/// The memory range must be allocated by [`alloc_pages`] with length `len`
+/// and not be used afterwards.
+#[cfg(not(tarpaulin_include))]
+pub unsafe fn release_pages(ptr: NonNull<u8>, len: usize) {
+    with_pages(|pages| {
+        for addr in Pages::page_addrs(ptr.as_ptr(), len) {
+            let page = pages.pages.swap_remove(pages.index(addr));
+            if page.locked {
+                pages.locked -= page_size();
+            }
+        }
+    });
+    let layout = std::alloc::Layout::from_size_align(len, page_size()).unwrap();
+    // SAFETY: `ptr` was allocated with `layout` by the safety contract
+    unsafe { std::alloc::dealloc(ptr.as_ptr(), layout) };
 }
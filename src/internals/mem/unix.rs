@@ -1,6 +1,6 @@
 //! Unix `mmap` private anonymous memory pages.
 
-use super::Page;
+use crate::page_provider::Protection;
 
 use core::ffi::c_void;
 use core::ptr::NonNull;
@@ -16,98 +16,233 @@ pub enum PageAllocError {
     Mmap(rustix::io::Errno),
     #[error("could not lock memory page: {0}")]
     Mlock(rustix::io::Errno),
+    #[error("could not change memory page protection: {0}")]
+    Mprotect(rustix::io::Errno),
+    #[error("could not opt out of kernel same-page merging: {0}")]
+    Unmergeable(rustix::io::Errno),
+    #[error("could not exclude memory page from core dumps: {0}")]
+    DontDump(rustix::io::Errno),
+    #[error("could not prevent child processes from inheriting memory page: {0}")]
+    DontFork(rustix::io::Errno),
 }
 
-impl Page {
-    /// Get a mutable pointer to the start of the memory page.
-    fn as_c_ptr_mut(&self) -> *mut c_void {
-        self.as_ptr_mut() as *mut c_void
-    }
+/// Allocate `len` bytes of memory pages using (anonymous) `mmap` with the
+/// noreserve flag. The pages are placed at the address `hint` if possible
+/// (unless it is null), otherwise at an address chosen by the OS.
+///
+/// On Linux, the pages are excluded from kernel same-page merging (KSM) using
+/// `madvise(MADV_UNMERGEABLE)`, so secrets can't be merged with identical
+/// pages of other processes, which would leak their contents via timing side
+/// channels (even if the process or the system enables merging for all
+/// memory). The pages are also excluded from core dumps using
+/// `madvise(MADV_DONTDUMP)`, and wiped in child processes created by `fork`
+/// using `madvise(MADV_WIPEONFORK)` (since Linux 4.14), or not mapped in child
+/// processes at all using `madvise(MADV_DONTFORK)` on older kernels.
+///
+/// The noreserve flag disables swapping of the memory pages. As a
+/// consequence, the OS may unmap the pages, in which case writing to them
+/// causes a SIGSEGV. Therefore, the pages should be mlocked before actual use.
+///
+/// # Errors
+/// The function returns an `PageAllocError` if the `mmap` or the `madvise`
+/// call fails.
+pub fn alloc_pages(len: usize, hint: *mut u8) -> Result<NonNull<u8>, PageAllocError> {
+    use rustix::mm::{MapFlags, ProtFlags};
 
-    /// Allocate a new page of memory using (anonymous) `mmap` with the
-    /// noreserve flag.
-    ///
-    /// The noreserve flag disables swapping of the memory page. As a
-    /// consequence, the OS may unmap the page of memory, in which case
-    /// writing to it causes a SIGSEGV. Therefore, the page
-    /// should be mlocked before actual use.
-    ///
-    /// # Errors
-    /// The function returns an `PageAllocError` if the `mmap` call fails.
-    fn alloc_new_noreserve() -> Result<Self, rustix::io::Errno> {
-        use rustix::mm::{MapFlags, ProtFlags};
-
-        let addr: *mut c_void = core::ptr::null_mut();
-        let page_size = page_size();
-        let prot = ProtFlags::READ | ProtFlags::WRITE;
-        // NORESERVE disables backing the memory map with swap space. It requires
-        // `mlock` to be used on the resulting page before use. Redox, FreeBSD
-        // and DragonFlyBSD don't have NORESERVE. Other BSDs also don't implement it,
-        // but it is available for compatibility. FreeBSD and DragonflyBSD have a NOCORE
-        // flag, which hides the page from core dumps (memory dumps when the process
-        // crashes).
-        cfg_if::cfg_if! {
-            if #[cfg(target_os = "redox")] {
-                let flags = MapFlags::PRIVATE;
-            } else if #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))] {
-                let flags = MapFlags::PRIVATE | MapFlags::NOCORE;
-            } else {
-                let flags = MapFlags::PRIVATE | MapFlags::NORESERVE;
-            }
+    // without `MAP_FIXED`, the kernel only uses `addr` as a hint
+    let addr: *mut c_void = hint.cast();
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    // NORESERVE disables backing the memory map with swap space. It requires
+    // `mlock` to be used on the resulting page before use. Redox, FreeBSD
+    // and DragonFlyBSD don't have NORESERVE. Other BSDs also don't implement it,
+    // but it is available for compatibility. FreeBSD and DragonflyBSD have a NOCORE
+    // flag, which hides the page from core dumps (memory dumps when the process
+    // crashes).
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "redox")] {
+            let flags = MapFlags::PRIVATE;
+        } else if #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))] {
+            let flags = MapFlags::PRIVATE | MapFlags::NOCORE;
+        } else {
+            let flags = MapFlags::PRIVATE | MapFlags::NORESERVE;
         }
+    }
 
-        let page_ptr: *mut c_void =
-            unsafe { rustix::mm::mmap_anonymous(addr, page_size, prot, flags) }?;
+    let page_ptr: *mut c_void = unsafe { rustix::mm::mmap_anonymous(addr, len, prot, flags) }
+        .map_err(PageAllocError::Mmap)?;
 
-        // SAFETY: if `mmap` is successful, the result is non-zero
-        let page_ptr = unsafe { NonNull::new_unchecked(page_ptr as *mut u8) };
-        Ok(Self {
-            page_ptr,
-            page_size,
-            _phantom_pagemem: core::marker::PhantomData,
-        })
-    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use rustix::io::Errno;
+        use rustix::mm::Advice;
 
-    /// Lock the memory page to physical memory.
-    ///
-    /// When this function returns successfully then the memory page is
-    /// guarantied to be backed by physical memory, i.e. not (only) swapped.
-    /// In combination with the noreserve flag during the allocation, this
-    /// guaranties the memory to not be swapped at all, except on hibernation
-    /// or memory starvation. This is really the best we can achieve. If memory
-    /// contents are really secret than there is no other solution than to
-    /// use a swap space encrypted with an ephemeral secret key, and
-    /// hibernation should be disabled (both on the OS level).
-    fn mlock(&mut self) -> Result<(), rustix::io::Errno> {
-        unsafe { rustix::mm::mlock(self.as_c_ptr_mut(), self.page_size()) }
+        // SAFETY: the memory range is mapped by us above
+        let advise = |advice| unsafe { rustix::mm::madvise(page_ptr, len, advice) };
+        let result = match advise(Advice::LinuxUnmergeable) {
+            // `EINVAL` means the kernel is built without KSM, so nothing is merged
+            Ok(()) | Err(Errno::INVAL) => Ok(()),
+            Err(err) => Err(PageAllocError::Unmergeable(err)),
+        }
+        .and_then(|()| advise(Advice::LinuxDontDump).map_err(PageAllocError::DontDump))
+        .and_then(|()| match advise(Advice::LinuxWipeOnFork) {
+            // `EINVAL` means the kernel doesn't support `MADV_WIPEONFORK` (before 4.14)
+            Err(Errno::INVAL) => advise(Advice::LinuxDontFork).map_err(PageAllocError::DontFork),
+            result => result.map_err(PageAllocError::DontFork),
+        });
+        if let Err(err) = result {
+            // SAFETY: the memory range is mapped by us above and not used
+            unsafe { rustix::mm::munmap(page_ptr, len) }.unwrap();
+            return Err(err);
+        }
     }
 
-    /// Allocate a new page of memory using (anonymous) `mmap` with the
-    /// noreserve flag and mlock page.
-    ///
-    /// The noreserve flag disables swapping of the memory page. The page is
-    /// then mlocked to force it into physical memory.
-    ///
-    /// # Errors
-    /// The function returns an `PageAllocError` if the `mmap` or `mlock` call
-    /// fails.
-    pub fn alloc_new_lock() -> Result<Self, PageAllocError> {
-        let mut page = Self::alloc_new_noreserve().map_err(PageAllocError::Mmap)?;
-        page.mlock().map_err(PageAllocError::Mlock)?;
-        Ok(page)
-    }
+    // SAFETY: if `mmap` is successful, the result is non-zero
+    Ok(unsafe { NonNull::new_unchecked(page_ptr as *mut u8) })
 }
 
-impl Drop for Page {
-    fn drop(&mut self) {
-        let ptr = self.as_c_ptr_mut();
-        unsafe {
-            // SAFETY: we allocated/mapped this page in the constructor so it is safe to
-            // unmap now. `munmap` also unlocks a page if it was locked so it is
-            // not necessary to `munlock` the page if it was locked.
-            rustix::mm::munmap(ptr, self.page_size()).unwrap();
-        }
-        // SAFETY: `NonNull<u8>` and `usize` both do not drop so we need not
-        // worry about subsequent drops
+/// Lock the memory pages `ptr .. ptr + len` to physical memory.
+///
+/// When this function returns successfully then the memory pages are
+/// guarantied to be backed by physical memory, i.e. not (only) swapped.
+/// In combination with the noreserve flag during the allocation, this
+/// guaranties the memory to not be swapped at all, except on hibernation
+/// or memory starvation. This is really the best we can achieve. If memory
+/// contents are really secret than there is no other solution than to
+/// use a swap space encrypted with an ephemeral secret key, and
+/// hibernation should be disabled (both on the OS level).
+///
+/// # Safety
+/// The memory range must be mapped by [`alloc_pages`] and not yet released.
+pub unsafe fn lock_pages(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { rustix::mm::mlock(ptr.as_ptr() as *mut c_void, len) }.map_err(PageAllocError::Mlock)
+}
+
+/// Lock the memory pages `ptr .. ptr + len` to physical memory when they are
+/// first accessed, using `mlock2(MLOCK_ONFAULT)` (since Linux 4.4).
+///
+/// Unlike [`lock_pages`], this doesn't populate the whole range up front, so
+/// untouched pages of a large reservation don't use physical memory. Pages
+/// which are accessed are locked like by [`lock_pages`]. On kernels without
+/// `mlock2`, the pages are locked using [`lock_pages`] instead.
+///
+/// # Safety
+/// The memory range must be mapped by [`alloc_pages`] and not yet released.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub unsafe fn lock_pages_on_fault(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    use rustix::io::Errno;
+    use rustix::mm::MlockFlags;
+
+    // SAFETY: the caller must uphold the safety contract
+    match unsafe { rustix::mm::mlock_with(ptr.as_ptr() as *mut c_void, len, MlockFlags::ONFAULT) } {
+        Ok(()) => Ok(()),
+        // `ENOSYS` without `mlock2`, `EINVAL` if `MLOCK_ONFAULT` is unknown
+        // SAFETY: the caller must uphold the safety contract
+        Err(Errno::NOSYS | Errno::INVAL) => unsafe { lock_pages(ptr, len) },
+        Err(err) => Err(PageAllocError::Mlock(err)),
     }
 }
+
+/// Lock the memory pages `ptr .. ptr + len` of any memory mapping of the
+/// process (e.g. the stack or the heap) to physical memory using `mlock`.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size. The
+/// memory range must be mapped.
+#[cfg(feature = "std")]
+pub unsafe fn lock_range(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { rustix::mm::mlock(ptr.as_ptr() as *mut c_void, len) }.map_err(PageAllocError::Mlock)
+}
+
+/// Unlock the memory pages `ptr .. ptr + len` locked by [`lock_range`] using
+/// `munlock`. Errors are ignored, since the pages are then not locked anyway.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size.
+#[cfg(feature = "std")]
+pub unsafe fn unlock_range(ptr: NonNull<u8>, len: usize) {
+    // SAFETY: the caller must uphold the safety contract
+    let _ = unsafe { rustix::mm::munlock(ptr.as_ptr() as *mut c_void, len) };
+}
+
+/// Change the access protection of the memory pages `ptr .. ptr + len` using
+/// `mprotect`.
+///
+/// # Safety
+/// The memory range must be mapped by [`alloc_pages`] and not yet released.
+pub unsafe fn protect_pages(
+    ptr: NonNull<u8>,
+    len: usize,
+    protection: Protection,
+) -> Result<(), PageAllocError> {
+    use rustix::mm::MprotectFlags;
+
+    let flags = match protection {
+        Protection::NoAccess => MprotectFlags::empty(),
+        Protection::ReadOnly => MprotectFlags::READ,
+        Protection::ReadWrite => MprotectFlags::READ | MprotectFlags::WRITE,
+    };
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { rustix::mm::mprotect(ptr.as_ptr() as *mut c_void, len, flags) }
+        .map_err(PageAllocError::Mprotect)
+}
+
+/// Unmap the memory pages `ptr .. ptr + len`.
+///
+/// # Safety
+/// The memory range must be mapped by [`alloc_pages`] and not be used
+/// afterwards.
+pub unsafe fn release_pages(ptr: NonNull<u8>, len: usize) {
+    // SAFETY: the caller must uphold the safety contract. `munmap` also unlocks
+    // pages if they were locked so it is not necessary to `munlock` them.
+    unsafe { rustix::mm::munmap(ptr.as_ptr() as *mut c_void, len) }.unwrap();
+}
+
+/// Populate the memory pages in the range `ptr .. ptr + len` for writing
+/// using `madvise(MADV_POPULATE_WRITE)` (since Linux 5.14), so that writing to
+/// them doesn't cause page faults. The contents of the memory are unchanged.
+///
+/// Returns `true` iff the pages were populated.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size. The
+/// memory range must be mapped and writable.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub unsafe fn populate_pages(ptr: NonNull<u8>, len: usize) -> bool {
+    use rustix::mm::Advice;
+
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { rustix::mm::madvise(ptr.as_ptr() as *mut c_void, len, Advice::LinuxPopulateWrite) }
+        .is_ok()
+}
+
+/// Discard the memory pages in the range `ptr .. ptr + len`, so that they are
+/// replaced by fresh zero-filled pages on the next access.
+///
+/// This uses `madvise(MADV_DONTNEED)` for unlocked memory. Locked pages cannot
+/// be discarded using `MADV_DONTNEED`, so for `locked` memory
+/// `MADV_DONTNEED_LOCKED` (since Linux 5.18) is used instead. The memory range
+/// then stays locked: the pages are locked again when they are faulted back in.
+///
+/// Returns `true` iff the pages were discarded. If `false` is returned, the
+/// memory is left untouched and the caller has to zeroize it another way.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size. The
+/// memory range must be valid for writes and lie in a *private anonymous*
+/// memory mapping; only for those the kernel guaranties zero-fill-on-demand
+/// semantics. For other mappings (e.g. shared or file backed mappings) the old
+/// contents can reappear on the next access.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub unsafe fn discard_pages(ptr: *mut u8, len: usize, locked: bool) -> bool {
+    use rustix::mm::Advice;
+
+    let advice = if locked {
+        Advice::LinuxDontneedLocked
+    } else {
+        Advice::LinuxDontNeed
+    };
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { rustix::mm::madvise(ptr as *mut c_void, len, advice) }.is_ok()
+}
@@ -0,0 +1,71 @@
+//! Stubs for platforms without an operating system memory page API, e.g.
+//! bare metal targets. Allocating pages always fails; use a custom page
+//! provider such as
+//! [`StaticBufferProvider`](crate::page_provider::StaticBufferProvider)
+//! instead.
+
+use crate::page_provider::Protection;
+use core::ptr::NonNull;
+
+/// Nominal page size on platforms without memory pages.
+pub fn page_size() -> usize {
+    4096
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum PageAllocError {
+    #[error("memory page allocation is not supported on this platform")]
+    Unsupported,
+}
+
+/// Allocating memory pages is not supported on this platform; always fails.
+///
+/// # Errors
+/// Always returns `PageAllocError::Unsupported`.
+pub fn alloc_pages(_len: usize, _hint: *mut u8) -> Result<NonNull<u8>, PageAllocError> {
+    Err(PageAllocError::Unsupported)
+}
+
+/// Locking memory pages is not supported on this platform; always fails.
+///
+/// # Safety
+/// Identical to the safety contract of the supported platform version.
+pub unsafe fn lock_pages(_ptr: NonNull<u8>, _len: usize) -> Result<(), PageAllocError> {
+    Err(PageAllocError::Unsupported)
+}
+
+/// Locking memory pages is not supported on this platform; always fails.
+///
+/// # Safety
+/// Identical to the safety contract of the supported platform version.
+#[cfg(feature = "std")]
+pub unsafe fn lock_range(_ptr: NonNull<u8>, _len: usize) -> Result<(), PageAllocError> {
+    Err(PageAllocError::Unsupported)
+}
+
+/// Locking memory pages is not supported on this platform, so this is a
+/// no-op.
+///
+/// # Safety
+/// Identical to the safety contract of the supported platform version.
+#[cfg(feature = "std")]
+pub unsafe fn unlock_range(_ptr: NonNull<u8>, _len: usize) {}
+
+/// Protecting memory pages is not supported on this platform; always fails.
+///
+/// # Safety
+/// Identical to the safety contract of the supported platform version.
+pub unsafe fn protect_pages(
+    _ptr: NonNull<u8>,
+    _len: usize,
+    _protection: Protection,
+) -> Result<(), PageAllocError> {
+    Err(PageAllocError::Unsupported)
+}
+
+/// No memory pages can be allocated on this platform, so this is never
+/// called.
+///
+/// # Safety
+/// Identical to the safety contract of the supported platform version.
+pub unsafe fn release_pages(_ptr: NonNull<u8>, _len: usize) {}
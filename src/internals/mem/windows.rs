@@ -1,6 +1,6 @@
 //! Windows `VirtualAlloc` memory page allocation.
 
-use super::Page;
+use crate::page_provider::Protection;
 
 use core::ffi::c_void;
 use core::ptr::NonNull;
@@ -26,84 +26,122 @@ pub enum PageAllocError {
     VirtualAlloc,
     #[error("could not lock memory page: {0}")]
     VirtualLock(windows::core::Error),
+    #[error("could not change memory page protection: {0}")]
+    VirtualProtect(windows::core::Error),
 }
 
-impl Page {
-    /// Get a mutable pointer to the start of the memory page.
-    fn as_c_ptr_mut(&self) -> *mut c_void {
-        self.as_ptr_mut() as *mut c_void
-    }
+/// Allocate `len` bytes of memory pages using `VirtualAlloc`. The pages are
+/// placed at the address `hint` if possible (unless it is null), otherwise at
+/// an address chosen by the OS.
+///
+/// # Errors
+/// The function returns an `PageAllocError` if the `VirtualAlloc` call
+/// fails.
+pub fn alloc_pages(len: usize, hint: *mut u8) -> Result<NonNull<u8>, PageAllocError> {
+    use windows::Win32::System::Memory::{
+        MEM_COMMIT, MEM_RESERVE, PAGE_PROTECTION_FLAGS, PAGE_READWRITE, VIRTUAL_ALLOCATION_TYPE,
+        VirtualAlloc,
+    };
 
-    /// Allocate a new page of memory using `VirtualAlloc`.
-    ///
-    /// # Errors
-    /// The function returns an `PageAllocError` if the `VirtualAlloc` call
-    /// fails.
-    fn alloc_new() -> Result<Self, ()> {
-        use windows::Win32::System::Memory::{
-            MEM_COMMIT, MEM_RESERVE, PAGE_PROTECTION_FLAGS, PAGE_READWRITE,
-            VIRTUAL_ALLOCATION_TYPE, VirtualAlloc,
-        };
-
-        let page_size = page_size();
-        let alloc_type: VIRTUAL_ALLOCATION_TYPE = MEM_RESERVE | MEM_COMMIT;
-        let protect: PAGE_PROTECTION_FLAGS = PAGE_READWRITE;
-
-        let page_ptr: *mut c_void = unsafe { VirtualAlloc(None, page_size, alloc_type, protect) };
-
-        if page_ptr.is_null() {
-            Err(())
-        } else {
-            let page_ptr = unsafe {
-                // SAFETY: we just checked that `page_ptr` is non-null
-                NonNull::new_unchecked(page_ptr as *mut u8)
-            };
-            Ok(Self {
-                page_ptr,
-                page_size,
-                _phantom_pagemem: core::marker::PhantomData,
-            })
-        }
-    }
+    let alloc_type: VIRTUAL_ALLOCATION_TYPE = MEM_RESERVE | MEM_COMMIT;
+    let protect: PAGE_PROTECTION_FLAGS = PAGE_READWRITE;
 
-    /// Lock the memory page to physical memory.
-    ///
-    /// When this function returns successfully then the memory page is
-    /// guarantied to be backed by physical memory, i.e. not (only) swapped.
-    /// This guaranties the memory to not be swapped at all, except on
-    /// hibernation or memory starvation. This is really the best we can
-    /// achieve. If memory contents are really secret than there is no other
-    /// solution than to use a swap space encrypted with an ephemeral secret
-    /// key, and hibernation should be disabled (both on the OS level).
-    fn lock(&mut self) -> Result<(), windows::core::Error> {
-        use windows::Win32::System::Memory::VirtualLock;
-
-        unsafe { VirtualLock(self.as_c_ptr_mut(), self.page_size()) }
+    let mut page_ptr: *mut c_void = core::ptr::null_mut();
+    if !hint.is_null() {
+        // `VirtualAlloc` fails if the address range is not free, so the OS chooses
+        // the address below
+        page_ptr =
+            unsafe { VirtualAlloc(Some(hint.cast_const().cast()), len, alloc_type, protect) };
     }
-
-    /// Allocate a new page of memory using `VirtualAlloc` and `VirtualLock`
-    /// page.
-    ///
-    /// The page is locked to force it into physical memory.
-    ///
-    /// # Errors
-    /// The function returns an `PageAllocError` if the `VirtualAlloc` or
-    /// `VirtualLock` call fails.
-    pub fn alloc_new_lock() -> Result<Self, PageAllocError> {
-        let mut page = Self::alloc_new().map_err(|_| PageAllocError::VirtualAlloc)?;
-        page.lock().map_err(|e| PageAllocError::VirtualLock(e))?;
-        Ok(page)
+    if page_ptr.is_null() {
+        page_ptr = unsafe { VirtualAlloc(None, len, alloc_type, protect) };
     }
+
+    // `VirtualAlloc` returns null on failure
+    NonNull::new(page_ptr as *mut u8).ok_or(PageAllocError::VirtualAlloc)
 }
 
-impl Drop for Page {
-    fn drop(&mut self) {
-        use windows::Win32::System::Memory::{MEM_RELEASE, VirtualFree};
+/// Lock the memory pages `ptr .. ptr + len` to physical memory.
+///
+/// When this function returns successfully then the memory pages are
+/// guarantied to be backed by physical memory, i.e. not (only) swapped.
+/// This guaranties the memory to not be swapped at all, except on
+/// hibernation or memory starvation. This is really the best we can
+/// achieve. If memory contents are really secret than there is no other
+/// solution than to use a swap space encrypted with an ephemeral secret
+/// key, and hibernation should be disabled (both on the OS level).
+///
+/// # Safety
+/// The memory range must be allocated by [`alloc_pages`] and not yet released.
+pub unsafe fn lock_pages(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    use windows::Win32::System::Memory::VirtualLock;
 
-        // SAFETY: we allocated/mapped this page in the constructor so it is safe to
-        // unmap now
-        unsafe { VirtualFree(self.as_c_ptr_mut(), 0, MEM_RELEASE) }.unwrap();
-        // SAFETY: `NonNull<u8>` and `usize` both do not drop so we need not
-        // worry about subsequent drops
-    }
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { VirtualLock(ptr.as_ptr() as *mut c_void, len) }.map_err(PageAllocError::VirtualLock)
+}
+
+/// Lock the memory pages `ptr .. ptr + len` of any committed memory of the
+/// process (e.g. the stack or the heap) to physical memory using
+/// `VirtualLock`.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size. The
+/// memory range must be committed.
+#[cfg(feature = "std")]
+pub unsafe fn lock_range(ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+    use windows::Win32::System::Memory::VirtualLock;
+
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { VirtualLock(ptr.as_ptr() as *mut c_void, len) }.map_err(PageAllocError::VirtualLock)
+}
+
+/// Unlock the memory pages `ptr .. ptr + len` locked by [`lock_range`] using
+/// `VirtualUnlock`. Errors are ignored, since the pages are then not locked
+/// anyway.
+///
+/// # Safety
+/// `ptr` must be page aligned and `len` a multiple of the page size.
+#[cfg(feature = "std")]
+pub unsafe fn unlock_range(ptr: NonNull<u8>, len: usize) {
+    use windows::Win32::System::Memory::VirtualUnlock;
+
+    // SAFETY: the caller must uphold the safety contract
+    let _ = unsafe { VirtualUnlock(ptr.as_ptr() as *const c_void, len) };
+}
+
+/// Change the access protection of the memory pages `ptr .. ptr + len` using
+/// `VirtualProtect`.
+///
+/// # Safety
+/// The memory range must be allocated by [`alloc_pages`] and not yet released.
+pub unsafe fn protect_pages(
+    ptr: NonNull<u8>,
+    len: usize,
+    protection: Protection,
+) -> Result<(), PageAllocError> {
+    use windows::Win32::System::Memory::{
+        PAGE_NOACCESS, PAGE_PROTECTION_FLAGS, PAGE_READONLY, PAGE_READWRITE, VirtualProtect,
+    };
+
+    let flags = match protection {
+        Protection::NoAccess => PAGE_NOACCESS,
+        Protection::ReadOnly => PAGE_READONLY,
+        Protection::ReadWrite => PAGE_READWRITE,
+    };
+    let mut old_flags = PAGE_PROTECTION_FLAGS::default();
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { VirtualProtect(ptr.as_ptr() as *const c_void, len, flags, &mut old_flags) }
+        .map_err(PageAllocError::VirtualProtect)
+}
+
+/// Release the memory pages allocated by [`alloc_pages`] at `ptr`.
+///
+/// # Safety
+/// `ptr` must be allocated by [`alloc_pages`] and not be used afterwards.
+pub unsafe fn release_pages(ptr: NonNull<u8>, _len: usize) {
+    use windows::Win32::System::Memory::{MEM_RELEASE, VirtualFree};
+
+    // SAFETY: the caller must uphold the safety contract; `MEM_RELEASE` releases
+    // the whole allocation, and requires a length of 0
+    unsafe { VirtualFree(ptr.as_ptr() as *mut c_void, 0, MEM_RELEASE) }.unwrap();
 }
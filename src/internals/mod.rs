@@ -1,3 +1,5 @@
 //! Wrappers around platform specific functions, ffi and compiler intrinsics.
 
 pub mod mem;
+#[cfg(feature = "randomize")]
+pub mod rng;
@@ -0,0 +1,60 @@
+//! Randomness for the placement of secure memory, seeded by the OS RNG.
+
+use core::cell::Cell;
+
+/// Error returned when no random bytes could be obtained from the OS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("could not get random bytes from the OS: {0}")]
+pub struct RngError(getrandom::Error);
+
+/// Returns a random `u64` from the OS RNG.
+///
+/// # Errors
+/// Returns an error if the OS RNG fails.
+pub fn os_random_u64() -> Result<u64, RngError> {
+    getrandom::u64().map_err(RngError)
+}
+
+/// Fast non-cryptographic pseudo random number generator (splitmix64), seeded
+/// from the OS RNG.
+///
+/// This is only used to randomise memory layouts, so it must be unpredictable
+/// without knowledge of the seed, but doesn't need to resist an attacker who
+/// observes many outputs.
+pub struct Rng {
+    state: Cell<u64>,
+}
+
+impl Rng {
+    /// Create a new generator seeded from the OS RNG.
+    ///
+    /// # Errors
+    /// Returns an error if the OS RNG fails.
+    pub fn from_os() -> Result<Self, RngError> {
+        Ok(Self {
+            state: Cell::new(os_random_u64()?),
+        })
+    }
+
+    /// Returns the next pseudo random `u64`.
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a pseudo random number in `0 .. bound`, or 0 if `bound` is 0.
+    pub fn below(&self, bound: usize) -> usize {
+        if bound == 0 {
+            return 0;
+        }
+        // `bound` fits a `u64` and the result is less than `bound`, so the casts
+        // don't truncate; the modulo bias is negligible for the small bounds we use
+        #[allow(clippy::cast_possible_truncation)]
+        let res = (self.next_u64() % bound as u64) as usize;
+        res
+    }
+}
@@ -77,9 +77,23 @@
 //!
 //!
 //! # Cargo features
-//! - `std` (default): Enable functionality that requires `std`. Currently only
-//!   required for `Error` implements and required for tests. This feature is
-//!   enabled by default.
+//! - `std` (default): Enable functionality that requires `std`. Currently
+//!   required for `Error` implements, runtime CPU feature detection (to select
+//!   the fastest zeroizer), thread tracking in the registry, the panic hook and
+//!   required for tests. This feature is enabled by default.
+//! - `derive`: Enable the derive macro for the [`Zeroable`] trait.
+//! - `hardened`: Enable allocation tracking of
+//!   [`SecStackSinglePageAlloc`](sec_alloc::SecStackSinglePageAlloc) by
+//!   default also in release builds, so invalid deallocations abort the
+//!   process.
+//! - `randomize`: Enable random placement of secure memory pages
+//!   ([`RandomizedOsPageProvider`](page_provider::RandomizedOsPageProvider))
+//!   and allocations
+//!   ([`with_random_placement`](sec_alloc::SecStackSinglePageAlloc::with_random_placement)),
+//!   using the OS RNG through the `getrandom` crate.
+//! - `testing`: Enable the `testing` module, which injects faults into the OS
+//!   memory page backend to test failure paths. Requires `std`. Only meant
+//!   for tests.
 //! - `nightly_allocator_api` (requires nightly): Use the nightly allocator api
 //!   from the standard library (actually the `core` crate), gated behind the
 //!   nightly-only feature `allocator_api`. When disabled, a copy of the
@@ -89,8 +103,8 @@
 //! - `nightly_core_intrinsics` (requires nightly): Use the intrinsics from the
 //!   standard library (actually the `core` crate), gated behind the
 //!   nightly-only feature `core_intrinsics`. This allows for a slightly faster
-//!   [`zeroize_mem`] implementation, and various other small optimisations.
-//!   This feature requires a nightly compiler.
+//!   portable [`zeroize_mem`] implementation, and various other small
+//!   optimisations. This feature requires a nightly compiler.
 //! - `nightly` (requires nightly): Enable all nightly-only features (i.e. the
 //!   above two). Enabling this feature is highly recommended when a nightly
 //!   compiler is available. This feature requires a nightly compiler.
@@ -122,9 +136,26 @@ mod macros;
 mod util;
 mod zeroize;
 
-pub use zeroize::zeroize_mem;
+/// Derive macro for the [`Zeroable`] trait. Requires the `derive` feature.
+#[cfg(feature = "derive")]
+pub use secmem_alloc_derive::Zeroable;
+pub use zeroize::{
+    Zeroable, Zeroizer, zeroize_mem, zeroize_mem_flush, zeroize_slice, zeroize_uninit, zeroize_val,
+};
 
+pub mod containers;
+#[cfg(feature = "std")]
+pub mod locked_ref;
+pub mod page_provider;
+#[cfg(feature = "std")]
+pub mod panic_hook;
+pub mod registry;
 pub mod sec_alloc;
+pub mod sec_region;
+pub mod sec_slot_pool;
+pub mod shared_alloc;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod zeroizing_alloc;
 
 #[cfg(test)]
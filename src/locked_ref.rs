@@ -0,0 +1,232 @@
+//! Lock existing memory in place, and wipe it when done. Requires the `std`
+//! feature.
+//!
+//! Secrets are not always stored in memory allocated by this crate: e.g. a
+//! third party library may deserialize a key into a `Vec<u8>`, or a key may
+//! live in an array on the stack. A [`LockedRef`] locks the memory pages
+//! spanning such a buffer to physical memory (using `mlock` on unix and
+//! `VirtualLock` on windows), so it is not swapped out while the secret is
+//! in use, and zeroizes the buffer on drop.
+//!
+//! Memory locks don't nest: unlocking a page unlocks it, no matter how often
+//! it was locked. Therefore the number of locks of every page is counted
+//! process wide, and a page is only unlocked once no [`LockedRef`] refers to
+//! it anymore. Secure memory pages of this crate (which are locked by their
+//! owner) are never unlocked by a [`LockedRef`] into them, provided that the
+//! [`registry`](crate::registry) is enabled: the registry is consulted to
+//! recognise these pages.
+
+use crate::internals::mem::{self, PageAllocError};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Number of locks per locked page, indexed by the page address.
+static LOCK_COUNTS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// Lock the lock count table.
+fn lock_counts() -> MutexGuard<'static, BTreeMap<usize, usize>> {
+    // the table is consistent at all times, so we can ignore poisoning
+    LOCK_COUNTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the page aligned start and length of the memory pages spanning
+/// `ptr .. ptr + len`, or `None` if `len` is zero.
+fn page_span(ptr: *const u8, len: usize) -> Option<(usize, usize)> {
+    if len == 0 {
+        return None;
+    }
+    let page_size = mem::page_size();
+    let start = ptr.addr() & !(page_size - 1);
+    let end = (ptr.addr() + len).next_multiple_of(page_size);
+    Some((start, end - start))
+}
+
+/// Increment the lock counts of the pages `start .. start + len`. `start`
+/// must be page aligned and `len` a multiple of the page size.
+fn increment(counts: &mut BTreeMap<usize, usize>, start: usize, len: usize) {
+    for page in (start..start + len).step_by(mem::page_size()) {
+        *counts.entry(page).or_insert(0) += 1;
+    }
+}
+
+/// Decrement the lock counts of the pages `start .. start + len`, and call
+/// `unlock` with the start address of every page whose count drops to zero.
+/// `start` must be page aligned and `len` a multiple of the page size.
+fn decrement(
+    counts: &mut BTreeMap<usize, usize>,
+    start: usize,
+    len: usize,
+    mut unlock: impl FnMut(usize),
+) {
+    for page in (start..start + len).step_by(mem::page_size()) {
+        let count = counts.get_mut(&page).expect("lock count of a locked page");
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&page);
+            unlock(page);
+        }
+    }
+}
+
+/// A buffer locked in physical memory, which is zeroized on drop.
+///
+/// Created from an existing `&mut [u8]` using [`LockedRef::new`]; see the
+/// [module level documentation](self).
+///
+/// # Examples
+/// ```
+/// use secmem_alloc::locked_ref::LockedRef;
+///
+/// let mut key = [0_u8; 32];
+/// let mut locked = LockedRef::new(&mut key).expect("could not lock memory");
+/// locked.copy_from_slice(&[0xAF; 32]);
+/// // use the key
+/// drop(locked);
+/// assert_eq!(key, [0; 32]);
+/// ```
+pub struct LockedRef<'a> {
+    /// The locked buffer.
+    buf: &'a mut [u8],
+}
+
+impl<'a> LockedRef<'a> {
+    /// Lock the memory pages spanning `buf`.
+    ///
+    /// # Errors
+    /// Returns an error if the pages could not be locked, e.g. because the
+    /// process would exceed the amount of memory it is allowed to lock. The
+    /// buffer is not zeroized in that case.
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, PageAllocError> {
+        if let Some((start, len)) = page_span(buf.as_ptr(), buf.len()) {
+            let ptr = NonNull::new(buf.as_mut_ptr().with_addr(start))
+                .expect("page of a reference is non-null");
+            // keep the table locked while locking, so pages are not unlocked
+            // concurrently
+            let mut counts = lock_counts();
+            // SAFETY: `ptr` is page aligned and `len` a multiple of the page size;
+            // the pages are mapped since they contain `buf`
+            unsafe { mem::lock_range(ptr, len) }?;
+            increment(&mut counts, start, len);
+        }
+        Ok(Self { buf })
+    }
+
+    /// Returns the buffer as a slice.
+    pub fn as_slice(&self) -> &[u8] {
+        self.buf
+    }
+
+    /// Returns the buffer as a mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.buf
+    }
+}
+
+impl Drop for LockedRef<'_> {
+    fn drop(&mut self) {
+        crate::zeroize_slice(self.buf);
+        if let Some((start, len)) = page_span(self.buf.as_ptr(), self.buf.len()) {
+            let ptr = self.buf.as_mut_ptr();
+            decrement(&mut lock_counts(), start, len, |page| {
+                // registered secure memory pages are unlocked by their owner
+                if crate::registry::contains(page) {
+                    return;
+                }
+                let page = NonNull::new(ptr.with_addr(page)).expect("page is non-null");
+                // SAFETY: `page` is page aligned
+                unsafe { mem::unlock_range(page, mem::page_size()) };
+            });
+        }
+    }
+}
+
+impl Deref for LockedRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for LockedRef<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl fmt::Debug for LockedRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the contents are secret
+        f.debug_struct("LockedRef")
+            .field("len", &self.buf.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Returns the lock count of the page containing `ptr`.
+    fn lock_count(ptr: *const u8) -> usize {
+        let (page, _) = page_span(ptr, 1).expect("non-empty span");
+        lock_counts().get(&page).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn shared_pages_counted() {
+        let page_size = mem::page_size();
+        let mut buf: Vec<u8> = std::vec![0xAF; 4 * page_size];
+        // the second page of `buf`
+        let offset = page_size - buf.as_ptr().addr() % page_size + page_size;
+        let (first, second) = buf[offset - page_size..].split_at_mut(page_size + 10);
+        let page_ptr = second.as_ptr().wrapping_sub(10);
+        let first = LockedRef::new(first).expect("locking failed");
+        let second = LockedRef::new(&mut second[..page_size]).expect("locking failed");
+        // both refs span the page at `page_ptr`
+        assert_eq!(lock_count(page_ptr), 2);
+        assert_eq!(first.len(), page_size + 10);
+        drop(first);
+        assert_eq!(lock_count(page_ptr), 1);
+        drop(second);
+        assert_eq!(lock_count(page_ptr), 0);
+        let wiped = offset - page_size..offset + page_size + 10;
+        assert!(buf[wiped.clone()].iter().all(|&b| b == 0));
+        assert!(buf[..wiped.start].iter().all(|&b| b == 0xAF));
+        assert!(buf[wiped.end..].iter().all(|&b| b == 0xAF));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn lock_failure() {
+        use crate::testing::{self, PageOp};
+
+        let mut key = [0xAF_u8; 64];
+        let page_ptr = key.as_ptr();
+        testing::reset();
+        testing::fail_nth(PageOp::Lock, 1, 12);
+        assert!(LockedRef::new(&mut key).is_err());
+        // nothing is counted or mapped, and the buffer is not zeroized
+        assert_eq!(lock_count(page_ptr), 0);
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (0, 1, 0));
+        assert_eq!(key, [0xAF; 64]);
+    }
+
+    #[test]
+    fn empty_and_stack_buffers() {
+        let mut empty: [u8; 0] = [];
+        let locked = LockedRef::new(&mut empty).expect("locking failed");
+        assert!(locked.is_empty());
+        drop(locked);
+        let mut key = [0xAF_u8; 64];
+        let locked = LockedRef::new(&mut key).expect("locking failed");
+        assert_eq!(std::format!("{locked:?}"), "LockedRef { len: 64, .. }");
+        drop(locked);
+        assert_eq!(key, [0; 64]);
+    }
+}
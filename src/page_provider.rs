@@ -0,0 +1,411 @@
+//! Pluggable sources of memory pages for the secure allocators.
+//!
+//! The secure allocators (e.g.
+//! [`SecStackSinglePageAlloc`](crate::sec_alloc::SecStackSinglePageAlloc)) get
+//! their memory pages from a [`PageProvider`]. By default this is the
+//! [`OsPageProvider`], which maps private anonymous memory pages from the
+//! operating system (`mmap` on unix, `VirtualAlloc` on windows). Implementing
+//! [`PageProvider`] allows to plug in other page sources, e.g. a static buffer
+//! on bare metal (see [`StaticBufferProvider`]), a pool of reusable locked
+//! pages (see [`PagePool`], requires `std`), `memfd` backed memory or an
+//! instrumented provider for testing.
+
+use crate::internals::mem;
+use core::fmt;
+use core::ptr::{self, NonNull};
+
+#[cfg(feature = "std")]
+mod pool;
+mod static_buffer;
+
+pub use crate::internals::mem::PageAllocError;
+#[cfg(feature = "std")]
+pub use pool::{DEFAULT_MAX_IDLE, PagePool};
+pub use static_buffer::{StaticBufferError, StaticBufferProvider};
+
+/// Access protection of memory pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Protection {
+    /// The pages can not be accessed.
+    NoAccess,
+    /// The pages can only be read.
+    ReadOnly,
+    /// The pages can be read and written.
+    ReadWrite,
+}
+
+/// Security properties of the memory pages of a [`PageProvider`], returned by
+/// [`PageProvider::protections`].
+///
+/// Every field is `true` iff the property is guarantied for all memory pages
+/// allocated (and locked) by the provider.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct PageProtections {
+    /// The pages are locked in physical memory, so they are not swapped out.
+    pub locked: bool,
+    /// The pages are excluded from kernel same-page merging (KSM on Linux), so
+    /// they are never merged with identical pages.
+    pub unmergeable: bool,
+    /// The pages are excluded from core dumps.
+    pub dump_excluded: bool,
+    /// Child processes created by `fork` don't inherit the contents of the
+    /// pages: the pages are zeroed (or not mapped at all) in the child.
+    pub wiped_on_fork: bool,
+}
+
+/// A source of memory pages.
+///
+/// All lengths are in bytes and must be multiples of the page size; all
+/// pointers passed to the methods must point into a memory region returned by
+/// [`allocate`](Self::allocate) of the same provider which is not yet
+/// released.
+///
+/// # Safety
+/// Implementors must guaranty that:
+/// - [`page_size`](Self::page_size) returns the same non-zero multiple of 8 on
+///   every call, which is a power of two if [`discard`](Self::discard) can
+///   return `true`;
+/// - a successful [`allocate`](Self::allocate) call returns an 8 byte aligned
+///   (and page aligned if [`discard`](Self::discard) can return `true`)
+///   pointer to a memory region of the requested length, which is readable
+///   and writable (until its protection is changed), initialised with zeros,
+///   and not accessed by anything else until it is released;
+/// - memory which is successfully [`lock`](Self::lock)ed (or
+///   [`lock_on_fault`](Self::lock_on_fault)ed) stays readable and writable,
+///   and is not written to swap (as far as the platform allows);
+/// - [`discard`](Self::discard) only returns `true` if the memory region now
+///   reads as all zeros, and stays valid for reads and writes.
+pub unsafe trait PageProvider {
+    /// Error returned when allocating, locking or protecting pages fails.
+    type Error: fmt::Debug + fmt::Display;
+
+    /// Returns the page size in bytes.
+    fn page_size(&self) -> usize;
+
+    /// Allocate a memory region of `len` bytes, initialised with zeros.
+    ///
+    /// # Errors
+    /// Returns an error if no memory could be allocated.
+    fn allocate(&self, len: usize) -> Result<NonNull<u8>, Self::Error>;
+
+    /// Lock the memory region `ptr .. ptr + len` into physical memory, so
+    /// that it is not swapped out.
+    ///
+    /// # Errors
+    /// Returns an error if the memory could not be locked.
+    ///
+    /// # Safety
+    /// The memory region must be allocated by `self` and not yet released.
+    unsafe fn lock(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Self::Error>;
+
+    /// Lock the memory region `ptr .. ptr + len` into physical memory like
+    /// [`lock`](Self::lock), but only populate (and lock) each page when it is
+    /// first accessed. This way, untouched parts of large reservations don't
+    /// use physical memory. The default implementation locks the whole region
+    /// up front using [`lock`](Self::lock).
+    ///
+    /// # Errors
+    /// Returns an error if the memory could not be locked.
+    ///
+    /// # Safety
+    /// The memory region must be allocated by `self` and not yet released.
+    unsafe fn lock_on_fault(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Self::Error> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.lock(ptr, len) }
+    }
+
+    /// Change the access protection of the memory region `ptr .. ptr + len`.
+    ///
+    /// # Errors
+    /// Returns an error if the protection could not be changed.
+    ///
+    /// # Safety
+    /// The memory region must be allocated by `self` and not yet released.
+    /// The memory must not be accessed in a way prohibited by `protection`
+    /// afterwards.
+    unsafe fn protect(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        protection: Protection,
+    ) -> Result<(), Self::Error>;
+
+    /// Release the memory region `ptr .. ptr + len`, unlocking it if it was
+    /// locked.
+    ///
+    /// # Safety
+    /// The memory region must have been returned by a call to
+    /// [`allocate`](Self::allocate) on `self` with the same length, and must
+    /// not be used afterwards.
+    unsafe fn release(&self, ptr: NonNull<u8>, len: usize);
+
+    /// Try to discard the memory pages in the region `ptr .. ptr + len`, so
+    /// that they read as zeros afterwards, without writing zeros to them.
+    ///
+    /// `locked` is `true` iff the region is locked; the region must then stay
+    /// locked. Returns `true` iff the pages were discarded; if `false` is
+    /// returned, the memory is left untouched. The default implementation
+    /// never discards.
+    ///
+    /// # Safety
+    /// The memory region must be allocated by `self` and not yet released.
+    unsafe fn discard(&self, ptr: NonNull<u8>, len: usize, locked: bool) -> bool {
+        let _ = (ptr, len, locked);
+        false
+    }
+
+    /// Returns the security properties of the memory pages of the provider.
+    /// The default implementation guaranties none.
+    fn protections(&self) -> PageProtections {
+        PageProtections::default()
+    }
+}
+
+/// The default page provider, which maps private anonymous memory pages from
+/// the operating system.
+///
+/// This uses `mmap` (with `MAP_NORESERVE` where available), `mlock` (and
+/// `mlock2(MLOCK_ONFAULT)` on Linux for [`PageProvider::lock_on_fault`]),
+/// `mprotect` and `munmap` on unix, and `VirtualAlloc`, `VirtualLock`,
+/// `VirtualProtect` and `VirtualFree` on windows. On platforms without an
+/// operating system page API, allocating pages always fails. On Linux, pages
+/// are excluded from kernel same-page merging using `MADV_UNMERGEABLE` and
+/// from core dumps using `MADV_DONTDUMP`, and are wiped in child processes
+/// using `MADV_WIPEONFORK` (or not mapped in child processes using
+/// `MADV_DONTFORK` before Linux 4.14). On FreeBSD and DragonFly BSD, pages are
+/// excluded from core dumps using `MAP_NOCORE`. See
+/// [`PageProvider::protections`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OsPageProvider;
+
+// SAFETY: the OS page size is a constant power of two of at least 4096; the
+// OS maps fresh private anonymous pages which are zero initialised; `mlock` and
+// `VirtualLock` keep pages in physical memory; pages are only discarded when
+// the kernel guaranties zero-fill-on-demand semantics
+unsafe impl PageProvider for OsPageProvider {
+    type Error = PageAllocError;
+
+    fn page_size(&self) -> usize {
+        mem::page_size()
+    }
+
+    fn allocate(&self, len: usize) -> Result<NonNull<u8>, PageAllocError> {
+        mem::alloc_pages(len, ptr::null_mut())
+    }
+
+    unsafe fn lock(&self, ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { mem::lock_pages(ptr, len) }
+    }
+
+    unsafe fn lock_on_fault(&self, ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { mem::lock_pages_on_fault(ptr, len) }
+    }
+
+    unsafe fn protect(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        protection: Protection,
+    ) -> Result<(), PageAllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { mem::protect_pages(ptr, len, protection) }
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, len: usize) {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { mem::release_pages(ptr, len) }
+    }
+
+    unsafe fn discard(&self, ptr: NonNull<u8>, len: usize, locked: bool) -> bool {
+        // SAFETY: the memory region lies in a private anonymous memory mapping
+        // allocated by us, and the caller must uphold the rest of the safety contract
+        unsafe { mem::discard_pages(ptr.as_ptr(), len, locked) }
+    }
+
+    fn protections(&self) -> PageProtections {
+        let linux = cfg!(all(
+            not(miri),
+            any(target_os = "linux", target_os = "android")
+        ));
+        PageProtections {
+            locked: cfg!(any(unix, windows)),
+            unmergeable: linux,
+            dump_excluded: linux
+                || cfg!(all(
+                    not(miri),
+                    any(target_os = "freebsd", target_os = "dragonfly")
+                )),
+            wiped_on_fork: linux,
+        }
+    }
+}
+
+/// Page provider like [`OsPageProvider`], but which maps memory pages at a
+/// random address. Requires the `randomize` feature.
+///
+/// The address is chosen using the OS RNG and passed as a hint to `mmap` (or
+/// `VirtualAlloc`); if the range is not free, or the OS RNG fails, the OS
+/// chooses the address. This makes the addresses of secure memory pages
+/// independent of the other mappings of the process, so leaking the address of
+/// other memory doesn't reveal where secrets are stored.
+#[cfg(feature = "randomize")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RandomizedOsPageProvider;
+
+#[cfg(feature = "randomize")]
+impl RandomizedOsPageProvider {
+    /// Returns a random address aligned to `page_size` to map new memory pages
+    /// at, or null if the OS RNG fails.
+    fn random_hint(page_size: usize) -> *mut u8 {
+        // stay clear of the program and the heap at the bottom and of the stack at
+        // the top of the user space address space
+        cfg_if::cfg_if! {
+            if #[cfg(target_pointer_width = "64")] {
+                let (low, high): (usize, usize) = (1 << 32, 1 << 46);
+            } else {
+                let (low, high): (usize, usize) = (1 << 28, 1 << 30);
+            }
+        }
+        let Ok(random) = crate::internals::rng::os_random_u64() else {
+            return ptr::null_mut();
+        };
+        let pages = (high - low) / page_size;
+        // the result is less than `pages`, so fits a `usize`
+        #[allow(clippy::cast_possible_truncation)]
+        let page = (random % pages as u64) as usize;
+        ptr::without_provenance_mut(low + page * page_size)
+    }
+}
+
+// SAFETY: identical to `OsPageProvider`, except for the placement of the pages
+#[cfg(feature = "randomize")]
+unsafe impl PageProvider for RandomizedOsPageProvider {
+    type Error = PageAllocError;
+
+    fn page_size(&self) -> usize {
+        OsPageProvider.page_size()
+    }
+
+    fn allocate(&self, len: usize) -> Result<NonNull<u8>, PageAllocError> {
+        mem::alloc_pages(len, Self::random_hint(self.page_size()))
+    }
+
+    unsafe fn lock(&self, ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+        // SAFETY: the pages are allocated like those of `OsPageProvider`
+        unsafe { OsPageProvider.lock(ptr, len) }
+    }
+
+    unsafe fn lock_on_fault(&self, ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+        // SAFETY: the pages are allocated like those of `OsPageProvider`
+        unsafe { OsPageProvider.lock_on_fault(ptr, len) }
+    }
+
+    unsafe fn protect(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        protection: Protection,
+    ) -> Result<(), PageAllocError> {
+        // SAFETY: the pages are allocated like those of `OsPageProvider`
+        unsafe { OsPageProvider.protect(ptr, len, protection) }
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, len: usize) {
+        // SAFETY: the pages are allocated like those of `OsPageProvider`
+        unsafe { OsPageProvider.release(ptr, len) }
+    }
+
+    unsafe fn discard(&self, ptr: NonNull<u8>, len: usize, locked: bool) -> bool {
+        // SAFETY: the pages are allocated like those of `OsPageProvider`
+        unsafe { OsPageProvider.discard(ptr, len, locked) }
+    }
+
+    fn protections(&self) -> PageProtections {
+        OsPageProvider.protections()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_provider_alloc_lock_protect() {
+        let provider = OsPageProvider;
+        let page_size = provider.page_size();
+        assert!(page_size.is_power_of_two());
+        let len = 2 * page_size;
+        let ptr = provider.allocate(len).expect("page allocation failed");
+        // SAFETY: `ptr` is allocated with `len` bytes by `provider`
+        unsafe {
+            assert!((0..len).all(|i| ptr.as_ptr().add(i).read() == 0));
+            provider.lock(ptr, len).expect("page lock failed");
+            ptr.as_ptr().write_bytes(0xAF, len);
+            provider
+                .protect(ptr, len, Protection::ReadOnly)
+                .expect("page protect failed");
+            assert_eq!(ptr.as_ptr().add(len - 1).read(), 0xAF);
+            provider
+                .protect(ptr, len, Protection::ReadWrite)
+                .expect("page protect failed");
+            provider.release(ptr, len);
+        }
+    }
+
+    #[cfg(feature = "randomize")]
+    #[test]
+    fn randomized_provider() {
+        let provider = RandomizedOsPageProvider;
+        let len = provider.page_size();
+        let ptrs = [(); 4].map(|()| provider.allocate(len).expect("page allocation failed"));
+        for ptr in ptrs {
+            assert!(ptr.as_ptr().addr().is_multiple_of(len));
+            // SAFETY: `ptr` is allocated with `len` bytes by `provider`
+            unsafe { provider.release(ptr, len) };
+        }
+    }
+
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn os_provider_not_dumped_or_inherited() {
+        let provider = OsPageProvider;
+        let len = provider.page_size();
+        let ptr = provider.allocate(len).expect("page allocation failed");
+        let flags = mem::smaps_vm_flags(ptr.as_ptr().addr());
+        // SAFETY: `ptr` is allocated with `len` bytes by `provider`
+        unsafe { provider.release(ptr, len) };
+        // excluded from core dumps, and wiped on fork (or not copied before Linux
+        // 4.14)
+        assert!(flags.iter().any(|flag| flag == "dd"), "{flags:?}");
+        assert!(
+            flags.iter().any(|flag| flag == "wf" || flag == "dc"),
+            "{flags:?}"
+        );
+    }
+
+    #[test]
+    fn os_provider_protection_checked() {
+        let provider = OsPageProvider;
+        let len = provider.page_size();
+        let ptr = provider.allocate(len).expect("page allocation failed");
+        // SAFETY: `ptr` is allocated with `len` bytes by `provider`, and not
+        // written while it is read-only
+        unsafe {
+            provider
+                .protect(ptr, len, Protection::ReadOnly)
+                .expect("page protect failed");
+            mem::check_access(ptr.as_ptr(), len, false);
+            // only the miri shims check accesses, the OS would raise `SIGSEGV`
+            let write = std::panic::catch_unwind(|| mem::check_access(ptr.as_ptr(), 8, true));
+            assert_eq!(write.is_err(), cfg!(miri));
+            provider
+                .protect(ptr, len, Protection::ReadWrite)
+                .expect("page protect failed");
+            provider.release(ptr, len);
+        }
+    }
+}
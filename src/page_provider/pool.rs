@@ -0,0 +1,411 @@
+//! Pool of locked memory pages, reused by short lived allocators.
+
+use super::{OsPageProvider, PageAllocError, PageProtections, PageProvider, Protection};
+use crate::internals::mem;
+use core::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::vec::Vec;
+
+/// Default maximal number of idle pages kept by a [`PagePool`].
+pub const DEFAULT_MAX_IDLE: usize = 16;
+
+/// A locked memory page of the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PoolPage(NonNull<u8>);
+
+// SAFETY: memory mappings and locks are process wide, so the page can be used
+// and released from any thread
+unsafe impl Send for PoolPage {}
+
+/// A page handed out by the pool.
+#[derive(Debug)]
+struct LentPage {
+    /// The page.
+    page: PoolPage,
+    /// `true` iff the protection of the page was changed, so it must be
+    /// restored to read-write before the page is reused.
+    protected: bool,
+}
+
+/// State of a [`PagePool`].
+#[derive(Debug)]
+struct PoolState {
+    /// Idle pages: locked and zeroized.
+    idle: Vec<PoolPage>,
+    /// Pages handed out by the pool.
+    lent: Vec<LentPage>,
+    /// Number of idle pages kept by [`PagePool::trim`].
+    min_idle: usize,
+    /// Maximal number of idle pages.
+    max_idle: usize,
+}
+
+/// Page provider keeping a pool of locked, zeroized memory pages, so that
+/// short lived allocators don't have to map, lock and unmap a page every
+/// time.
+///
+/// Allocating a single page takes an idle page from the pool if there is one,
+/// and otherwise maps and locks a new page using the [`OsPageProvider`].
+/// Released pages are checked to be zeroized and put back into the pool, as
+/// long as it holds less than [`max_idle`](Self::set_max_idle) idle pages.
+/// Pages which are not zeroized (e.g. because the allocator leaked memory)
+/// are unmapped instead. Regions of several pages are never pooled.
+///
+/// A pool can be owned by the user, or the process wide pool
+/// [`PagePool::global`] can be used. The provider is implemented for
+/// `&PagePool`:
+/// ```
+/// use secmem_alloc::page_provider::PagePool;
+/// use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+///
+/// for _ in 0..4 {
+///     // only the first allocator maps and locks a page
+///     let allocator = SecStackSinglePageAlloc::new_with_provider(PagePool::global())
+///         .expect("allocator creation failed");
+///     drop(allocator);
+/// }
+/// assert!(PagePool::global().idle_len() >= 1);
+/// ```
+#[derive(Debug)]
+pub struct PagePool {
+    /// State of the pool.
+    state: Mutex<PoolState>,
+}
+
+/// The process wide pool.
+static GLOBAL: PagePool = PagePool::new();
+
+impl PagePool {
+    /// Create a new, empty pool keeping at most [`DEFAULT_MAX_IDLE`] idle
+    /// pages.
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                lent: Vec::new(),
+                min_idle: 0,
+                max_idle: DEFAULT_MAX_IDLE,
+            }),
+        }
+    }
+
+    /// Returns the process wide pool.
+    pub fn global() -> &'static Self {
+        &GLOBAL
+    }
+
+    /// Lock the state of the pool.
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        // the state is consistent at all times, so we can ignore poisoning
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the number of idle pages in the pool.
+    pub fn idle_len(&self) -> usize {
+        self.state().idle.len()
+    }
+
+    /// Set the minimal number of idle pages, and map and lock new pages until
+    /// the pool holds that many. [`Self::trim`] keeps this many idle pages.
+    /// The maximal number of idle pages is raised to `min_idle` if it is
+    /// smaller.
+    ///
+    /// # Errors
+    /// The function returns an `PageAllocError` if a page could not be mapped
+    /// or locked. The pages mapped before are kept in the pool.
+    pub fn set_min_idle(&self, min_idle: usize) -> Result<(), PageAllocError> {
+        let mut state = self.state();
+        state.min_idle = min_idle;
+        state.max_idle = state.max_idle.max(min_idle);
+        while state.idle.len() < min_idle {
+            let page = Self::map_page()?;
+            state.idle.push(page);
+        }
+        Ok(())
+    }
+
+    /// Set the maximal number of idle pages; released pages are unmapped
+    /// when the pool holds that many. Idle pages beyond the new maximum are
+    /// unmapped. The minimal number of idle pages is lowered to `max_idle`
+    /// if it is larger.
+    pub fn set_max_idle(&self, max_idle: usize) {
+        let mut state = self.state();
+        state.max_idle = max_idle;
+        state.min_idle = state.min_idle.min(max_idle);
+        Self::unmap_idle_above(&mut state, max_idle);
+    }
+
+    /// Unmap the idle pages exceeding the [minimal number of idle
+    /// pages](Self::set_min_idle), e.g. after a burst of allocators.
+    pub fn trim(&self) {
+        let mut state = self.state();
+        let min_idle = state.min_idle;
+        Self::unmap_idle_above(&mut state, min_idle);
+    }
+
+    /// Map and lock a new page.
+    fn map_page() -> Result<PoolPage, PageAllocError> {
+        let page_size = mem::page_size();
+        let ptr = OsPageProvider.allocate(page_size)?;
+        // SAFETY: `ptr` is allocated by `OsPageProvider` with length `page_size`
+        if let Err(e) = unsafe { OsPageProvider.lock(ptr, page_size) } {
+            // SAFETY: `ptr` is allocated by `OsPageProvider` with length `page_size`
+            // and not used afterwards
+            unsafe { OsPageProvider.release(ptr, page_size) };
+            return Err(e);
+        }
+        Ok(PoolPage(ptr))
+    }
+
+    /// Unmap idle pages until at most `len` are left.
+    fn unmap_idle_above(state: &mut PoolState, len: usize) {
+        while state.idle.len() > len {
+            let PoolPage(ptr) = state.idle.pop().expect("more than `len` idle pages");
+            // SAFETY: idle pages are allocated by `OsPageProvider` with the page size
+            // as length, and not used anymore
+            unsafe { OsPageProvider.release(ptr, mem::page_size()) };
+        }
+    }
+
+    /// Remove the page at `ptr` from the lent pages, if it was lent.
+    fn take_lent(state: &mut PoolState, ptr: NonNull<u8>) -> Option<LentPage> {
+        let i = state
+            .lent
+            .iter()
+            .position(|lent| lent.page == PoolPage(ptr))?;
+        Some(state.lent.swap_remove(i))
+    }
+}
+
+impl Default for PagePool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PagePool {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        Self::unmap_idle_above(state, 0);
+    }
+}
+
+// SAFETY: single pages are mapped and locked by `OsPageProvider`, and only
+// reused after checking that they are zeroized, with their protection restored
+// to read-write; locking a lent page is a no-op since it is already locked;
+// all other requests are forwarded to `OsPageProvider`
+unsafe impl PageProvider for &PagePool {
+    type Error = PageAllocError;
+
+    fn page_size(&self) -> usize {
+        mem::page_size()
+    }
+
+    fn allocate(&self, len: usize) -> Result<NonNull<u8>, PageAllocError> {
+        if len != mem::page_size() {
+            return OsPageProvider.allocate(len);
+        }
+        let mut state = self.state();
+        let page = match state.idle.pop() {
+            Some(page) => page,
+            None => PagePool::map_page()?,
+        };
+        state.lent.push(LentPage {
+            page,
+            protected: false,
+        });
+        Ok(page.0)
+    }
+
+    unsafe fn lock(&self, ptr: NonNull<u8>, len: usize) -> Result<(), PageAllocError> {
+        if len == mem::page_size()
+            && self
+                .state()
+                .lent
+                .iter()
+                .any(|lent| lent.page == PoolPage(ptr))
+        {
+            // pool pages are locked when they are mapped
+            return Ok(());
+        }
+        // SAFETY: the pages are allocated by `OsPageProvider`, by the safety contract
+        unsafe { OsPageProvider.lock(ptr, len) }
+    }
+
+    unsafe fn protect(
+        &self,
+        ptr: NonNull<u8>,
+        len: usize,
+        protection: Protection,
+    ) -> Result<(), PageAllocError> {
+        // SAFETY: the pages are allocated by `OsPageProvider`, by the safety contract
+        unsafe { OsPageProvider.protect(ptr, len, protection) }?;
+        let mut state = self.state();
+        // the range lies within a single allocation, so it can only contain the
+        // start of a lent page if it starts there
+        if let Some(lent) = state
+            .lent
+            .iter_mut()
+            .find(|lent| lent.page == PoolPage(ptr))
+        {
+            lent.protected = protection != Protection::ReadWrite;
+        }
+        Ok(())
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, len: usize) {
+        let page_size = mem::page_size();
+        let mut state = self.state();
+        let lent = if len == page_size {
+            PagePool::take_lent(&mut state, ptr)
+        } else {
+            None
+        };
+        let Some(lent) = lent else {
+            drop(state);
+            // SAFETY: the pages are allocated by `OsPageProvider`, by the safety contract
+            return unsafe { OsPageProvider.release(ptr, len) };
+        };
+        // SAFETY: the page is allocated by `OsPageProvider` and not yet released
+        let restored = !lent.protected
+            || unsafe { OsPageProvider.protect(ptr, page_size, Protection::ReadWrite) }.is_ok();
+        let zeroized = restored && {
+            mem::check_access(ptr.as_ptr(), page_size, false);
+            // SAFETY: the page is mapped and readable, and no longer used by the safety
+            // contract
+            let page = unsafe { core::slice::from_raw_parts(ptr.as_ptr(), page_size) };
+            page.iter().all(|&b| b == 0)
+        };
+        if zeroized && state.idle.len() < state.max_idle {
+            state.idle.push(lent.page);
+        } else {
+            drop(state);
+            // SAFETY: the page is allocated by `OsPageProvider` with length `page_size`
+            // and not used afterwards
+            unsafe { OsPageProvider.release(ptr, page_size) };
+        }
+    }
+
+    unsafe fn discard(&self, ptr: NonNull<u8>, len: usize, locked: bool) -> bool {
+        // SAFETY: the pages are allocated by `OsPageProvider`, by the safety contract
+        unsafe { OsPageProvider.discard(ptr, len, locked) }
+    }
+
+    fn protections(&self) -> PageProtections {
+        OsPageProvider.protections()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator_api::Box;
+    use crate::sec_alloc::SecStackSinglePageAlloc;
+
+    /// Returns the start of the page containing `ptr`.
+    fn page_of<T>(ptr: *const T) -> usize {
+        ptr.addr() & !(mem::page_size() - 1)
+    }
+
+    #[test]
+    fn pages_reused() {
+        let pool = PagePool::new();
+        let allocator =
+            SecStackSinglePageAlloc::new_with_provider(&pool).expect("allocator creation failed");
+        let value = Box::new_in([0xAF_u8; 16], &allocator);
+        let page = page_of(&*value);
+        drop(value);
+        drop(allocator);
+        assert_eq!(pool.idle_len(), 1);
+        let allocator =
+            SecStackSinglePageAlloc::new_with_provider(&pool).expect("allocator creation failed");
+        assert_eq!(pool.idle_len(), 0);
+        let value = Box::new_in(1_u64, &allocator);
+        assert_eq!(page_of(&*value), page);
+    }
+
+    #[test]
+    fn min_max_trim() {
+        let pool = PagePool::new();
+        pool.set_min_idle(3).expect("mapping pages failed");
+        assert_eq!(pool.idle_len(), 3);
+        pool.set_max_idle(2);
+        assert_eq!(pool.idle_len(), 2);
+        pool.trim();
+        assert_eq!(pool.idle_len(), 2);
+        pool.set_min_idle(0).expect("mapping pages failed");
+        pool.trim();
+        assert_eq!(pool.idle_len(), 0);
+    }
+
+    #[test]
+    fn dirty_and_protected_pages() {
+        let pool = PagePool::new();
+        let provider = &pool;
+        let page_size = provider.page_size();
+        let ptr = provider.allocate(page_size).expect("allocation failed");
+        // SAFETY: `ptr` is allocated by `provider` with length `page_size`, and not
+        // used after the release
+        unsafe {
+            ptr.as_ptr().write(0xAF);
+            provider.release(ptr, page_size);
+        }
+        // the page is not zeroized, so it is unmapped
+        assert_eq!(pool.idle_len(), 0);
+        let ptr = provider.allocate(page_size).expect("allocation failed");
+        // SAFETY: `ptr` is allocated by `provider` with length `page_size`, and not
+        // used after the release
+        unsafe {
+            provider
+                .protect(ptr, page_size, Protection::ReadOnly)
+                .expect("protection failed");
+            provider.release(ptr, page_size);
+        }
+        // the protection is restored, so the page is reused
+        assert_eq!(pool.idle_len(), 1);
+        let ptr = provider.allocate(page_size).expect("allocation failed");
+        // SAFETY: `ptr` is allocated by `provider` with length `page_size`, and not
+        // used after the release
+        unsafe {
+            ptr.as_ptr().write(0);
+            provider.release(ptr, page_size);
+        }
+        assert_eq!(pool.idle_len(), 1);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn set_min_idle_failures() {
+        use crate::testing::{self, PageOp};
+
+        let pool = PagePool::new();
+        testing::reset();
+        // the third page can't be mapped, and the fourth can't be locked
+        testing::fail_nth(PageOp::Map, 3, 12);
+        assert!(pool.set_min_idle(4).is_err());
+        assert_eq!(pool.idle_len(), 2);
+        testing::fail_nth(PageOp::Lock, 2, 12);
+        assert!(pool.set_min_idle(4).is_err());
+        assert_eq!(pool.idle_len(), 3);
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (5, 4, 1));
+        // the pages mapped before the failures are kept, and unmapped on drop
+        drop(pool);
+        let counts = testing::call_counts();
+        assert_eq!(counts.unmap, 4);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn pooled_allocators_skip_syscalls() {
+        let pool = PagePool::new();
+        crate::testing::reset();
+        for _ in 0..4 {
+            let allocator = SecStackSinglePageAlloc::new_with_provider(&pool)
+                .expect("allocator creation failed");
+            drop(Box::new_in([0xAF_u8; 64], &allocator));
+        }
+        let counts = crate::testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (1, 1, 0));
+    }
+}
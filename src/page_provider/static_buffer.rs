@@ -0,0 +1,169 @@
+//! Page provider handing out a caller provided static buffer.
+
+use super::{PageProvider, Protection};
+use crate::zeroize::zeroize_mem;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// Page provider backed by a caller provided `&'static mut [u8]` buffer, e.g.
+/// a buffer placed in a dedicated SRAM section by the linker.
+///
+/// This provider makes no OS calls, so it can be used on bare metal `no_std`
+/// targets. The buffer is handed out as a single page: the page size of the
+/// provider is the length of the buffer, after aligning its start to 8 bytes
+/// and rounding the length down to a multiple of 8. It can be allocated only
+/// once at a time. The buffer is zeroized when it is allocated and released.
+///
+/// Locking is a no-op, since a static buffer is never swapped out, and the
+/// protection of the buffer can not be changed. Use
+/// [`SecStackSinglePageAlloc::from_static_buffer`](crate::sec_alloc::SecStackSinglePageAlloc::from_static_buffer)
+/// to create an allocator backed by a static buffer.
+pub struct StaticBufferProvider {
+    /// Start of the usable part of the buffer.
+    // SAFETY INVARIANT: aligned to 8 bytes
+    ptr: NonNull<u8>,
+    /// Length of the usable part of the buffer.
+    // SAFETY INVARIANT: a non-zero multiple of 8
+    len: usize,
+    /// `true` iff the buffer is allocated.
+    allocated: Cell<bool>,
+    /// This type uniquely borrows the buffer for the rest of the program.
+    _phantom_buf: PhantomData<&'static mut [u8]>,
+}
+
+/// Error returned by the [`StaticBufferProvider`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StaticBufferError {
+    #[error("static buffer is too small")]
+    BufferTooSmall,
+    #[error("static buffer is already allocated")]
+    AlreadyAllocated,
+    #[error("requested length exceeds the static buffer")]
+    LengthExceeded,
+    #[error("static buffer protection can not be changed")]
+    ProtectionUnsupported,
+}
+
+// SAFETY: `StaticBufferProvider` uniquely borrows it's buffer, like a
+// `&'static mut [u8]`
+unsafe impl Send for StaticBufferProvider {}
+
+impl StaticBufferProvider {
+    /// Create a provider handing out the memory of `buf`.
+    ///
+    /// # Errors
+    /// Returns an error if `buf` contains less than 8 bytes at an 8 byte
+    /// aligned address.
+    pub fn new(buf: &'static mut [u8]) -> Result<Self, StaticBufferError> {
+        let offset = buf.as_mut_ptr().align_offset(8);
+        if offset >= buf.len() {
+            return Err(StaticBufferError::BufferTooSmall);
+        }
+        let len = (buf.len() - offset) & !7;
+        if len == 0 {
+            return Err(StaticBufferError::BufferTooSmall);
+        }
+        // SAFETY: `offset < buf.len()` so the result points into `buf`, and is
+        // therefore non-null
+        let ptr = unsafe { NonNull::new_unchecked(buf.as_mut_ptr().add(offset)) };
+        Ok(Self {
+            ptr,
+            len,
+            allocated: Cell::new(false),
+            _phantom_buf: PhantomData,
+        })
+    }
+}
+
+// SAFETY: the page size is a non-zero multiple of 8, and the buffer is 8 byte
+// aligned; the buffer is uniquely borrowed, zeroized on allocation and only
+// handed out once at a time; static memory is never swapped out
+unsafe impl PageProvider for StaticBufferProvider {
+    type Error = StaticBufferError;
+
+    fn page_size(&self) -> usize {
+        self.len
+    }
+
+    fn allocate(&self, len: usize) -> Result<NonNull<u8>, StaticBufferError> {
+        if self.allocated.get() {
+            return Err(StaticBufferError::AlreadyAllocated);
+        }
+        if len > self.len {
+            return Err(StaticBufferError::LengthExceeded);
+        }
+        // SAFETY: `self.ptr` is valid for writes of `self.len` bytes, and not in use
+        // since the buffer is not allocated
+        unsafe { zeroize_mem(self.ptr.as_ptr(), self.len) };
+        self.allocated.set(true);
+        Ok(self.ptr)
+    }
+
+    unsafe fn lock(&self, _ptr: NonNull<u8>, _len: usize) -> Result<(), StaticBufferError> {
+        // static memory is not swapped out
+        Ok(())
+    }
+
+    unsafe fn protect(
+        &self,
+        _ptr: NonNull<u8>,
+        _len: usize,
+        protection: Protection,
+    ) -> Result<(), StaticBufferError> {
+        match protection {
+            Protection::ReadWrite => Ok(()),
+            _ => Err(StaticBufferError::ProtectionUnsupported),
+        }
+    }
+
+    unsafe fn release(&self, _ptr: NonNull<u8>, _len: usize) {
+        // SAFETY: the buffer is allocated, and not used anymore by the safety
+        // contract
+        unsafe { zeroize_mem(self.ptr.as_ptr(), self.len) };
+        self.allocated.set(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    #[test]
+    fn static_buffer_provider() {
+        let buf: &'static mut [u8] = vec![0xAF_u8; 103].leak();
+        let provider = StaticBufferProvider::new(&mut buf[1..]).expect("buffer large enough");
+        assert_eq!(provider.page_size() % 8, 0);
+        assert!(provider.page_size() >= 88);
+        let ptr = provider
+            .allocate(provider.page_size())
+            .expect("allocation failed");
+        assert_eq!(ptr.as_ptr().addr() % 8, 0);
+        assert_eq!(
+            provider.allocate(8),
+            Err(StaticBufferError::AlreadyAllocated)
+        );
+        // SAFETY: `ptr` is allocated by `provider` with length `page_size`
+        unsafe {
+            assert!((0..provider.page_size()).all(|i| ptr.as_ptr().add(i).read() == 0));
+            assert_eq!(
+                provider.protect(ptr, provider.page_size(), Protection::ReadOnly),
+                Err(StaticBufferError::ProtectionUnsupported)
+            );
+            ptr.as_ptr().write_bytes(0xAF, provider.page_size());
+            provider.release(ptr, provider.page_size());
+        }
+        assert!(provider.allocate(provider.page_size()).is_ok());
+    }
+
+    #[test]
+    fn static_buffer_too_small() {
+        let buf: &'static mut [u8] = vec![0_u8; 16].leak();
+        let misaligned = 8 - buf.as_ptr().addr() % 8;
+        assert_eq!(
+            StaticBufferProvider::new(&mut buf[misaligned..misaligned + 7]).err(),
+            Some(StaticBufferError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Panic hook wiping secure memory on panic. Requires the `std` feature.
+//!
+//! When a thread panics, secrets stay in memory until they are dropped during
+//! unwinding. With `panic = "abort"` they are never dropped, so secure memory
+//! pages stay populated until the kernel tears down the process, and a core
+//! dump contains them. The [`PanicHook`] wipes the secure memory pages in the
+//! [`registry`] before the previously installed panic hook runs (which e.g.
+//! prints the panic message).
+//!
+//! # Examples
+//! ```
+//! use secmem_alloc::panic_hook::PanicHook;
+//!
+//! // SAFETY: we don't catch panics, so secrets are not used after a panic
+//! unsafe { PanicHook::new().install() };
+//! ```
+
+use crate::registry;
+
+/// Configuration of the secure memory wiping panic hook.
+///
+/// By default the hook wipes all registered secure memory regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanicHook {
+    /// Wipe all registered regions.
+    wipe_all: bool,
+    /// Wipe the regions created by the panicking thread.
+    wipe_thread_arenas: bool,
+}
+
+impl Default for PanicHook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PanicHook {
+    /// Create the default panic hook configuration, which wipes all
+    /// registered secure memory regions.
+    pub const fn new() -> Self {
+        Self {
+            wipe_all: true,
+            wipe_thread_arenas: false,
+        }
+    }
+
+    /// Enable or disable wiping of all registered secure memory regions on
+    /// panic. Enabled by default.
+    ///
+    /// This is the right choice when panics abort the process. When panics
+    /// unwind and are caught, other threads might continue to use their
+    /// (now wiped) secrets; consider wiping only the arenas of the panicking
+    /// thread instead, see [`Self::wipe_thread_arenas`].
+    pub const fn wipe_all(mut self, wipe_all: bool) -> Self {
+        self.wipe_all = wipe_all;
+        self
+    }
+
+    /// Enable or disable wiping of the secure memory regions created by the
+    /// panicking thread (e.g. thread local secure arenas) on panic. Disabled
+    /// by default; this has no additional effect if
+    /// [`wipe_all`](Self::wipe_all) is enabled.
+    pub const fn wipe_thread_arenas(mut self, wipe_thread_arenas: bool) -> Self {
+        self.wipe_thread_arenas = wipe_thread_arenas;
+        self
+    }
+
+    /// Install the panic hook, and enable the [`registry`].
+    ///
+    /// The hook wipes the configured secure memory regions, and then runs the
+    /// previously installed panic hook. Only secure memory pages created after
+    /// enabling the registry are wiped, so this should be called early, e.g.
+    /// at the start of `main`.
+    ///
+    /// # Panics
+    /// Panics if called from a panicking thread.
+    ///
+    /// # Safety
+    /// The hook destroys secrets while they might still be in use, see
+    /// [`registry::emergency_wipe_all`]. If panics are caught (e.g. using
+    /// [`std::panic::catch_unwind`]) or a thread panics while other threads
+    /// continue, the program must not rely on the contents of allocations in
+    /// wiped regions anymore. In particular, they must be valid when all
+    /// zeros, since they are still dropped during unwinding.
+    pub unsafe fn install(self) {
+        registry::enable();
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(std::boxed::Box::new(move |info| {
+            self.wipe();
+            previous_hook(info);
+        }));
+    }
+
+    /// Wipe the configured secure memory regions.
+    fn wipe(self) {
+        if self.wipe_all {
+            // SAFETY: the user accepted wiping secrets in use by installing the hook
+            unsafe { registry::emergency_wipe_all() };
+        } else if self.wipe_thread_arenas {
+            // SAFETY: the user accepted wiping secrets in use by installing the hook
+            unsafe { registry::emergency_wipe_current_thread() };
+        }
+    }
+}
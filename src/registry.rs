@@ -0,0 +1,334 @@
+//! Opt-in, process wide registry of secure memory regions, for emergency
+//! wiping.
+//!
+//! When tampering is detected or the process receives e.g. `SIGTERM`, it can
+//! be desirable to immediately destroy every secret in memory. After calling
+//! [`enable`], every secure memory page created by this crate (e.g. the page
+//! of a [`SecStackSinglePageAlloc`](crate::sec_alloc::SecStackSinglePageAlloc))
+//! registers itself here on creation, and unregisters when it is released.
+//! [`emergency_wipe_all`] zeroizes all registered regions, and
+//! [`emergency_wipe_current_thread`] those created by the current thread. With
+//! the `std` feature, [`PanicHook`](crate::panic_hook::PanicHook) calls these
+//! on panic.
+//!
+//! The registry is a fixed size table of [`CAPACITY`] slots in static memory,
+//! which is managed using atomics only. In particular [`emergency_wipe_all`]
+//! doesn't allocate, doesn't take locks and doesn't touch allocator metadata,
+//! so it is async-signal-safe and can be called from a signal handler or a
+//! panic hook. Regions created while the registry is full are not registered;
+//! see [`overflowed`].
+
+use crate::zeroize::zeroize_mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+/// Maximal number of simultaneously registered memory regions.
+pub const CAPACITY: usize = 256;
+
+/// The slot is unused.
+const FREE: u8 = 0;
+/// The slot is being filled by [`register`].
+const CLAIMED: u8 = 1;
+/// The slot contains a registered region.
+const REGISTERED: u8 = 2;
+/// The region in the slot is being wiped by [`emergency_wipe_all`].
+const WIPING: u8 = 3;
+
+/// Registry slot for one memory region.
+struct Slot {
+    /// State of the slot, one of `FREE`, `CLAIMED`, `REGISTERED` or `WIPING`.
+    state: AtomicU8,
+    /// Start of the memory region.
+    ptr: AtomicPtr<u8>,
+    /// Length of the memory region in bytes.
+    len: AtomicUsize,
+    /// Marker of the thread which registered the region, see
+    /// [`current_thread_marker`].
+    owner: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    state: AtomicU8::new(FREE),
+    ptr: AtomicPtr::new(ptr::null_mut()),
+    len: AtomicUsize::new(0),
+    owner: AtomicUsize::new(0),
+};
+
+/// The registry.
+static SLOTS: [Slot; CAPACITY] = [EMPTY_SLOT; CAPACITY];
+/// `true` iff registration is enabled.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// `true` iff a region could not be registered since the registry was full.
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+/// Enable the registry: secure memory pages created from now on are
+/// registered, so they are wiped by [`emergency_wipe_all`].
+///
+/// Pages created before calling this function are not registered, so this
+/// should be called early, e.g. at the start of `main`. The registry can not
+/// be disabled again.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Returns `true` iff the registry is enabled, see [`enable`].
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns `true` iff a secure memory region could not be registered since the
+/// registry was full. Such regions are not wiped by [`emergency_wipe_all`].
+pub fn overflowed() -> bool {
+    OVERFLOWED.load(Ordering::Acquire)
+}
+
+/// Number of regions wiped by [`emergency_wipe_all`] and
+/// [`emergency_wipe_current_thread`] since the process started.
+static WIPE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of regions wiped by an emergency wipe since the process
+/// started. It is incremented before a region is wiped, so it changed if the
+/// memory of a region was zeroized by an emergency wipe.
+pub(crate) fn wipe_count() -> usize {
+    WIPE_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns the number of currently registered memory regions.
+pub fn registered_count() -> usize {
+    SLOTS
+        .iter()
+        .filter(|slot| slot.state.load(Ordering::Acquire) >= REGISTERED)
+        .count()
+}
+
+/// Next thread id returned by [`current_thread_marker`].
+#[cfg(feature = "std")]
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns a non-zero number uniquely identifying the current thread, or `0`
+/// without the `std` feature.
+///
+/// Threads are assigned an id from a global counter on first use, which is
+/// never reused, even after the thread exited. The id is stored in a thread
+/// local, and the first access to it on a thread might allocate (e.g. in
+/// `__tls_get_addr` when this crate is in a dynamically loaded library). So
+/// this is only async-signal-safe on threads which called it before, e.g. by
+/// registering a region.
+fn current_thread_marker() -> usize {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "std")] {
+            std::thread_local! {
+                static THREAD_ID: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+            }
+            THREAD_ID.with(|id| {
+                if id.get() == 0 {
+                    id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+                }
+                id.get()
+            })
+        } else {
+            0
+        }
+    }
+}
+
+/// Register the memory region `ptr .. ptr + len` if the registry is enabled.
+///
+/// Memory regions must be unregistered using [`unregister`] before the memory
+/// is released.
+pub(crate) fn register(ptr: *mut u8, len: usize) {
+    if is_enabled() {
+        register_region(ptr, len);
+    }
+}
+
+/// Register the memory region `ptr .. ptr + len`, independent of whether the
+/// registry is enabled.
+fn register_region(ptr: *mut u8, len: usize) {
+    for slot in &SLOTS {
+        if slot
+            .state
+            .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            slot.ptr.store(ptr, Ordering::Relaxed);
+            slot.len.store(len, Ordering::Relaxed);
+            slot.owner.store(current_thread_marker(), Ordering::Relaxed);
+            // publish the region to `emergency_wipe_all`
+            slot.state.store(REGISTERED, Ordering::Release);
+            return;
+        }
+    }
+    OVERFLOWED.store(true, Ordering::Release);
+}
+
+/// Unregister the memory region starting at `ptr`, if it is registered.
+///
+/// When this function returns, the region is not (and will not be) wiped by
+/// [`emergency_wipe_all`], so it can be safely released.
+pub(crate) fn unregister(ptr: *mut u8) {
+    for slot in &SLOTS {
+        if slot.state.load(Ordering::Acquire) < REGISTERED
+            || slot.ptr.load(Ordering::Relaxed) != ptr
+        {
+            continue;
+        }
+        // wait until a concurrent wipe of the region finished; the wiping thread never
+        // waits on us, so this can't deadlock (also not when `emergency_wipe_all` runs
+        // in a signal handler on this thread, since it then completes before we resume)
+        while slot
+            .state
+            .compare_exchange_weak(REGISTERED, FREE, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+}
+
+/// Change the length of the registered memory region starting at `ptr` to
+/// `len`, if it is registered.
+///
+/// A concurrent [`emergency_wipe_all`] might still use the old length, so the
+/// old region must stay valid for writes.
+pub(crate) fn update_len(ptr: *mut u8, len: usize) {
+    for slot in &SLOTS {
+        if slot.state.load(Ordering::Acquire) >= REGISTERED
+            && slot.ptr.load(Ordering::Relaxed) == ptr
+        {
+            slot.len.store(len, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+/// Returns `true` iff `addr` lies in a registered memory region.
+///
+/// Regions which are concurrently registered or unregistered might be missed
+/// (or still be found).
+#[cfg(feature = "std")]
+pub(crate) fn contains(addr: usize) -> bool {
+    SLOTS.iter().any(|slot| {
+        slot.state.load(Ordering::Acquire) >= REGISTERED && {
+            let start = slot.ptr.load(Ordering::Relaxed).addr();
+            let len = slot.len.load(Ordering::Relaxed);
+            addr.wrapping_sub(start) < len
+        }
+    })
+}
+
+/// Zeroize all registered secure memory regions.
+///
+/// Returns the number of wiped regions. This function is async-signal-safe:
+/// it doesn't allocate, doesn't take locks and doesn't touch allocator
+/// metadata. Regions which are concurrently registered or unregistered might
+/// be skipped.
+///
+/// # Safety
+/// All secrets in the registered regions are destroyed, even while they are
+/// in use (possibly by other threads). Afterwards, the contents of all live
+/// allocations in those regions are unspecified (zeros, or newly written
+/// data). The caller must ensure the process does not rely on the contents of
+/// these allocations anymore, e.g. by terminating the process right after the
+/// wipe.
+pub unsafe fn emergency_wipe_all() -> usize {
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { wipe_where(|_owner| true) }
+}
+
+/// Zeroize all registered secure memory regions which were created by the
+/// current thread, e.g. the pages of thread local secure arenas.
+///
+/// Returns the number of wiped regions. Without the `std` feature, the
+/// creating thread of regions is not tracked and no regions are wiped.
+///
+/// Unlike [`emergency_wipe_all`], this function is only async-signal-safe on
+/// threads which registered a secure memory region before (so on every
+/// thread which has regions to wipe): the current thread is identified using
+/// a thread local, and the first access to it on a thread might allocate.
+///
+/// # Safety
+/// Identical to [`emergency_wipe_all`], but only for the regions created by
+/// the current thread.
+pub unsafe fn emergency_wipe_current_thread() -> usize {
+    let marker = current_thread_marker();
+    if marker == 0 {
+        return 0;
+    }
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { wipe_where(|owner| owner == marker) }
+}
+
+/// Zeroize all registered regions for which `filter` returns `true` when
+/// called with the marker of the thread that registered the region.
+///
+/// # Safety
+/// See [`emergency_wipe_all`].
+unsafe fn wipe_where(filter: impl Fn(usize) -> bool) -> usize {
+    let mut count = 0;
+    for slot in &SLOTS {
+        if slot
+            .state
+            .compare_exchange(REGISTERED, WIPING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            continue;
+        }
+        if filter(slot.owner.load(Ordering::Relaxed)) {
+            let ptr = slot.ptr.load(Ordering::Relaxed);
+            let len = slot.len.load(Ordering::Relaxed);
+            // count the wipe before zeroizing, so the zeros are never observed with the
+            // old count (also not by the thread interrupted by a signal handler)
+            WIPE_COUNT.fetch_add(1, Ordering::SeqCst);
+            // SAFETY: the region is registered, so it is valid for writes (`unregister`
+            // waits for us before the region is released); the caller accepts that the
+            // memory is overwritten while in use
+            unsafe { zeroize_mem(ptr, len) };
+            count += 1;
+        }
+        slot.state.store(REGISTERED, Ordering::Release);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_wipe_unregister() {
+        let mut buf = [0xAF_u8; 123];
+        let ptr = buf.as_mut_ptr();
+        register_region(ptr, buf.len());
+        assert!(registered_count() >= 1);
+        // SAFETY: `buf` is not in use
+        assert!(unsafe { emergency_wipe_all() } >= 1);
+        unregister(ptr);
+        assert_eq!(buf, [0; 123]);
+        assert!(wipe_count() >= 1);
+        assert!(
+            SLOTS
+                .iter()
+                .all(|slot| slot.state.load(Ordering::Acquire) < REGISTERED
+                    || slot.ptr.load(Ordering::Relaxed) != ptr)
+        );
+    }
+    #[cfg(feature = "std")]
+    #[test]
+    fn thread_markers_not_reused() {
+        let marker = current_thread_marker();
+        assert_ne!(marker, 0);
+        assert_eq!(current_thread_marker(), marker);
+        let first = std::thread::spawn(current_thread_marker)
+            .join()
+            .expect("thread panicked");
+        // the first thread exited, so its thread locals may be reused
+        let second = std::thread::spawn(current_thread_marker)
+            .join()
+            .expect("thread panicked");
+        assert_ne!(first, marker);
+        assert_ne!(first, second);
+    }
+}
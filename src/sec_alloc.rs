@@ -12,6 +12,10 @@
 //! change the `mlock` limit using `setrlimit` from libc (available in rust
 //! through the `secmem-proc` crate).
 //!
+//! On targets without an operating system, an allocator can be backed by a
+//! static buffer instead, see
+//! [`SecStackSinglePageAlloc::from_static_buffer`].
+//!
 //! Various security measures are implemented:
 //! - Zeroization of memory on drop.
 //! - Non-swappable locked memory.
@@ -21,16 +25,31 @@
 //!   but not impossible.
 
 use crate::internals::mem;
+#[cfg(feature = "randomize")]
+use crate::internals::rng::Rng;
+use crate::page_provider::{
+    OsPageProvider, PageProtections, PageProvider, StaticBufferError, StaticBufferProvider,
+};
 use crate::util::{
     align_up_ptr_mut, align_up_usize, is_aligned_ptr, large_offset_from, nonnull_as_mut_ptr,
     unlikely,
 };
-use crate::zeroize::zeroize_mem;
+use crate::zeroize::WipeOptions;
 use allocator_api2::alloc::{AllocError, Allocator};
+use bitmap::{AllocBitmap, InvalidFree};
 use core::alloc::Layout;
 use core::cell::Cell;
 use core::ptr::{self, NonNull};
 use mirai_annotations::debug_checked_precondition;
+use quarantine::Quarantine;
+
+mod bitmap;
+mod quarantine;
+mod stack_ref;
+
+#[cfg(feature = "randomize")]
+pub use crate::internals::rng::RngError;
+pub use stack_ref::{PopOrderError, SecStackRef};
 
 /// Memory allocator for confidential memory. See the module level
 /// documentation.
@@ -48,6 +67,12 @@ use mirai_annotations::debug_checked_precondition;
 /// This is not a zero sized type and should not be dropped before all it's
 /// memory is deallocated. The same allocator instance must be used for
 /// allocation and deallocation.
+/// To tie the lifetime of the allocator to that of it's allocations instead,
+/// use an owned handle from the [`shared_alloc`](crate::shared_alloc) module.
+///
+/// The allocator is [`Send`], so it can be created on one thread and moved to
+/// another, but not [`Sync`]. To share it between threads, use an
+/// [`ArcAlloc`](crate::shared_alloc::ArcAlloc) handle.
 ///
 /// # Panics
 /// If debug assertions are enabled, *some* of the safety requirement for using
@@ -71,11 +96,13 @@ use mirai_annotations::debug_checked_precondition;
 /// like manner, that is, always only deallocate, shrink or grow the
 /// last created allocation, and request at most 8 byte alignment for all but
 /// the first allocation.
-pub struct SecStackSinglePageAlloc {
+/// The typed stack API [`push`](Self::push) enforces this discipline: values
+/// are always pushed on top of the stack and popped in reverse order.
+pub struct SecStackSinglePageAlloc<P: PageProvider = OsPageProvider> {
     /// The number of bytes currently allocated.
     bytes: Cell<usize>,
     /// Page of allocated mlocked memory.
-    page: mem::Page,
+    page: mem::Page<P>,
     // /// Top of the stack, i.e. pointer to the first byte of available memory.
     // stack_ptr: Cell<NonNull<u8>>,
     /// Top of the stack, i.e. offset to the first byte of available memory.
@@ -84,23 +111,60 @@ pub struct SecStackSinglePageAlloc {
     /// Page size always fits an `isize` so this can safely be cast to an
     /// `isize`.
     // SAFETY INVARIANT: always a multiple of 8
-    // SAFETY INVARIANT: at most page size (`self.page.page_size()`)
+    // SAFETY INVARIANT: at most `self.capacity`
     stack_offset: Cell<usize>,
+    /// Number of bytes at the start of the page which can be allocated. The
+    /// rest of the page stores the allocation bitmaps, if enabled.
+    // SAFETY INVARIANT: a multiple of 8, at most page size (`self.page.page_size()`)
+    capacity: usize,
+    /// Allocation bitmaps, stored in the page after `self.capacity`, if
+    /// allocation tracking is enabled.
+    bitmap: Option<AllocBitmap>,
+    /// Deallocated blocks which are not yet reused, if the quarantine is
+    /// enabled. Quarantined blocks are counted in `self.bytes`.
+    quarantine: Quarantine,
+    /// Random number generator for random placement, if enabled.
+    #[cfg(feature = "randomize")]
+    rng: Option<Rng>,
+    /// How to wipe deallocated memory.
+    wipe: WipeOptions,
 }
 
-impl SecStackSinglePageAlloc {
+/// Marker for the state of a [`SecStackSinglePageAlloc`], returned by
+/// [`SecStackSinglePageAlloc::checkpoint`].
+///
+/// Rewinding the allocator to the checkpoint using
+/// [`SecStackSinglePageAlloc::rewind`] releases all allocations made after the
+/// checkpoint at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct Checkpoint {
+    /// Address of the page of the allocator the checkpoint was created with.
+    page_addr: usize,
+    /// Stack offset at the checkpoint.
+    // SAFETY INVARIANT: a multiple of 8, at most the capacity of the allocator
+    stack_offset: usize,
+    /// Number of allocated bytes at the checkpoint.
+    bytes: usize,
+}
+
+impl<P: PageProvider> SecStackSinglePageAlloc<P> {
     #[cfg(test)]
     /// Panic on inconsistent internal state.
     fn consistency_check(&self) {
         let bytes = self.bytes.get();
         let stack_offset = self.stack_offset.get();
         assert!(
-            stack_offset % 8 == 0,
+            stack_offset.is_multiple_of(8),
             "safety critical SecStackSinglePageAlloc invariant: offset alignment"
         );
         assert!(
-            stack_offset <= self.page.page_size(),
-            "safety critical SecStackSinglePageAlloc invariant: offset in page size"
+            stack_offset <= self.capacity,
+            "safety critical SecStackSinglePageAlloc invariant: offset in capacity"
+        );
+        assert!(
+            self.capacity <= self.page.page_size() && self.capacity.is_multiple_of(8),
+            "safety critical SecStackSinglePageAlloc invariant: capacity"
         );
         assert!(
             is_aligned_ptr(self.page.as_ptr(), 8),
@@ -111,24 +175,36 @@ impl SecStackSinglePageAlloc {
             "critical SecStackSinglePageAlloc consistency: allocated bytes in offset"
         );
         assert!(
-            bytes % 8 == 0,
+            bytes.is_multiple_of(8),
             "SecStackSinglePageAlloc consistency: allocated bytes 8 multiple"
         );
     }
 }
 
+impl<P: PageProvider> Drop for SecStackSinglePageAlloc<P> {
+    fn drop(&mut self) {
+        // quarantined blocks contain poison; wipe them to zero before the page is
+        // released
+        self.flush_quarantine();
+        #[cfg(debug_assertions)]
+        self.check_on_drop();
+    }
+}
+
 #[cfg(debug_assertions)]
-impl Drop for SecStackSinglePageAlloc {
+impl<P: PageProvider> SecStackSinglePageAlloc<P> {
     // panic in drop leads to abort, so we better just abort
     // however, abort is only stably available with `std` (not `core`)
     #[cfg(feature = "std")]
-    fn drop(&mut self) {
+    /// Check for leaks and that the page is zeroized, at drop.
+    fn check_on_drop(&self) {
         // check for leaks
         if self.bytes.get() != 0 {
             std::process::abort();
         }
         // check that the entire page contains only zeroized memory
         let page_ptr: *const u8 = self.page.as_ptr();
+        mem::check_access(page_ptr, self.page.page_size(), false);
         for offset in 0..self.page.page_size() {
             // SAFETY: `page_ptr + offset` still points into the memory page, but `offset`
             // doesn't necessarily fit `isize` so we have to use `wrapping_add`
@@ -140,11 +216,13 @@ impl Drop for SecStackSinglePageAlloc {
     }
 
     #[cfg(not(feature = "std"))]
-    fn drop(&mut self) {
+    /// Check for leaks and that the page is zeroized, at drop.
+    fn check_on_drop(&self) {
         // check for leaks
         debug_assert!(self.bytes.get() == 0);
         // check that the entire page contains only zeroized memory
         let page_ptr: *const u8 = self.page.as_ptr();
+        mem::check_access(page_ptr, self.page.page_size(), false);
         for offset in 0..self.page.page_size() {
             // SAFETY: `page_ptr + offset` still points into the memory page, but `offset`
             // doesn't necessarily fit `isize` so we have to use `wrapping_add`
@@ -170,18 +248,546 @@ impl SecStackSinglePageAlloc {
     /// on Linux. A process with `CAP_SYS_RESOURCE` can change the `mlock`
     /// limit using `setrlimit` from libc.
     pub fn new() -> Result<Self, mem::PageAllocError> {
-        let page = mem::Page::alloc_new_lock()?;
-        //let stack_ptr = page.page_ptr_nonnull();
-        Ok(Self {
+        Self::new_with_provider(OsPageProvider)
+    }
+}
+
+impl SecStackSinglePageAlloc<StaticBufferProvider> {
+    /// Create a new `SecStackSinglePageAlloc` allocator backed by the static
+    /// buffer `buf`, e.g. a buffer placed in a dedicated SRAM section on an
+    /// embedded target.
+    ///
+    /// The buffer is zeroized and then used like the memory page of an
+    /// allocator created by [`Self::new`], with the same bump allocation and
+    /// zeroize on deallocation semantics. No OS calls are made, so this works
+    /// on bare metal `no_std` targets. The start of the buffer is aligned to 8
+    /// bytes and it's length rounded down to a multiple of 8, see
+    /// [`StaticBufferProvider`]. Use the fallible allocation methods (e.g.
+    /// [`Self::try_push`]) to avoid depending on
+    /// [`handle_alloc_error`](alloc::alloc::handle_alloc_error).
+    ///
+    /// # Errors
+    /// Returns an error if `buf` contains less than 8 bytes at an 8 byte
+    /// aligned address.
+    pub fn from_static_buffer(buf: &'static mut [u8]) -> Result<Self, StaticBufferError> {
+        Self::new_with_provider(StaticBufferProvider::new(buf)?)
+    }
+}
+
+impl<P: PageProvider> SecStackSinglePageAlloc<P> {
+    /// Create a new `SecStackSinglePageAlloc` allocator backed by a page of
+    /// memory from `provider`. The page is allocated and locked using the
+    /// provider, and only released once the allocator is dropped.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if no page could be
+    /// allocated or if the page could not be locked.
+    pub fn new_with_provider(provider: P) -> Result<Self, P::Error> {
+        let page = mem::Page::alloc_new_lock_in(provider)?;
+        let capacity = page.page_size();
+        let alloc = Self {
             bytes: Cell::new(0),
             page,
-            //stack_ptr,
             stack_offset: Cell::new(0),
-        })
+            capacity,
+            bitmap: None,
+            quarantine: Quarantine::new(0),
+            #[cfg(feature = "randomize")]
+            rng: None,
+            // a single page is always smaller than the discard threshold, so
+            // discarding pages would never pay off
+            wipe: WipeOptions {
+                discard_pages: false,
+                flush_cache: false,
+            },
+        };
+        Ok(alloc.with_alloc_tracking(cfg!(feature = "hardened")))
+    }
+
+    /// Returns a reference to the page provider of the allocator.
+    pub fn provider(&self) -> &P {
+        self.page.provider()
+    }
+
+    /// Returns the security properties of the memory page of the allocator,
+    /// e.g. whether it is locked and excluded from kernel same-page merging.
+    pub fn protections(&self) -> PageProtections {
+        self.page.provider().protections()
+    }
+
+    /// Enable or disable allocation tracking.
+    ///
+    /// When enabled, the allocator records the start and size of every
+    /// allocation in bitmaps (one bit per 8 byte granule), which are stored at
+    /// the end of the memory page, reducing the memory available for
+    /// allocations by about 3%. Deallocating (or reallocating) a pointer which
+    /// is not the start of a live allocation (e.g. a double free or an
+    /// interior pointer) or with the wrong layout then deterministically
+    /// aborts the process, instead of corrupting the allocator state. Enabled
+    /// by default with the `hardened` feature, use `with_alloc_tracking(true)`
+    /// to opt in otherwise. Pages too small to store the bitmaps are not
+    /// tracked.
+    ///
+    /// # Panics
+    /// Panics if memory is allocated with the allocator.
+    pub fn with_alloc_tracking(mut self, enable: bool) -> Self {
+        assert!(
+            self.bytes.get() == 0,
+            "allocation tracking can only be changed on an unused allocator"
+        );
+        let page_size = self.page.page_size();
+        let reserved = AllocBitmap::reserved_bytes(page_size);
+        if enable && reserved < page_size {
+            // SAFETY: `reserved` is a multiple of 8 less than the page size, so the
+            // result is an 8 byte aligned pointer into the page
+            let bits =
+                unsafe { NonNull::new_unchecked(self.page.as_ptr_mut().add(page_size - reserved)) };
+            // SAFETY: the bitmap memory lies at the end of the page, which outlives the
+            // bitmap; nothing is allocated, so the whole page contains zeros
+            self.bitmap = Some(unsafe { AllocBitmap::new(bits, page_size) });
+            self.capacity = page_size - reserved;
+        } else {
+            self.bitmap = None;
+            self.capacity = page_size;
+        }
+        // emergency wipes must not destroy the allocation bitmaps, so the allocator
+        // stays usable (and only contains zeros) afterwards
+        crate::registry::update_len(self.page.as_ptr_mut(), self.capacity);
+        self.stack_offset.set(self.initial_offset());
+        self
+    }
+
+    /// Returns `true` iff allocation tracking is enabled, see
+    /// [`Self::with_alloc_tracking`].
+    pub fn alloc_tracking(&self) -> bool {
+        self.bitmap.is_some()
+    }
+
+    /// Enable or disable random placement of allocations. Requires the
+    /// `randomize` feature.
+    ///
+    /// When enabled, the first allocation on the (empty) memory page starts at a
+    /// random offset in the first eighth of the page (at most 512 bytes), and
+    /// every allocation is preceded by a random gap of up to 56 bytes, using a
+    /// pseudo random number generator seeded from the OS RNG. The address of an
+    /// allocation then doesn't reveal the addresses of its neighbours. All
+    /// allocations stay 8 byte aligned. This costs memory, and the typed stack
+    /// API only reclaims a gap when the value after it is popped in order.
+    /// Combine with [`RandomizedOsPageProvider`] to also place the page itself
+    /// at a random address. Disabled by default.
+    ///
+    /// [`RandomizedOsPageProvider`]: crate::page_provider::RandomizedOsPageProvider
+    ///
+    /// # Errors
+    /// Returns an error if random placement is enabled, but the OS RNG fails.
+    ///
+    /// # Panics
+    /// Panics if memory is allocated with the allocator.
+    #[cfg(feature = "randomize")]
+    pub fn with_random_placement(mut self, enable: bool) -> Result<Self, RngError> {
+        assert!(
+            self.bytes.get() == 0,
+            "random placement can only be changed on an unused allocator"
+        );
+        self.rng = if enable { Some(Rng::from_os()?) } else { None };
+        self.stack_offset.set(self.initial_offset());
+        Ok(self)
+    }
+
+    /// Returns `true` iff random placement is enabled, see
+    /// [`Self::with_random_placement`].
+    #[cfg(feature = "randomize")]
+    pub fn random_placement(&self) -> bool {
+        self.rng.is_some()
+    }
+
+    /// Returns the stack offset of the empty allocator: random with random
+    /// placement enabled, and 0 otherwise.
+    fn initial_offset(&self) -> usize {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "randomize")] {
+                match &self.rng {
+                    // a multiple of 8 less than an eighth of the capacity, which is a
+                    // multiple of 8
+                    Some(rng) => 8 * rng.below(self.capacity.min(8 * 512) / 64),
+                    None => 0,
+                }
+            } else {
+                0
+            }
+        }
+    }
+
+    /// Returns the gap to leave before the next allocation: random with random
+    /// placement enabled, and 0 otherwise. A multiple of 8.
+    fn random_gap(&self) -> usize {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "randomize")] {
+                self.rng.as_ref().map_or(0, |rng| 8 * rng.below(8))
+            } else {
+                0
+            }
+        }
+    }
+
+    /// Enable or disable flushing of the CPU caches after zeroization.
+    ///
+    /// When enabled, deallocated memory is zeroized using
+    /// [`zeroize_mem_flush`](crate::zeroize_mem_flush), which writes the zeros
+    /// back to main memory, so the old contents do not linger in DRAM. Disabled
+    /// by default.
+    pub fn with_cache_flush(mut self, flush_cache: bool) -> Self {
+        self.wipe.flush_cache = flush_cache;
+        self
+    }
+
+    /// Pre-fault the free memory of the page for the next `capacity` bytes of
+    /// allocations, so that writing to them doesn't cause page faults, e.g.
+    /// in a latency critical code path. Use `usize::MAX` to pre-fault all
+    /// free memory. See [`Self::with_populate`] to populate the page at
+    /// creation.
+    ///
+    /// Locking the page normally populates it, but on some kernels the first
+    /// write still faults (e.g. to break copy-on-write sharing of the zero
+    /// page). This uses `madvise(MADV_POPULATE_WRITE)` on Linux where
+    /// supported, and otherwise writes to every memory page. The memory is
+    /// not modified.
+    pub fn prewarm(&self, capacity: usize) {
+        let start = self.stack_offset.get();
+        // the allocation bitmaps after `self.capacity` are in use
+        let len = capacity.min(self.capacity - start);
+        // SAFETY: `start <= self.capacity <= page_size` by the invariants, so the
+        // pointer lies in the memory page (or one past it), and is therefore non-null
+        let ptr = unsafe { NonNull::new_unchecked(self.page.as_ptr_mut().add(start)) };
+        // SAFETY: the memory from `start` up to `self.capacity` is valid for reads and
+        // writes, and not in use since it is free; `self` is not `Sync` so it is not
+        // allocated concurrently
+        unsafe { mem::populate_pages(ptr, len) };
+    }
+
+    /// Populate the memory page when `populate` is `true`, so that
+    /// allocations from the fresh allocator don't cause page faults, like
+    /// [`Self::prewarm`] with `usize::MAX`. Meant to be called directly after
+    /// creating the allocator.
+    pub fn with_populate(self, populate: bool) -> Self {
+        if populate {
+            self.prewarm(usize::MAX);
+        }
+        self
+    }
+
+    /// Enable the quarantine of deallocated blocks (hardened mode), with room
+    /// for `len` blocks, or disable it if `len` is zero.
+    ///
+    /// With the quarantine enabled, deallocated blocks are wiped and then
+    /// filled with a poison pattern instead of zeros, and kept in a first-in
+    /// first-out queue of `len` blocks before their memory can be reused. When
+    /// a block leaves the quarantine, the poison is verified and the block
+    /// wiped to zero. A modified poison pattern indicates a write after free,
+    /// and aborts the process. Quarantined blocks still take up memory, but the
+    /// quarantine is flushed when an allocation doesn't fit otherwise, and
+    /// when the allocator is dropped (so the page is zeroized before it's
+    /// released). Shrinking an allocation in place doesn't quarantine the
+    /// released tail. Disabled by default.
+    ///
+    /// Currently quarantined blocks are released first.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds [`Self::MAX_QUARANTINE_LEN`]. Aborts the process
+    /// if a write after free is detected.
+    pub fn with_quarantine(mut self, len: usize) -> Self {
+        assert!(
+            len <= Self::MAX_QUARANTINE_LEN,
+            "quarantine length exceeds `MAX_QUARANTINE_LEN`"
+        );
+        self.flush_quarantine();
+        self.quarantine = Quarantine::new(len);
+        self
+    }
+
+    /// Maximal number of blocks in the quarantine, see
+    /// [`Self::with_quarantine`].
+    pub const MAX_QUARANTINE_LEN: usize = quarantine::MAX_LEN;
+
+    /// Returns the number of blocks the quarantine has room for (zero if it is
+    /// disabled), see [`Self::with_quarantine`].
+    pub fn quarantine_len(&self) -> usize {
+        self.quarantine.capacity()
+    }
+
+    /// Release all quarantined blocks, verifying their poison pattern and
+    /// wiping them to zero, so their memory can be reused. See
+    /// [`Self::with_quarantine`].
+    ///
+    /// # Panics
+    /// Aborts the process if a write after free is detected.
+    pub fn flush_quarantine(&self) {
+        while let Some((offset, len, wipes)) = self.quarantine.pop() {
+            // SAFETY: the block was deallocated and is quarantined, so it lies in the
+            // memory page and is not in use
+            unsafe { self.release_quarantined(offset, len, wipes) };
+        }
+    }
+
+    /// Create a checkpoint of the current state of the allocator, to which it
+    /// can later be rewound using [`Self::rewind`].
+    ///
+    /// This flushes the quarantine, see [`Self::flush_quarantine`].
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.flush_quarantine();
+        Checkpoint {
+            page_addr: self.page.as_ptr().addr(),
+            stack_offset: self.stack_offset.get(),
+            bytes: self.bytes.get(),
+        }
+    }
+
+    /// Rewind the allocator to the checkpoint `marker`, releasing all
+    /// allocations made after the checkpoint at once.
+    ///
+    /// The released memory is wiped in a single operation, and the top of the
+    /// stack is reset to where it was at the checkpoint. Allocations made after
+    /// the checkpoint which were leaked are reclaimed too.
+    ///
+    /// # Panics
+    /// Panics if `marker` was not created by `self`, or if the top of the stack
+    /// lies below the checkpoint (e.g. because the allocator was rewound to
+    /// an earlier checkpoint already).
+    ///
+    /// # Safety
+    /// All allocations made with `self` after the checkpoint was created are
+    /// released: they must not be used or deallocated afterwards. Allocations
+    /// which existed at the checkpoint must not have been deallocated, shrunk or
+    /// grown since.
+    ///
+    /// See [`Self::scope`] for a safe alternative.
+    pub unsafe fn rewind(&self, marker: Checkpoint) {
+        assert_eq!(
+            marker.page_addr,
+            self.page.as_ptr().addr(),
+            "checkpoint of a different allocator"
+        );
+        let stack_offset = self.stack_offset.get();
+        assert!(
+            marker.stack_offset <= stack_offset,
+            "checkpoint lies above the top of the stack"
+        );
+        // SAFETY: `marker.stack_offset` is at most `stack_offset`, which is at most
+        // the page size, so the result points into the memory page or one byte past it
+        let start: *mut u8 = unsafe { self.page.as_ptr_mut().add(marker.stack_offset) };
+        // securely wipe all memory allocated after the checkpoint
+        // SAFETY: the memory range lies in our locked private anonymous memory page,
+        // and by the safety contract it is no longer in use
+        unsafe {
+            self.wipe_mem(start, stack_offset - marker.stack_offset);
+        }
+        if let Some(bitmap) = &self.bitmap {
+            bitmap.clear_from(marker.stack_offset / 8);
+        }
+        // the quarantine was flushed at the checkpoint, so quarantined blocks were
+        // allocated after it and are wiped now
+        self.quarantine.remove_from(marker.stack_offset);
+        // SAFETY: `marker.stack_offset` is a multiple of 8 and at most the capacity
+        // since it was the stack offset at the checkpoint
+        self.stack_offset.set(marker.stack_offset);
+        self.bytes.set(marker.bytes);
+    }
+
+    /// Run `f` with a scoped arena: all allocations made with the allocator
+    /// passed to `f` are released, and their memory wiped, when `f` returns
+    /// (or panics).
+    ///
+    /// Allocations can't escape the closure since they borrow the allocator
+    /// reference passed to `f`. This makes it easy to allocate many temporary
+    /// secrets, e.g. for a single cryptographic operation, and to discard them
+    /// all at once. Leaked allocations are reclaimed too.
+    ///
+    /// # Examples
+    /// ```
+    /// # #![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+    /// use secmem_alloc::allocator_api::{Box, Vec};
+    /// use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+    ///
+    /// let mut arena = SecStackSinglePageAlloc::new().unwrap();
+    /// let checksum = arena.scope(|arena| {
+    ///     let key = Box::new_in([0xAF_u8; 32], arena);
+    ///     let mut buf = Vec::new_in(arena);
+    ///     buf.extend_from_slice(&key[..]);
+    ///     // no need to drop `buf` before `key`; both are released at once
+    ///     core::mem::forget(buf);
+    ///     key.iter().map(|&b| u64::from(b)).sum::<u64>()
+    /// });
+    /// assert_eq!(checksum, 32 * 0xAF);
+    /// ```
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        /// Rewinds the allocator on drop, also when `f` panics.
+        struct Rewind<'a, P: PageProvider> {
+            alloc: &'a SecStackSinglePageAlloc<P>,
+            marker: Checkpoint,
+        }
+
+        impl<P: PageProvider> Drop for Rewind<'_, P> {
+            fn drop(&mut self) {
+                // SAFETY: allocations made by `f` borrow the allocator reference passed to
+                // `f`, so they don't outlive the call to `f`; no allocation borrowing
+                // `self` existed before, since we have a mutable reference to it
+                unsafe { self.alloc.rewind(self.marker) };
+            }
+        }
+
+        let rewind = Rewind {
+            alloc: self,
+            marker: self.checkpoint(),
+        };
+        f(rewind.alloc)
+    }
+
+    /// If allocation tracking is enabled, check that `ptr` points to a live
+    /// allocation of `self` with layout `layout`, and return the allocation
+    /// bitmaps and the first granule of the allocation.
+    ///
+    /// # Errors
+    /// Returns an error if allocation tracking is enabled and the check fails.
+    fn check_allocation(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Result<Option<(&AllocBitmap, usize)>, InvalidFree> {
+        let Some(bitmap) = &self.bitmap else {
+            return Ok(None);
+        };
+        let offset = ptr.as_ptr().addr().wrapping_sub(self.page.as_ptr().addr());
+        if offset >= self.capacity {
+            return Err(InvalidFree::Foreign);
+        }
+        if !offset.is_multiple_of(8) {
+            return Err(InvalidFree::NotAllocated);
+        }
+        if !is_aligned_ptr(ptr.as_ptr(), layout.align()) {
+            return Err(InvalidFree::WrongLayout);
+        }
+        let granules = align_up_usize(layout.size(), 8) / 8;
+        bitmap.check(offset / 8, granules, self.capacity / 8)?;
+        Ok(Some((bitmap, offset / 8)))
+    }
+
+    /// Like [`Self::check_allocation`], but aborts the process if the check
+    /// fails.
+    fn check_allocation_or_abort(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+    ) -> Option<(&AllocBitmap, usize)> {
+        self.check_allocation(ptr, layout)
+            .unwrap_or_else(|err| bitmap::abort_invalid_free(err))
+    }
+
+    /// Securely wipe the memory pointed to by `ptr` and of size `len` bytes,
+    /// according to the wipe options of the allocator.
+    ///
+    /// # Safety
+    /// The memory range must lie in the memory page of `self`, and must not be
+    /// in use.
+    unsafe fn wipe_mem(&self, ptr: *mut u8, len: usize) {
+        mem::check_access(ptr, len, true);
+        // SAFETY: the memory range lies in our locked memory page, which is
+        // allocated by the provider, and is not in use by the safety contract
+        unsafe { self.wipe.wipe(self.page.provider(), ptr, len, true) };
+    }
+
+    /// Release the deallocated block `ptr` of `len` bytes: wipe it, and make
+    /// it's memory available for reuse where possible.
+    ///
+    /// # Safety
+    /// `ptr` must have the provenance of the memory page, and point to a block
+    /// of `len` bytes allocated with `self` which is no longer in use. `len`
+    /// must be the rounded size of the allocation.
+    unsafe fn release(&self, ptr: *mut u8, len: usize) {
+        // securely wipe the deallocated memory
+        // SAFETY: `ptr` is valid for writes of `len` bytes since it was
+        // previously successfully allocated and is no longer in use
+        // SAFETY: the memory lies in our locked private anonymous memory page
+        unsafe {
+            self.wipe_mem(ptr, len);
+        }
+        // `self.bytes - len` doesn't overflow since the memory has previously been
+        // allocated
+        self.bytes.set(self.bytes.get() - len);
+
+        // if `self.bytes` is now 0 then this was the last allocation
+        // hence we can reset the allocator: reset the stack offset
+        if self.bytes.get() == 0 {
+            self.stack_offset.set(self.initial_offset());
+            return;
+        }
+
+        // otherwise, if this allocation was the last one on the stack, rewind the stack
+        // offset so we can reuse the memory for later allocation requests
+
+        // SAFETY: this doesn't overflow as `ptr` was returned by a previous allocation
+        // request so lies in our memory page, so `ptr` is larger than the page
+        // pointer
+        let alloc_start_offset = unsafe { large_offset_from(ptr, self.page.as_ptr()) };
+        let alloc_end_offset = alloc_start_offset + len;
+        // `alloc_end_offset` is the stack offset directly after it's allocation
+        if alloc_end_offset == self.stack_offset.get() {
+            // SAFETY: `alloc_start_offset` is a multiple of 8 since both `ptr` and the page
+            // pointer are 8 byte aligned
+            self.stack_offset.set(alloc_start_offset);
+        }
+    }
+
+    /// Put the deallocated block `ptr` of `len` bytes in quarantine: wipe it
+    /// and fill it with poison. If the quarantine is full, the oldest
+    /// quarantined block is released.
+    ///
+    /// # Safety
+    /// Same as for [`Self::release`].
+    unsafe fn quarantine_block(&self, ptr: *mut u8, len: usize) {
+        // SAFETY: by the safety contract, the block lies in our memory page and is
+        // no longer in use, so it is valid for writes of `len` bytes
+        unsafe {
+            self.wipe_mem(ptr, len);
+            ptr.write_bytes(quarantine::POISON, len);
+        }
+        let offset = ptr.addr() - self.page.as_ptr().addr();
+        if let Some((offset, len, wipes)) = self.quarantine.push(offset, len) {
+            // SAFETY: the block was quarantined, so it is no longer in use
+            unsafe { self.release_quarantined(offset, len, wipes) };
+        }
+    }
+
+    /// Verify the poison of the quarantined block at page offset `offset` of
+    /// `len` bytes, and release it. `wipes` is the
+    /// [wipe count](crate::registry::wipe_count) when the block was
+    /// quarantined; the block may only be zero instead of poisoned if an
+    /// emergency wipe happened since.
+    ///
+    /// # Panics
+    /// Aborts the process if the poison was modified (write after free).
+    ///
+    /// # Safety
+    /// The block must have been removed from the quarantine.
+    unsafe fn release_quarantined(&self, offset: usize, len: usize, wipes: usize) {
+        // SAFETY: quarantined blocks lie in the memory page
+        let ptr = unsafe { self.page.as_ptr_mut().add(offset) };
+        mem::check_access(ptr, len, false);
+        // the wipe count is read after the block, so it changed if the block was
+        // zeroized by an emergency wipe (the count is incremented before the wipe)
+        // SAFETY: `ptr` is 8 byte aligned and valid for reads of `len` bytes, which
+        // is a multiple of 8
+        let poisoned = unsafe {
+            quarantine::is_poisoned(ptr, len, false)
+                || crate::registry::wipe_count() != wipes && quarantine::is_poisoned(ptr, len, true)
+        };
+        if !poisoned {
+            bitmap::abort_invalid_free(InvalidFree::WriteAfterFree);
+        }
+        // SAFETY: `ptr` has the provenance of the page, and the block is no longer
+        // in use
+        unsafe { self.release(ptr, len) };
     }
-}
 
-impl SecStackSinglePageAlloc {
     /// Returns `true` iff `ptr` points to the final allocation on the memory
     /// page of `self`.
     ///
@@ -210,6 +816,18 @@ impl SecStackSinglePageAlloc {
         alloc_end_offset == self.stack_offset.get()
     }
 
+    /// Like [`Self::ptr_is_last_allocation`], but ignores quarantined blocks
+    /// on top of the stack.
+    fn ptr_is_last_live_allocation(&self, ptr: NonNull<u8>, rounded_size: usize) -> bool {
+        // SAFETY: see `Self::ptr_is_last_allocation`
+        let alloc_start_offset = unsafe { large_offset_from(ptr.as_ptr(), self.page.as_ptr()) };
+        let mut top = self.stack_offset.get();
+        while let Some(offset) = self.quarantine.block_ending_at(top) {
+            top = offset;
+        }
+        alloc_start_offset + rounded_size == top
+    }
+
     /// Create a zero-sized allocation.
     ///
     /// # Safety
@@ -305,15 +923,10 @@ impl SecStackSinglePageAlloc {
 
         Ok(new_ptr)
     }
-}
 
-unsafe impl Allocator for SecStackSinglePageAlloc {
-    // The backing memory is zeroed on deallocation and `mmap` initialises the
-    // memory with zeros so every allocation has zeroed memory.
-    // We always return a multiple of 8 bytes and a minimal alignment of 8. This
-    // allows for fast zeroization and reduces the chance for (external) memory
-    // fragmentation, at the cost of increased internal memory fragmentation.
-    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    /// Bump allocate a block of memory for `layout`, see
+    /// [`Allocator::allocate_zeroed`].
+    fn bump_allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         debug_checked_precondition!(layout.align().is_power_of_two());
 
         // catch zero sized allocations immediately so we do not have to bother with
@@ -330,17 +943,29 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             return Err(AllocError);
         }
         // error if we do not have enough space for this allocation
-        if rounded_req_size > self.page.page_size() - self.stack_offset.get() {
+        let available = self.capacity - self.stack_offset.get();
+        if rounded_req_size > available {
             return Err(AllocError);
         }
+        // with random placement, leave a random gap (a multiple of 8) before the
+        // allocation if it fits
+        let gap = self.random_gap();
+        let gap = if gap <= available - rounded_req_size {
+            gap
+        } else {
+            0
+        };
+        // the allocation starts at (or after, for large align) `start_offset`
+        // SAFETY: a multiple of 8, at most the capacity
+        let start_offset = self.stack_offset.get() + gap;
 
-        // SAFETY: `self.stack_offset` is at most the page size so fits an `isize` and
+        // SAFETY: `start_offset` is at most the page size so fits an `isize` and
         // the addition does not wrap.
-        // SAFETY: `self.stack_offset` is at most the page size so the result of `add`
+        // SAFETY: `start_offset` is at most the page size so the result of `add`
         // still points into the mapped memory page or one byte after it
         // SAFETY: hence the use of `add` is sound
-        let stack_ptr: *mut u8 = unsafe { self.page.as_ptr_mut().add(self.stack_offset.get()) };
-        // also the pointer is 8 byte aligned since `self.stack_offset` is a multiple of
+        let stack_ptr: *mut u8 = unsafe { self.page.as_ptr_mut().add(start_offset) };
+        // also the pointer is 8 byte aligned since `start_offset` is a multiple of
         // 8 and the page pointer is page aligned, so also 8 byte aligned
 
         // we use a minimum alignment of 8 since this allows a fast path for many
@@ -360,12 +985,16 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             // result is nonnull
             let alloc_slice_ptr: NonNull<[u8]> = unsafe { NonNull::new_unchecked(alloc_slice_ptr) };
 
+            if let Some(bitmap) = &self.bitmap {
+                bitmap.track(start_offset / 8, rounded_req_size / 8);
+            }
             // SAFETY: rounded_req_size is a multiple of 8 (by rounding) so that
             // `self.stack_offset` stays a multiple of 8
-            self.stack_offset
-                .set(self.stack_offset.get() + rounded_req_size);
+            self.stack_offset.set(start_offset + rounded_req_size);
 
             self.bytes.set(self.bytes.get() + rounded_req_size);
+            // the allocation will be written to, so the page must be writable
+            mem::check_access(nonnull_as_mut_ptr(alloc_slice_ptr), rounded_req_size, true);
             Ok(alloc_slice_ptr)
         } else {
             // slower path for large align
@@ -382,15 +1011,15 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             let next_align_pageoffset =
                 unsafe { large_offset_from(next_aligned_ptr, self.page.as_ptr()) };
             // error if `next_aligned_ptr` falls outside of our page
-            if next_align_pageoffset >= self.page.page_size() {
+            if next_align_pageoffset >= self.capacity {
                 return Err(AllocError);
             }
             // the new allocation will start at `next_aligned_ptr` and be `rounded_req_size`
             // long; error if we do not have enough space for this allocation
-            // by the previous branch `self.page.page_size() - next_align_pageoffset` won't
-            // wrap (`self.page.page_size() - next_align_pageoffset` is the
+            // by the previous branch `self.capacity - next_align_pageoffset` won't
+            // wrap (`self.capacity - next_align_pageoffset` is the
             // number of bytes available)
-            if rounded_req_size > self.page.page_size() - next_align_pageoffset {
+            if rounded_req_size > self.capacity - next_align_pageoffset {
                 return Err(AllocError);
             }
 
@@ -409,11 +1038,34 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             // (`alloc_slice_ptr`)
             self.stack_offset
                 .set(next_align_pageoffset + rounded_req_size);
+            if let Some(bitmap) = &self.bitmap {
+                bitmap.track(next_align_pageoffset / 8, rounded_req_size / 8);
+            }
 
             self.bytes.set(self.bytes.get() + rounded_req_size);
+            // the allocation will be written to, so the page must be writable
+            mem::check_access(nonnull_as_mut_ptr(alloc_slice_ptr), rounded_req_size, true);
             Ok(alloc_slice_ptr)
         }
     }
+}
+
+unsafe impl<P: PageProvider> Allocator for SecStackSinglePageAlloc<P> {
+    // The backing memory is zeroed on deallocation and `mmap` initialises the
+    // memory with zeros so every allocation has zeroed memory.
+    // We always return a multiple of 8 bytes and a minimal alignment of 8. This
+    // allows for fast zeroization and reduces the chance for (external) memory
+    // fragmentation, at the cost of increased internal memory fragmentation.
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.bump_allocate(layout) {
+            Err(AllocError) if !self.quarantine.is_empty() => {
+                // release the quarantined blocks to make room for the allocation
+                self.flush_quarantine();
+                self.bump_allocate(layout)
+            },
+            res => res,
+        }
+    }
 
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         // zero initialisation doesn't come at a cost, see `allocate_zeroed`
@@ -442,42 +1094,25 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
         // safety and correct functioning
         let rounded_req_size = align_up_usize(layout.size(), 8);
 
+        // with allocation tracking, invalid deallocations abort here, before the
+        // allocator state is corrupted
+        if let Some((bitmap, start)) = self.check_allocation_or_abort(ptr, layout) {
+            bitmap.untrack(start, rounded_req_size / 8);
+        }
+
         // The pointer we got from the caller might have provenance for only
         // `layout.size()` bytes. We reconstruct the pointer with our full page
         // provenance, so that `ptr` is valid for `rounded_req_size` byte writes.
         let ptr = self.page.as_ptr_mut().with_addr(ptr.as_ptr().addr());
 
-        // securely wipe the deallocated memory
-        // SAFETY: `ptr` is valid for writes of `rounded_req_size` bytes since it was
-        // previously successfully allocated (by the safety contract for this
-        // function) and not yet deallocated
-        unsafe {
-            zeroize_mem(ptr, rounded_req_size);
-        }
-        // `self.bytes - rounded_req_size` doesn't overflow since the memory has
-        // previously been allocated
-        self.bytes.set(self.bytes.get() - rounded_req_size);
-
-        // if `self.bytes` is now 0 then this was the last allocation
-        // hence we can reset the allocator: reset the stack offset
-        if self.bytes.get() == 0 {
-            self.stack_offset.set(0);
-            return;
-        }
-
-        // otherwise, if this allocation was the last one on the stack, rewind the stack
-        // offset so we can reuse the memory for later allocation requests
-
-        // SAFETY: this doesn't overflow as `ptr` was returned by a previous allocation
-        // request so lies in our memory page, so `ptr` is larger than the page
-        // pointer
-        let alloc_start_offset = unsafe { large_offset_from(ptr, self.page.as_ptr()) };
-        let alloc_end_offset = alloc_start_offset + rounded_req_size;
-        // `alloc_end_offset` is the stack offset directly after it's allocation
-        if alloc_end_offset == self.stack_offset.get() {
-            // SAFETY: `alloc_start_offset` is a multiple of 8 since both `ptr` and the page
-            // pointer are 8 byte aligned
-            self.stack_offset.set(alloc_start_offset);
+        if self.quarantine.capacity() == 0 {
+            // SAFETY: `ptr` has the provenance of the page, was allocated with `self`
+            // with rounded size `rounded_req_size`, and is not used anymore by the
+            // safety contract of this function
+            unsafe { self.release(ptr, rounded_req_size) };
+        } else {
+            // SAFETY: see above
+            unsafe { self.quarantine_block(ptr, rounded_req_size) };
         }
     }
 
@@ -511,6 +1146,10 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             ptr.as_ptr().addr() <= self.page.as_ptr().addr() + self.stack_offset.get()
         );
 
+        // with allocation tracking, invalid reallocations abort here, before the
+        // allocator state is corrupted
+        let tracked = self.check_allocation_or_abort(ptr, old_layout);
+
         // check whether the existing allocation has the requested alignment
         if is_aligned_ptr(ptr.as_ptr(), new_layout.align()) {
             // old allocation has the (new) required alignment
@@ -543,11 +1182,15 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             // new_rounded_size` bytes since it is only `new_rounded_size` past
             // `ptr`, which was successfully allocated (by the safety contract
             // for this function) and not yet deallocated
+            // SAFETY: the memory lies in our locked private anonymous memory page
             unsafe {
-                zeroize_mem(new_alloc_end, size_decrease);
+                self.wipe_mem(new_alloc_end, size_decrease);
             }
             // decrement the number of allocated bytes by the allocation size reduction
             self.bytes.set(self.bytes.get() - size_decrease);
+            if let Some((bitmap, start)) = tracked {
+                bitmap.resize(start, rounded_size / 8, new_rounded_size / 8);
+            }
 
             // if the allocation is the final allocation in our memory page, then we can
             // rewind the stack offset to limit memory fragmentation
@@ -598,6 +1241,10 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
             ptr.as_ptr().addr() <= self.page.as_ptr().addr() + self.stack_offset.get()
         );
 
+        // with allocation tracking, invalid reallocations abort here, before the
+        // allocator state is corrupted
+        let tracked = self.check_allocation_or_abort(ptr, old_layout);
+
         // check whether the existing allocation has the requested alignment
         if is_aligned_ptr(ptr.as_ptr(), new_layout.align()) {
             // old allocation has the (new) required alignment
@@ -628,7 +1275,7 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
                 // the subtraction doesn't wrap since `alloc_start_offset` is the part of the
                 // page that is used (without counting the allocation currently
                 // being resized)
-                if new_rounded_size > self.page.page_size() - alloc_start_offset {
+                if new_rounded_size > self.capacity - alloc_start_offset {
                     return Err(AllocError);
                 }
 
@@ -639,6 +1286,9 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
                 let size_increase: usize = new_rounded_size - rounded_size;
                 // increase the number of allocated bytes by the allocation size increase
                 self.bytes.set(self.bytes.get() + size_increase);
+                if let Some((bitmap, start)) = tracked {
+                    bitmap.resize(start, rounded_size / 8, new_rounded_size / 8);
+                }
                 // and the stack offset
                 // SAFETY: `size_increase` is a multiple of 8 so `self.stack_offset` remains so
                 self.stack_offset
@@ -676,6 +1326,8 @@ unsafe impl Allocator for SecStackSinglePageAlloc {
 mod tests {
     use super::*;
     use crate::allocator_api::{Box, Vec};
+    use crate::containers::{SecBox, SecString, SecVec};
+    use crate::shared_alloc::ArcAlloc;
     use std::mem::drop;
 
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -910,6 +1562,122 @@ mod tests {
         // drop `allocator`
     }
 
+    #[test]
+    fn vec_allocation_cache_flush() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_cache_flush(true);
+        allocator.consistency_check();
+        {
+            let mut heap_mem = Vec::<u8, _>::with_capacity_in(9, &allocator);
+            heap_mem.extend_from_slice(&[0xAF; 300]);
+            allocator.consistency_check();
+            heap_mem.truncate(3);
+            heap_mem.shrink_to_fit();
+            allocator.consistency_check();
+        } // drop `heap_mem`
+        allocator.consistency_check();
+        // drop `allocator`
+    }
+
+    #[test]
+    fn checkpoint_rewind() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let heap_mem = Box::new_in([1u8; 9], &allocator);
+        let marker = allocator.checkpoint();
+        let mut heap_mem2 = Vec::new_in(&allocator);
+        heap_mem2.extend_from_slice(&[0xAF_u64; 37]);
+        let heap_mem3 = Box::new_in(37_u64, &allocator);
+        core::mem::forget(heap_mem2);
+        core::mem::forget(heap_mem3);
+        // SAFETY: the allocations made after the checkpoint are forgotten
+        unsafe { allocator.rewind(marker) };
+        allocator.consistency_check();
+        assert_eq!(allocator.checkpoint(), marker);
+        drop(heap_mem);
+        allocator.consistency_check();
+        // drop `allocator`
+    }
+
+    #[test]
+    #[should_panic]
+    fn rewind_foreign_checkpoint() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let allocator2 = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        // SAFETY: panics before changing any state
+        unsafe { allocator.rewind(allocator2.checkpoint()) };
+    }
+
+    #[test]
+    fn scope_releases_allocations() {
+        let mut allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        for i in 0..10_u8 {
+            let sum = allocator.scope(|arena| {
+                let heap_mem = Box::new_in([i; 17], arena);
+                let mut heap_mem2 = Vec::new_in(arena);
+                heap_mem2.extend_from_slice(&heap_mem[..]);
+                // dropped out of order
+                drop(heap_mem);
+                core::mem::forget(Box::new_in([0xAF_u8; 100], arena));
+                heap_mem2.iter().map(|&b| usize::from(b)).sum::<usize>()
+            });
+            assert_eq!(sum, 17 * usize::from(i));
+            allocator.consistency_check();
+            assert_eq!(allocator.stack_offset.get(), 0);
+            assert_eq!(allocator.bytes.get(), 0);
+        }
+        // drop `allocator`, checking the page is zeroized
+    }
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn send_bounds() {
+        assert_send::<mem::Page>();
+        assert_send::<SecStackSinglePageAlloc>();
+        assert_send::<ArcAlloc<SecStackSinglePageAlloc>>();
+        assert_send::<Box<[u8; 32], ArcAlloc<SecStackSinglePageAlloc>>>();
+        assert_send::<SecBox<[u8; 32], ArcAlloc<SecStackSinglePageAlloc>>>();
+        assert_send::<SecVec<u8, ArcAlloc<SecStackSinglePageAlloc>>>();
+        assert_send::<SecString<ArcAlloc<SecStackSinglePageAlloc>>>();
+    }
+
+    #[test]
+    fn send_allocator_to_thread() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let allocator = std::thread::spawn(move || {
+            {
+                let mut heap_mem = Vec::new_in(&allocator);
+                heap_mem.extend_from_slice(&[0xAF_u8; 100]);
+                allocator.consistency_check();
+            } // drop `heap_mem`
+            allocator
+        })
+        .join()
+        .expect("thread panicked");
+        let _heap_mem = Box::new_in([1u8; 9], &allocator);
+        allocator.consistency_check();
+        // drop `heap_mem` and `allocator` on the main thread
+    }
+
+    #[test]
+    fn send_containers_between_threads() {
+        let allocator =
+            ArcAlloc::new(SecStackSinglePageAlloc::new().expect("allocator creation failed"));
+        let key = SecBox::new_in([0xAF_u8; 32], allocator.clone());
+        let worker_allocator = allocator.clone();
+        let password = std::thread::spawn(move || {
+            assert_eq!(*key, [0xAF; 32]);
+            drop(key);
+            SecString::from_str_in("hunter2", worker_allocator)
+        })
+        .join()
+        .expect("thread panicked");
+        assert_eq!(password.as_str(), "hunter2");
+        drop(password);
+        assert_eq!(allocator.handle_count(), 1);
+    }
+
     #[test]
     fn allocate_zeroed() {
         let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
@@ -926,4 +1694,350 @@ mod tests {
             allocator.deallocate(ptr.cast(), layout);
         }
     }
+
+    /// Page provider counting the calls to an [`OsPageProvider`].
+    struct CountingProvider<'a> {
+        allocs: &'a Cell<usize>,
+        locks: &'a Cell<usize>,
+        releases: &'a Cell<usize>,
+    }
+
+    // SAFETY: forwards to `OsPageProvider`
+    unsafe impl PageProvider for CountingProvider<'_> {
+        type Error = mem::PageAllocError;
+
+        fn page_size(&self) -> usize {
+            OsPageProvider.page_size()
+        }
+
+        fn allocate(&self, len: usize) -> Result<NonNull<u8>, Self::Error> {
+            self.allocs.set(self.allocs.get() + 1);
+            OsPageProvider.allocate(len)
+        }
+
+        unsafe fn lock(&self, ptr: NonNull<u8>, len: usize) -> Result<(), Self::Error> {
+            self.locks.set(self.locks.get() + 1);
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { OsPageProvider.lock(ptr, len) }
+        }
+
+        unsafe fn protect(
+            &self,
+            ptr: NonNull<u8>,
+            len: usize,
+            protection: crate::page_provider::Protection,
+        ) -> Result<(), Self::Error> {
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { OsPageProvider.protect(ptr, len, protection) }
+        }
+
+        unsafe fn release(&self, ptr: NonNull<u8>, len: usize) {
+            self.releases.set(self.releases.get() + 1);
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { OsPageProvider.release(ptr, len) }
+        }
+    }
+
+    #[test]
+    fn custom_page_provider() {
+        let (allocs, locks, releases) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let allocator = SecStackSinglePageAlloc::new_with_provider(CountingProvider {
+            allocs: &allocs,
+            locks: &locks,
+            releases: &releases,
+        })
+        .expect("allocator creation failed");
+        assert_eq!((allocs.get(), locks.get(), releases.get()), (1, 1, 0));
+        {
+            let mut vec = Vec::new_in(&allocator);
+            vec.extend_from_slice(&[0xAF_u8; 100]);
+            allocator.consistency_check();
+        }
+        drop(allocator);
+        assert_eq!((allocs.get(), locks.get(), releases.get()), (1, 1, 1));
+    }
+
+    #[test]
+    fn static_buffer_allocator() {
+        let buf: &'static mut [u8] = std::vec![0xAF_u8; 259].leak();
+        let allocator =
+            SecStackSinglePageAlloc::from_static_buffer(&mut buf[3..]).expect("buffer too small");
+        allocator.consistency_check();
+        assert!(allocator.page.page_size() >= 248);
+        // static buffers are not locked by the provider
+        assert_eq!(allocator.protections(), PageProtections::default());
+        {
+            let mut vec = Vec::new_in(&allocator);
+            vec.extend_from_slice(&[0xAF_u64; 20]);
+            let zeroed = allocator
+                .allocate_zeroed(Layout::new::<[u8; 16]>())
+                .expect("allocation failed");
+            // SAFETY: `zeroed` is allocated with 16 bytes
+            assert_eq!(unsafe { zeroed.cast::<[u8; 16]>().read() }, [0; 16]);
+            // SAFETY: `zeroed` was allocated by `allocator` with this layout
+            unsafe { allocator.deallocate(zeroed.cast(), Layout::new::<[u8; 16]>()) };
+            allocator.consistency_check();
+        }
+        let top = allocator.try_push(7_u64).expect("push failed");
+        assert_eq!(top.pop().expect("in order pop"), 7);
+        assert!(allocator.try_push([0_u8; 256]).is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn new_map_failure() {
+        use crate::testing::{self, PageOp};
+
+        testing::reset();
+        testing::fail_nth(PageOp::Map, 1, 12);
+        let result = SecStackSinglePageAlloc::new();
+        #[cfg(all(unix, not(miri)))]
+        assert!(matches!(result, Err(mem::PageAllocError::Mmap(_))));
+        assert!(result.is_err());
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (1, 0, 0));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn new_lock_failure_releases_page() {
+        use crate::testing::{self, PageOp};
+
+        testing::reset();
+        testing::fail_nth(PageOp::Lock, 1, 12);
+        let result = SecStackSinglePageAlloc::new();
+        #[cfg(all(unix, not(miri)))]
+        assert!(matches!(result, Err(mem::PageAllocError::Mlock(_))));
+        assert!(result.is_err());
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (1, 1, 1));
+        // the next allocator is created successfully
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        drop(allocator);
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (2, 2, 2));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    #[should_panic(expected = "could not unmap memory pages")]
+    fn drop_unmap_failure() {
+        use crate::testing::{self, PageOp};
+
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        testing::fail_nth(PageOp::Unmap, 1, 22);
+        drop(allocator);
+    }
+
+    #[test]
+    fn alloc_tracking_detects_invalid_frees() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_alloc_tracking(true);
+        assert!(allocator.alloc_tracking());
+        assert!(allocator.capacity < allocator.page.page_size());
+        let layout = Layout::new::<[u64; 3]>();
+        let a = allocator
+            .allocate(layout)
+            .expect("allocation failed")
+            .cast::<u8>();
+        let b = allocator
+            .allocate(layout)
+            .expect("allocation failed")
+            .cast::<u8>();
+        assert!(allocator.check_allocation(a, layout).is_ok());
+        // SAFETY: `a` points to an allocation of 24 bytes
+        let interior = unsafe { a.add(8) };
+        assert_eq!(
+            allocator
+                .check_allocation(interior, Layout::new::<u64>())
+                .err(),
+            Some(InvalidFree::NotAllocated)
+        );
+        assert_eq!(
+            allocator
+                .check_allocation(a, Layout::new::<[u64; 2]>())
+                .err(),
+            Some(InvalidFree::WrongLayout)
+        );
+        assert_eq!(
+            allocator
+                .check_allocation(a, Layout::new::<[u64; 4]>())
+                .err(),
+            Some(InvalidFree::WrongLayout)
+        );
+        let foreign = NonNull::from(&layout).cast::<u8>();
+        assert_eq!(
+            allocator.check_allocation(foreign, layout).err(),
+            Some(InvalidFree::Foreign)
+        );
+        // SAFETY: `a` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(a, layout) };
+        assert_eq!(
+            allocator.check_allocation(a, layout).err(),
+            Some(InvalidFree::NotAllocated)
+        );
+        // SAFETY: `b` was allocated by `allocator` with `layout`
+        let b = unsafe { allocator.shrink(b, layout, Layout::new::<u64>()) }
+            .expect("shrink failed")
+            .cast::<u8>();
+        assert!(allocator.check_allocation(b, Layout::new::<u64>()).is_ok());
+        // SAFETY: `b` was allocated by `allocator` with the layout of `u64`
+        unsafe { allocator.deallocate(b, Layout::new::<u64>()) };
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn alloc_tracking_toggle() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_alloc_tracking(false);
+        assert!(!allocator.alloc_tracking());
+        assert_eq!(allocator.capacity, allocator.page.page_size());
+        // the whole page can be allocated
+        let layout = Layout::from_size_align(allocator.capacity, 8).expect("valid layout");
+        let ptr = allocator.allocate(layout).expect("allocation failed");
+        // SAFETY: `ptr` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptr.cast(), layout) };
+        let allocator = allocator.with_alloc_tracking(true);
+        assert!(allocator.alloc_tracking());
+        assert!(allocator.allocate(layout).is_err());
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn quarantine_poisons_and_evicts() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_quarantine(2);
+        assert_eq!(allocator.quarantine_len(), 2);
+        let layout = Layout::new::<[u64; 2]>();
+        let page: *const u8 = allocator.page.as_ptr();
+        let ptrs: [NonNull<u8>; 3] = core::array::from_fn(|_| {
+            let ptr = allocator.allocate(layout).expect("allocation failed");
+            // SAFETY: `ptr` is valid for writes of 16 bytes
+            unsafe { ptr.cast::<u8>().write_bytes(0xFF, 16) };
+            ptr.cast()
+        });
+        let is_poisoned = |offset: usize| {
+            // SAFETY: the block at `offset` lies in the memory page
+            unsafe { quarantine::is_poisoned(page.add(offset), 16, false) }
+        };
+        // SAFETY: `ptrs[0]` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptrs[0], layout) };
+        // SAFETY: `page` points to the start of the memory page
+        assert_eq!(unsafe { page.read() }, quarantine::POISON);
+        assert!(is_poisoned(0));
+        // the memory stays in use
+        assert_eq!(allocator.bytes.get(), 48);
+        assert_eq!(allocator.stack_offset.get(), 48);
+        // SAFETY: `ptrs[2]` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptrs[2], layout) };
+        assert_eq!(allocator.stack_offset.get(), 48);
+        // SAFETY: `ptrs[1]` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptrs[1], layout) };
+        // the first block left the quarantine and was wiped to zero
+        // SAFETY: `page` points to the start of the memory page
+        assert_eq!(unsafe { page.read() }, 0);
+        assert!(is_poisoned(16) && is_poisoned(32));
+        assert_eq!(allocator.bytes.get(), 32);
+        allocator.flush_quarantine();
+        assert_eq!(allocator.bytes.get(), 0);
+        assert_eq!(allocator.stack_offset.get(), 0);
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn quarantine_flushed_when_full() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_quarantine(4);
+        let layout = Layout::from_size_align(allocator.capacity, 8).expect("valid layout");
+        let ptr = allocator.allocate(layout).expect("allocation failed");
+        // SAFETY: `ptr` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptr.cast(), layout) };
+        assert_eq!(allocator.stack_offset.get(), allocator.capacity);
+        // the quarantined block is released to make room
+        let ptr = allocator.allocate(layout).expect("allocation failed");
+        // SAFETY: `ptr` was allocated by `allocator` with `layout`
+        unsafe { allocator.deallocate(ptr.cast(), layout) };
+        allocator.consistency_check();
+        // dropping the allocator wipes the quarantined block (checked on drop
+        // with debug assertions)
+    }
+
+    #[test]
+    fn quarantine_stack_and_checkpoint() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_quarantine(SecStackSinglePageAlloc::<OsPageProvider>::MAX_QUARANTINE_LEN);
+        let a = allocator.push(1_u64);
+        let b = allocator.push(2_u64);
+        assert_eq!(b.pop().expect("in order pop"), 2);
+        // the quarantined value is ignored
+        assert!(a.is_top());
+        let marker = allocator.checkpoint();
+        assert_eq!(allocator.bytes.get(), 8);
+        let c = allocator.push([3_u8; 24]);
+        drop(c);
+        // SAFETY: the allocation made after the checkpoint is released
+        unsafe { allocator.rewind(marker) };
+        assert!(allocator.quarantine.is_empty());
+        assert_eq!(a.pop().expect("in order pop"), 1);
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn prewarm_keeps_contents() {
+        for tracking in [false, true] {
+            let allocator = SecStackSinglePageAlloc::new()
+                .expect("allocator creation failed")
+                .with_alloc_tracking(tracking);
+            let value = Box::new_in([0xAF_u8; 100], &allocator);
+            allocator.prewarm(0);
+            allocator.prewarm(256);
+            // stops at the allocation bitmaps, if any
+            allocator.prewarm(usize::MAX);
+            assert_eq!(*value, [0xAF; 100]);
+            drop(value);
+            allocator.consistency_check();
+        }
+    }
+
+    #[test]
+    fn populate_at_creation() {
+        let allocator = SecStackSinglePageAlloc::new()
+            .expect("allocator creation failed")
+            .with_alloc_tracking(true)
+            .with_populate(true);
+        let value = Box::new_in([0xAF_u8; 100], &allocator);
+        assert_eq!(*value, [0xAF; 100]);
+        drop(value);
+        allocator.consistency_check();
+    }
+
+    #[cfg(feature = "randomize")]
+    #[test]
+    fn random_placement() {
+        let offsets: Vec<usize> = (0..16)
+            .map(|_| {
+                let allocator = SecStackSinglePageAlloc::new()
+                    .expect("allocator creation failed")
+                    .with_random_placement(true)
+                    .expect("OS RNG failed");
+                assert!(allocator.random_placement());
+                let boxes: Vec<_> = (0..8).map(|i| Box::new_in(i, &allocator)).collect();
+                for b in &boxes {
+                    assert!(is_aligned_ptr(&raw const **b, 8));
+                }
+                let offset = (&raw const *boxes[0]).addr() - allocator.page.as_ptr().addr();
+                // random initial offset and gap
+                assert!(offset < 512 + 64);
+                drop(boxes);
+                allocator.consistency_check();
+                offset
+            })
+            .collect();
+        assert!(offsets.iter().any(|&offset| offset != offsets[0]));
+    }
 }
@@ -0,0 +1,213 @@
+//! Allocation bitmaps detecting invalid deallocations in
+//! [`SecStackSinglePageAlloc`](super::SecStackSinglePageAlloc).
+
+use core::ptr::NonNull;
+
+/// Number of bits in a bitmap word.
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Invalid deallocation (or reallocation) detected by an [`AllocBitmap`], or
+/// write after free detected by the quarantine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub(super) enum InvalidFree {
+    #[error("invalid deallocation: pointer does not lie in the memory page of the allocator")]
+    Foreign,
+    #[error(
+        "invalid deallocation: pointer is not the start of a live allocation (double free or \
+         interior pointer)"
+    )]
+    NotAllocated,
+    #[error("invalid deallocation: layout does not match the allocation")]
+    WrongLayout,
+    #[error("quarantined memory was modified (write after free)")]
+    WriteAfterFree,
+}
+
+/// Abort the process because of the invalid deallocation or write after free
+/// `err`.
+///
+/// Without the `std` feature, this panics instead.
+#[cold]
+pub(super) fn abort_invalid_free(err: InvalidFree) -> ! {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "std")] {
+            use std::io::Write;
+
+            // ignore errors, we abort anyway
+            let _ = writeln!(std::io::stderr(), "secmem-alloc: {err}");
+            std::process::abort()
+        } else {
+            panic!("secmem-alloc: {err}")
+        }
+    }
+}
+
+/// Allocation bitmaps of a memory page, with one bit per 8 byte granule in
+/// each bitmap.
+///
+/// The *start* bitmap marks the first granule of every live allocation, and
+/// the *used* bitmap all granules of live allocations. Together they record
+/// the start and size of every allocation. The bitmaps are stored at the end
+/// of the memory page they track, see [`AllocBitmap::reserved_bytes`].
+pub(super) struct AllocBitmap {
+    /// Pointer to `words` words of start bits, followed by `words` words of
+    /// used bits.
+    // SAFETY INVARIANT: valid for reads and writes of `2 * words` `u64`s
+    bits: NonNull<u64>,
+    /// Number of words per bitmap.
+    words: usize,
+}
+
+// SAFETY: the bitmaps are stored in the memory page of the allocator owning the
+// `AllocBitmap`, and are only accessed through it
+unsafe impl Send for AllocBitmap {}
+
+impl AllocBitmap {
+    /// Returns the number of bytes to reserve at the end of a memory page of
+    /// `page_size` bytes to store the bitmaps of the page.
+    ///
+    /// This is a multiple of 8.
+    pub(super) const fn reserved_bytes(page_size: usize) -> usize {
+        2 * size_of::<u64>() * page_size.div_ceil(8 * WORD_BITS)
+    }
+
+    /// Create allocation bitmaps stored in the memory region pointed to by
+    /// `bits` of [`Self::reserved_bytes(page_size)`](Self::reserved_bytes)
+    /// bytes, tracking `page_size` bytes. No allocations are recorded.
+    ///
+    /// # Safety
+    /// `bits` must be 8 byte aligned, and valid for reads and writes of
+    /// `Self::reserved_bytes(page_size)` bytes for the lifetime of the result.
+    /// The memory region must contain zeros.
+    pub(super) unsafe fn new(bits: NonNull<u8>, page_size: usize) -> Self {
+        Self {
+            bits: bits.cast(),
+            words: page_size.div_ceil(8 * WORD_BITS),
+        }
+    }
+
+    /// Returns the bit of granule `granule` in bitmap `map` (`0` for the start
+    /// bitmap and `1` for the used bitmap).
+    fn get(&self, map: usize, granule: usize) -> bool {
+        assert!(granule < self.words * WORD_BITS);
+        // SAFETY: the index is less than `2 * self.words`
+        let word = unsafe { self.bits.add(map * self.words + granule / WORD_BITS).read() };
+        word & (1 << (granule % WORD_BITS)) != 0
+    }
+
+    /// Set the bits of the granules `start .. end` in bitmap `map` to
+    /// `value`.
+    fn set_range(&self, map: usize, start: usize, end: usize, value: bool) {
+        assert!(end <= self.words * WORD_BITS);
+        for granule in start..end {
+            // SAFETY: the index is less than `2 * self.words`
+            unsafe {
+                let word = self.bits.add(map * self.words + granule / WORD_BITS);
+                let mask = 1 << (granule % WORD_BITS);
+                word.write(if value {
+                    word.read() | mask
+                } else {
+                    word.read() & !mask
+                });
+            }
+        }
+    }
+
+    /// Record a new allocation of `len` granules starting at granule `start`.
+    pub(super) fn track(&self, start: usize, len: usize) {
+        self.set_range(0, start, start + 1, true);
+        self.set_range(1, start, start + len, true);
+    }
+
+    /// Check that a live allocation of exactly `len` granules starts at
+    /// granule `start`. `granules` is the total number of granules of the
+    /// tracked memory page.
+    ///
+    /// # Errors
+    /// Returns an error if `start` is not the start of a live allocation, or
+    /// if the allocation is not `len` granules long.
+    pub(super) fn check(
+        &self,
+        start: usize,
+        len: usize,
+        granules: usize,
+    ) -> Result<(), InvalidFree> {
+        if start >= granules {
+            return Err(InvalidFree::Foreign);
+        }
+        if !self.get(0, start) || !self.get(1, start) {
+            return Err(InvalidFree::NotAllocated);
+        }
+        let end = start + len;
+        if end > granules {
+            return Err(InvalidFree::WrongLayout);
+        }
+        // all granules of the allocation are used, and no other allocation starts
+        // inside it
+        if (start + 1..end).any(|granule| self.get(0, granule) || !self.get(1, granule)) {
+            return Err(InvalidFree::WrongLayout);
+        }
+        // the allocation doesn't continue after `end`
+        if end < granules && self.get(1, end) && !self.get(0, end) {
+            return Err(InvalidFree::WrongLayout);
+        }
+        Ok(())
+    }
+
+    /// Remove the allocation of `len` granules starting at granule `start`.
+    pub(super) fn untrack(&self, start: usize, len: usize) {
+        self.set_range(0, start, start + 1, false);
+        self.set_range(1, start, start + len, false);
+    }
+
+    /// Resize the allocation starting at granule `start` from `old_len` to
+    /// `new_len` granules in place. `new_len` must be non-zero.
+    pub(super) fn resize(&self, start: usize, old_len: usize, new_len: usize) {
+        if new_len < old_len {
+            self.set_range(1, start + new_len, start + old_len, false);
+        } else {
+            self.set_range(1, start + old_len, start + new_len, true);
+        }
+    }
+
+    /// Remove all allocations starting at granule `start` or later.
+    pub(super) fn clear_from(&self, start: usize) {
+        let end = self.words * WORD_BITS;
+        self.set_range(0, start, end, false);
+        self.set_range(1, start, end, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_invalid_frees() {
+        let mut buf = [0_u64; 4];
+        assert_eq!(AllocBitmap::reserved_bytes(1024), 32);
+        // SAFETY: `buf` is 8 byte aligned, zeroed and 32 bytes long
+        let bitmap = unsafe { AllocBitmap::new(NonNull::from(&mut buf).cast(), 1024) };
+        let granules = 128;
+        bitmap.track(0, 2);
+        bitmap.track(2, 3);
+        bitmap.track(5, 1);
+        assert_eq!(bitmap.check(0, 2, granules), Ok(()));
+        assert_eq!(bitmap.check(2, 3, granules), Ok(()));
+        assert_eq!(bitmap.check(5, 1, granules), Ok(()));
+        assert_eq!(bitmap.check(1, 1, granules), Err(InvalidFree::NotAllocated));
+        assert_eq!(bitmap.check(3, 2, granules), Err(InvalidFree::NotAllocated));
+        assert_eq!(bitmap.check(2, 2, granules), Err(InvalidFree::WrongLayout));
+        assert_eq!(bitmap.check(2, 4, granules), Err(InvalidFree::WrongLayout));
+        assert_eq!(bitmap.check(5, 2, granules), Err(InvalidFree::WrongLayout));
+        assert_eq!(bitmap.check(128, 1, granules), Err(InvalidFree::Foreign));
+        bitmap.untrack(2, 3);
+        assert_eq!(bitmap.check(2, 3, granules), Err(InvalidFree::NotAllocated));
+        bitmap.resize(0, 2, 1);
+        assert_eq!(bitmap.check(0, 1, granules), Ok(()));
+        bitmap.resize(0, 1, 4);
+        assert_eq!(bitmap.check(0, 4, granules), Ok(()));
+        bitmap.clear_from(0);
+        assert_eq!(buf, [0; 4]);
+    }
+}
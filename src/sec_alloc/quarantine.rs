@@ -0,0 +1,170 @@
+//! Quarantine of deallocated blocks of
+//! [`SecStackSinglePageAlloc`](super::SecStackSinglePageAlloc), to detect
+//! writes after free.
+
+use crate::registry;
+use core::cell::Cell;
+
+/// Maximal number of blocks in a quarantine.
+pub(super) const MAX_LEN: usize = 16;
+
+/// Byte pattern quarantined blocks are filled with.
+pub(super) const POISON: u8 = 0xA5;
+
+/// Bounded FIFO queue of quarantined blocks, stored as their offset in the
+/// memory page, their size in bytes and the
+/// [wipe count](registry::wipe_count) when they were quarantined.
+pub(super) struct Quarantine {
+    /// Ring buffer of quarantined blocks.
+    blocks: [Cell<(usize, usize, usize)>; MAX_LEN],
+    /// Index of the oldest block in `blocks`.
+    // SAFETY INVARIANT: less than `MAX_LEN`
+    head: Cell<usize>,
+    /// Number of quarantined blocks.
+    // SAFETY INVARIANT: at most `capacity`
+    len: Cell<usize>,
+    /// Maximal number of quarantined blocks.
+    // SAFETY INVARIANT: at most `MAX_LEN`
+    capacity: usize,
+}
+
+impl Quarantine {
+    /// Create an empty quarantine for at most `capacity` blocks.
+    ///
+    /// # Panics
+    /// Panics if `capacity` exceeds [`MAX_LEN`].
+    pub(super) const fn new(capacity: usize) -> Self {
+        assert!(capacity <= MAX_LEN, "quarantine length too large");
+        Self {
+            blocks: [const { Cell::new((0, 0, 0)) }; MAX_LEN],
+            head: Cell::new(0),
+            len: Cell::new(0),
+            capacity,
+        }
+    }
+
+    /// Returns the maximal number of quarantined blocks.
+    pub(super) const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns `true` iff no blocks are quarantined.
+    pub(super) fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    /// Returns the `i`th oldest quarantined block.
+    fn get(&self, i: usize) -> (usize, usize, usize) {
+        self.blocks[(self.head.get() + i) % MAX_LEN].get()
+    }
+
+    /// Add the block at `offset` of `len` bytes to the quarantine. If the
+    /// quarantine is full, the oldest block is removed and returned, like by
+    /// [`Self::pop`]. With a capacity of zero, the block itself is returned.
+    pub(super) fn push(&self, offset: usize, len: usize) -> Option<(usize, usize, usize)> {
+        let block = (offset, len, registry::wipe_count());
+        if self.capacity == 0 {
+            return Some(block);
+        }
+        let evicted = if self.len.get() == self.capacity {
+            self.pop()
+        } else {
+            None
+        };
+        self.blocks[(self.head.get() + self.len.get()) % MAX_LEN].set(block);
+        self.len.set(self.len.get() + 1);
+        evicted
+    }
+
+    /// Remove and return the oldest quarantined block, as its offset, its
+    /// size and the wipe count when it was quarantined.
+    pub(super) fn pop(&self) -> Option<(usize, usize, usize)> {
+        if self.is_empty() {
+            return None;
+        }
+        let block = self.get(0);
+        self.head.set((self.head.get() + 1) % MAX_LEN);
+        self.len.set(self.len.get() - 1);
+        Some(block)
+    }
+
+    /// Returns the offset of the quarantined block ending at offset `end`, if
+    /// any.
+    pub(super) fn block_ending_at(&self, end: usize) -> Option<usize> {
+        (0..self.len.get())
+            .map(|i| self.get(i))
+            .find(|&(offset, len, _)| offset + len == end)
+            .map(|(offset, _, _)| offset)
+    }
+
+    /// Remove all quarantined blocks at offset `offset` or later, keeping the
+    /// order of the other blocks.
+    pub(super) fn remove_from(&self, offset: usize) {
+        let mut kept = 0;
+        for i in 0..self.len.get() {
+            let block = self.get(i);
+            if block.0 < offset {
+                self.blocks[(self.head.get() + kept) % MAX_LEN].set(block);
+                kept += 1;
+            }
+        }
+        self.len.set(kept);
+    }
+}
+
+/// Returns `true` iff the `len` bytes at `ptr` are all [`POISON`], or all
+/// zero if `wiped` is `true` (when the block might have been wiped by an
+/// emergency wipe of the [`registry`]). Without an emergency wipe, zeros
+/// indicate a write after free.
+///
+/// # Safety
+/// `ptr` must be 8 byte aligned and valid for reads of `len` bytes, and `len`
+/// must be a multiple of 8.
+pub(super) unsafe fn is_poisoned(ptr: *const u8, len: usize, wiped: bool) -> bool {
+    let ptr: *const u64 = ptr.cast();
+    let words = len / 8;
+    // SAFETY: `ptr` is valid for reads of `len / 8` aligned `u64`s
+    let word = |i: usize| unsafe { ptr.add(i).read() };
+    let poison = u64::from_ne_bytes([POISON; 8]);
+    (0..words).all(|i| word(i) == poison) || wiped && (0..words).all(|i| word(i) == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_eviction() {
+        let quarantine = Quarantine::new(3);
+        assert!(quarantine.is_empty());
+        for i in 0..3 {
+            assert_eq!(quarantine.push(8 * i, 8), None);
+        }
+        let block =
+            |block: Option<(usize, usize, usize)>| block.map(|(offset, len, _)| (offset, len));
+        assert_eq!(block(quarantine.push(24, 16)), Some((0, 8)));
+        assert_eq!(quarantine.block_ending_at(40), Some(24));
+        assert_eq!(quarantine.block_ending_at(32), None);
+        quarantine.remove_from(16);
+        assert_eq!(block(quarantine.pop()), Some((8, 8)));
+        assert_eq!(quarantine.pop(), None);
+        assert_eq!(block(Quarantine::new(0).push(8, 8)), Some((8, 8)));
+
+        let poisoned = [POISON; 16];
+        let zeros = [0; 16];
+        let mut mixed = [POISON; 16];
+        mixed[5] = 0;
+        let aligned = |buf: &[u8; 16]| u128::from_ne_bytes(*buf);
+        for (buf, wiped, expected) in [
+            (poisoned, false, true),
+            (zeros, false, false),
+            (zeros, true, true),
+            (mixed, true, false),
+        ] {
+            let buf = aligned(&buf);
+            // SAFETY: `buf` is (at least) 8 byte aligned and 16 bytes long
+            let poisoned = unsafe { is_poisoned((&raw const buf).cast(), 16, wiped) };
+            assert_eq!(poisoned, expected);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Safe, typed stack API for [`SecStackSinglePageAlloc`].
+
+use super::SecStackSinglePageAlloc;
+use crate::page_provider::{OsPageProvider, PageProvider};
+use crate::util::align_up_usize;
+use alloc::alloc::handle_alloc_error;
+use allocator_api2::alloc::{AllocError, Allocator};
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// A value pushed on top of the stack of a [`SecStackSinglePageAlloc`],
+/// created by [`SecStackSinglePageAlloc::push`].
+///
+/// Dropping the `SecStackRef` pops the value from the stack, wiping its
+/// memory. When values are popped in last-in first-out order, all memory
+/// (including alignment padding) is reclaimed immediately. Use
+/// [`SecStackRef::pop`] to check the order explicitly: it returns an error
+/// instead of popping a value which is not on top of the stack. A value
+/// dropped out of order is still wiped, but (like any out of order
+/// deallocation) it's memory is not reclaimed: it is only reused once the
+/// allocator has no live allocations left.
+pub struct SecStackRef<'a, T, P: PageProvider = OsPageProvider> {
+    /// Pointer to the value.
+    ptr: NonNull<T>,
+    /// Stack offset of the allocator before the value was pushed.
+    // SAFETY INVARIANT: a multiple of 8, at most the start offset of the value
+    prev_offset: usize,
+    /// Allocator the value is allocated in.
+    alloc: &'a SecStackSinglePageAlloc<P>,
+    /// This type owns a `T`.
+    _phantom_val: PhantomData<T>,
+}
+
+/// Error returned by [`SecStackRef::pop`] when the value is not on top of the
+/// stack.
+///
+/// The error contains the `SecStackRef`, so it can be popped later.
+#[derive(thiserror::Error)]
+#[error("value is not on top of the stack")]
+pub struct PopOrderError<'a, T, P: PageProvider = OsPageProvider>(pub SecStackRef<'a, T, P>);
+
+impl<T, P: PageProvider> fmt::Debug for PopOrderError<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PopOrderError").field(&self.0).finish()
+    }
+}
+
+impl<P: PageProvider> SecStackSinglePageAlloc<P> {
+    /// Push `value` on top of the stack of the allocator.
+    ///
+    /// # Errors
+    /// Returns an error if the value does not fit the remaining memory of the
+    /// allocator's page.
+    pub fn try_push<T>(&self, value: T) -> Result<SecStackRef<'_, T, P>, AllocError> {
+        let prev_offset = self.stack_offset.get();
+        let ptr: NonNull<T> = self.allocate(Layout::new::<T>())?.cast();
+        // SAFETY: `ptr` is valid for writes of a `T` and properly aligned since it
+        // was allocated with the layout of `T`
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(SecStackRef {
+            ptr,
+            prev_offset,
+            alloc: self,
+            _phantom_val: PhantomData,
+        })
+    }
+
+    /// Push `value` on top of the stack of the allocator.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`] if the value does not fit the
+    /// remaining memory of the allocator's page.
+    pub fn push<T>(&self, value: T) -> SecStackRef<'_, T, P> {
+        match self.try_push(value) {
+            Ok(sec_ref) => sec_ref,
+            Err(AllocError) => handle_alloc_error(Layout::new::<T>()),
+        }
+    }
+}
+
+impl<'a, T, P: PageProvider> SecStackRef<'a, T, P> {
+    /// Returns the allocator the value is allocated in.
+    pub fn allocator(&self) -> &'a SecStackSinglePageAlloc<P> {
+        self.alloc
+    }
+
+    /// Returns `true` iff the value is on top of the stack, i.e. it can be
+    /// popped in order. Quarantined values above it (see
+    /// [`SecStackSinglePageAlloc::with_quarantine`]) are ignored.
+    pub fn is_top(&self) -> bool {
+        if size_of::<T>() == 0 {
+            // zero sized values take no place on the stack
+            return true;
+        }
+        // `self.ptr` was allocated with `self.alloc` and the rounded size fits it
+        self.alloc
+            .ptr_is_last_live_allocation(self.ptr.cast(), align_up_usize(size_of::<T>(), 8))
+    }
+
+    /// Pop the value from the top of the stack and return it, wiping it's
+    /// memory.
+    ///
+    /// # Errors
+    /// Returns an error containing `self` if the value is not on top of the
+    /// stack. The stack is unchanged in this case.
+    pub fn pop(self) -> Result<T, PopOrderError<'a, T, P>> {
+        if !self.is_top() {
+            return Err(PopOrderError(self));
+        }
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this.ptr` points to a valid `T`, which is not used afterwards
+        let value = unsafe { this.ptr.as_ptr().read() };
+        // SAFETY: the value is moved out, and `this` is not used afterwards
+        unsafe { this.release() };
+        Ok(value)
+    }
+
+    /// Deallocate the memory of the value, and reclaim the alignment padding
+    /// before it if it is on top of the stack.
+    ///
+    /// # Safety
+    /// The value must already be dropped or moved out, and `self` must not be
+    /// used afterwards.
+    unsafe fn release(&self) {
+        let is_top = self.is_top();
+        // SAFETY: `self.ptr` was allocated with `self.alloc` and the layout of `T`,
+        // and is not used afterwards
+        unsafe {
+            self.alloc.deallocate(self.ptr.cast(), Layout::new::<T>());
+        }
+        // if the value was on top of the stack (and not quarantined), the stack
+        // offset is now rewound to the start of the value; rewind further to before
+        // the alignment padding, which is never allocated while the value lives.
+        // Zero sized values are always on top but take no place, so the stack
+        // offset must not be touched: values pushed after them may still be live
+        let offset = self
+            .ptr
+            .addr()
+            .get()
+            .wrapping_sub(self.alloc.page.as_ptr().addr());
+        if is_top
+            && size_of::<T>() != 0
+            && self.alloc.stack_offset.get() == offset
+            && offset > self.prev_offset
+        {
+            // SAFETY: `self.prev_offset` is a multiple of 8 and at most the page size
+            self.alloc.stack_offset.set(self.prev_offset);
+        }
+    }
+}
+
+impl<T, P: PageProvider> Drop for SecStackRef<'_, T, P> {
+    fn drop(&mut self) {
+        // SAFETY: `self.ptr` points to a valid `T`, which is not used afterwards
+        unsafe { self.ptr.as_ptr().drop_in_place() };
+        // SAFETY: the value is dropped, and `self` is not used afterwards
+        unsafe { self.release() };
+    }
+}
+
+impl<T, P: PageProvider> Deref for SecStackRef<'_, T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: `self.ptr` points to a valid `T` owned by `self`
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, P: PageProvider> DerefMut for SecStackRef<'_, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: `self.ptr` points to a valid `T` owned by `self`
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, P: PageProvider> fmt::Debug for SecStackRef<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the value is secret
+        f.debug_struct("SecStackRef").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    #[repr(align(64))]
+    struct Align64([u8; 3]);
+
+    #[test]
+    fn push_pop_in_order() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let a = allocator.push(1_u8);
+        let b = allocator.push(Align64([2; 3]));
+        let mut c = allocator.push([3_u64; 5]);
+        c[1] = 7;
+        assert!(c.is_top());
+        assert!(!b.is_top());
+        assert_eq!(c.pop().expect("in order pop"), [3, 7, 3, 3, 3]);
+        assert_eq!(b.pop().expect("in order pop"), Align64([2; 3]));
+        // the alignment padding is reclaimed
+        assert_eq!(allocator.stack_offset.get(), 8);
+        assert_eq!(a.pop().expect("in order pop"), 1);
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn pop_out_of_order() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let a = allocator.push(1_u64);
+        let b = allocator.push(2_u64);
+        let Err(PopOrderError(a)) = a.pop() else {
+            panic!("out of order pop must fail");
+        };
+        assert_eq!(*a, 1);
+        assert_eq!(b.pop().expect("in order pop"), 2);
+        assert_eq!(a.pop().expect("in order pop"), 1);
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn drop_out_of_order() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let a = allocator.push([1_u8; 17]);
+        let b = allocator.push(Align64([2; 3]));
+        let c = allocator.push(());
+        drop(a);
+        allocator.consistency_check();
+        drop(c);
+        drop(b);
+        allocator.consistency_check();
+        assert_eq!(allocator.stack_offset.get(), 0);
+    }
+
+    #[test]
+    fn drop_zero_sized_out_of_order() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let a = allocator.push(1_u64);
+        let zst = allocator.push(());
+        let b = allocator.push(2_u64);
+        drop(zst);
+        // the stack offset must not be rewound below the live `b`
+        let c = allocator.push(3_u64);
+        assert_ne!(&raw const *c, &raw const *b);
+        assert_eq!((*a, *b, *c), (1, 2, 3));
+        drop(c);
+        drop(b);
+        drop(a);
+        allocator.consistency_check();
+    }
+
+    #[test]
+    fn push_page_full() {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        assert!(allocator.try_push([0xAF_u8; 1 << 17]).is_err());
+        allocator.consistency_check();
+    }
+}
@@ -0,0 +1,600 @@
+//! A locked buffer of arbitrary size, which is wiped on drop.
+//!
+//! Not every secret needs an allocator: e.g. the scratch area of a memory
+//! hard key derivation function is a single large buffer. A [`SecRegion`] maps
+//! the required number of memory pages, locks them, and exposes them as a
+//! `[u8]` slice. On drop, the memory is wiped and the pages are unmapped.
+//!
+//! Large buffers of which only a small part might be used can be reserved
+//! using [`SecRegion::reserve`]: the address range is mapped up front, but
+//! memory is only locked as the buffer grows (see [`SecRegion::resize`]), and
+//! then only when it is first accessed (using `mlock2(MLOCK_ONFAULT)` on
+//! Linux). This way, the unused part of the reservation doesn't count towards
+//! the locked memory limit of the process (`RLIMIT_MEMLOCK`), and doesn't use
+//! physical memory.
+//!
+//! The buffer is surrounded by inaccessible guard pages, so out of bounds
+//! accesses just before or after it (e.g. by a buggy unsafe block or C
+//! library) crash the process instead of reading or overwriting secrets. The
+//! memory pages of the [`OsPageProvider`] are also excluded from core dumps
+//! and not inherited by child processes, see [`SecRegion::protections`].
+
+use crate::internals::mem;
+use crate::page_provider::{OsPageProvider, PageProtections, PageProvider};
+use crate::zeroize::WipeOptions;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// A buffer of locked memory pages, which is wiped on drop.
+///
+/// The buffer is zero initialised and dereferences to a `[u8]` slice of the
+/// requested length. The pages are allocated and locked using the page
+/// provider `P` (by default the [`OsPageProvider`]), so they have the same
+/// protections as the memory page of a
+/// [`SecStackSinglePageAlloc`](crate::sec_alloc::SecStackSinglePageAlloc)
+/// with the same provider, see [`Self::protections`]. The pages are
+/// surrounded by guard pages, which are made inaccessible using
+/// [`Protection::NoAccess`](crate::page_provider::Protection::NoAccess), and
+/// registered in the [`registry`](crate::registry) when it is enabled.
+///
+/// # Examples
+/// ```
+/// use secmem_alloc::sec_region::SecRegion;
+///
+/// let mut scratch = SecRegion::new(1 << 16).expect("could not map locked memory");
+/// assert_eq!(scratch.len(), 1 << 16);
+/// scratch[..5].copy_from_slice(b"hello");
+/// // `scratch` is wiped and unmapped on drop
+/// ```
+pub struct SecRegion<P: PageProvider = OsPageProvider> {
+    /// The locked memory pages.
+    pages: mem::Page<P>,
+    /// Length of the buffer in bytes.
+    // SAFETY INVARIANT: at most the locked length of `pages`
+    len: usize,
+    /// How to wipe the memory on drop.
+    wipe: WipeOptions,
+}
+
+// SAFETY: `SecRegion` uniquely owns it's memory pages like a `Box<[u8]>`, and
+// shared references only give shared access to the memory and the provider
+unsafe impl<P: PageProvider + Sync> Sync for SecRegion<P> {}
+
+#[cfg(any(unix, windows))]
+impl SecRegion {
+    /// Create a new zero initialised buffer of `len` bytes, backed by locked
+    /// memory pages from the operating system.
+    ///
+    /// # Errors
+    /// The function returns an `PageAllocError` if the pages could not be
+    /// mapped, protected or locked, e.g. because the process would exceed the
+    /// amount of memory it is allowed to lock.
+    ///
+    /// # Panics
+    /// Panics if rounding `len` up to a multiple of the page size (plus the
+    /// guard pages) overflows.
+    pub fn new(len: usize) -> Result<Self, mem::PageAllocError> {
+        Self::new_with_provider(len, OsPageProvider)
+    }
+
+    /// Reserve an empty buffer which can grow up to `capacity` bytes, backed
+    /// by memory pages from the operating system which are locked as the
+    /// buffer grows. See [`Self::reserve_with_provider`].
+    ///
+    /// # Errors
+    /// The function returns an `PageAllocError` if the pages could not be
+    /// mapped or protected.
+    ///
+    /// # Panics
+    /// Panics if rounding `capacity` up to a multiple of the page size (plus
+    /// the guard pages) overflows.
+    pub fn reserve(capacity: usize) -> Result<Self, mem::PageAllocError> {
+        Self::reserve_with_provider(capacity, OsPageProvider)
+    }
+}
+
+impl<P: PageProvider> SecRegion<P> {
+    /// Create a new zero initialised buffer of `len` bytes, backed by locked
+    /// memory pages from `provider`.
+    ///
+    /// `len` is rounded up to a multiple of the page size (at least one page)
+    /// to allocate the pages; the buffer has length `len`. One more page is
+    /// allocated before and after the buffer as guard pages, so the provider
+    /// must support [`Protection::NoAccess`](crate::page_provider::Protection::NoAccess).
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if the pages could not
+    /// be allocated, protected or locked.
+    ///
+    /// # Panics
+    /// Panics if rounding `len` up to a multiple of the page size (plus the
+    /// guard pages) overflows.
+    pub fn new_with_provider(len: usize, provider: P) -> Result<Self, P::Error> {
+        let page_size = provider.page_size();
+        let pages_len = len
+            .max(1)
+            .checked_next_multiple_of(page_size)
+            .expect("capacity overflow");
+        let pages = mem::Page::alloc_new_lock_len_in(provider, pages_len, true)?;
+        Ok(Self {
+            pages,
+            len,
+            wipe: WipeOptions {
+                discard_pages: false,
+                flush_cache: false,
+            },
+        })
+    }
+
+    /// Reserve an empty buffer which can grow up to `capacity` bytes, backed
+    /// by memory pages from `provider`.
+    ///
+    /// The address range is allocated up front, but the pages are only locked
+    /// as the buffer grows using [`Self::resize`], using
+    /// [`PageProvider::lock_on_fault`]. Only the pages which are locked count
+    /// towards the locked memory limit of the process, and are wiped on drop.
+    /// `capacity` is rounded up to a multiple of the page size (at least one
+    /// page). The buffer is surrounded by guard pages like for
+    /// [`Self::new_with_provider`].
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if the pages could not
+    /// be allocated or protected.
+    ///
+    /// # Panics
+    /// Panics if rounding `capacity` up to a multiple of the page size (plus
+    /// the guard pages) overflows.
+    pub fn reserve_with_provider(capacity: usize, provider: P) -> Result<Self, P::Error> {
+        let page_size = provider.page_size();
+        let pages_len = capacity
+            .max(1)
+            .checked_next_multiple_of(page_size)
+            .expect("capacity overflow");
+        let pages = mem::Page::alloc_new_reserve_len_in(provider, pages_len, true)?;
+        Ok(Self {
+            pages,
+            len: 0,
+            wipe: WipeOptions {
+                discard_pages: false,
+                flush_cache: false,
+            },
+        })
+    }
+
+    /// Enable or disable discarding of the memory pages when the buffer is
+    /// wiped on drop.
+    ///
+    /// When enabled, the whole memory pages of large buffers (of at least 128
+    /// KiB) are discarded using the provider (`madvise(MADV_DONTNEED_LOCKED)`
+    /// on Linux 5.18 and later for the [`OsPageProvider`]) instead of
+    /// overwritten with zeros, which is much faster. However, the discarded
+    /// pages are handed back to the kernel with their old contents, which
+    /// only zeroes them when they are reused. Disabled by default.
+    pub fn with_page_discard(mut self, discard_pages: bool) -> Self {
+        self.wipe.discard_pages = discard_pages;
+        self
+    }
+
+    /// Enable or disable flushing of the CPU caches after the memory is wiped
+    /// on drop, like
+    /// [`SecStackSinglePageAlloc::with_cache_flush`](crate::sec_alloc::SecStackSinglePageAlloc::with_cache_flush).
+    /// Disabled by default.
+    pub fn with_cache_flush(mut self, flush_cache: bool) -> Self {
+        self.wipe.flush_cache = flush_cache;
+        self
+    }
+
+    /// Populate the locked memory pages of the buffer when `populate` is
+    /// `true`, so that writing to them doesn't cause page faults, like
+    /// [`Self::prewarm`]. Meant to be called directly after creating the
+    /// buffer. The pages of a [reserved](Self::reserve) buffer are not locked
+    /// yet, so this only populates the pages locked by [`Self::resize`] before.
+    pub fn with_populate(self, populate: bool) -> Self {
+        if populate {
+            let len = self.pages.locked_len();
+            // SAFETY: the pointer to the pages is non-null
+            let ptr = unsafe { NonNull::new_unchecked(self.pages.as_ptr_mut()) };
+            // SAFETY: the first `len` bytes of the pages are locked, so valid for reads
+            // and writes, and not accessed concurrently since we own `self`
+            unsafe { mem::populate_pages(ptr, len) };
+        }
+        self
+    }
+
+    /// Returns the length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` iff the buffer has length 0.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximal length of the buffer in bytes, the length of the
+    /// memory pages backing it.
+    pub fn capacity(&self) -> usize {
+        self.pages.page_size()
+    }
+
+    /// Resize the buffer to `new_len` bytes.
+    ///
+    /// When the buffer grows, the new bytes are zero; the pages they are in
+    /// are locked first if they aren't yet. When the buffer shrinks, the
+    /// removed bytes are zeroized. Their pages stay locked.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if the pages could not
+    /// be locked. The buffer is unchanged in that case.
+    ///
+    /// # Panics
+    /// Panics if `new_len` exceeds the [capacity](Self::capacity) of the
+    /// buffer.
+    pub fn resize(&mut self, new_len: usize) -> Result<(), P::Error> {
+        assert!(
+            new_len <= self.capacity(),
+            "new length exceeds the capacity of the region"
+        );
+        if new_len < self.len {
+            crate::zeroize_slice(&mut self.as_mut_slice()[new_len..]);
+        } else {
+            let page_size = self.pages.provider().page_size();
+            // can't overflow since the capacity is a multiple of the page size
+            self.pages
+                .lock_on_fault_to(new_len.next_multiple_of(page_size))?;
+        }
+        // the bytes beyond `self.len` in the locked pages are zero: they are
+        // either fresh or were zeroized when the buffer shrunk
+        self.len = new_len;
+        Ok(())
+    }
+
+    /// Returns the buffer as a slice.
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: the pages are valid for reads of `self.len` bytes, initialised
+        // (zero or written through `as_mut_slice`) and owned by `self`
+        unsafe { core::slice::from_raw_parts(self.pages.as_ptr(), self.len) }
+    }
+
+    /// Returns the buffer as a mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the pages are valid for reads and writes of `self.len` bytes,
+        // initialised and uniquely owned by `self`
+        unsafe { core::slice::from_raw_parts_mut(self.pages.as_ptr_mut(), self.len) }
+    }
+
+    /// Pre-fault the first `capacity` bytes of the memory pages (at most the
+    /// [capacity](Self::capacity) of the buffer), so that writing to them
+    /// doesn't cause page faults, e.g. in a latency critical code path. The
+    /// pages of a [reserved](Self::reserve) buffer are locked first, like by
+    /// [`Self::resize`]. Use `usize::MAX` to pre-fault the whole buffer. See
+    /// [`Self::with_populate`] to populate the buffer at creation.
+    ///
+    /// This uses `madvise(MADV_POPULATE_WRITE)` on Linux where supported, and
+    /// otherwise writes to every memory page. The contents of the buffer are
+    /// not modified.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if the pages could not
+    /// be locked.
+    pub fn prewarm(&mut self, capacity: usize) -> Result<(), P::Error> {
+        let len = capacity.min(self.capacity());
+        let page_size = self.pages.provider().page_size();
+        // can't overflow since the capacity is a multiple of the page size
+        self.pages
+            .lock_on_fault_to(len.next_multiple_of(page_size))?;
+        // SAFETY: the pointer to the pages is non-null
+        let ptr = unsafe { NonNull::new_unchecked(self.pages.as_ptr_mut()) };
+        // SAFETY: the first `len` bytes of the pages are locked, so valid for reads
+        // and writes, and not accessed concurrently since we have a mutable
+        // reference to `self`
+        unsafe { mem::populate_pages(ptr, len) };
+        Ok(())
+    }
+
+    /// Returns a reference to the page provider of the buffer.
+    pub fn provider(&self) -> &P {
+        self.pages.provider()
+    }
+
+    /// Returns the security properties of the memory pages of the buffer,
+    /// e.g. whether they are locked, excluded from kernel same-page merging
+    /// and from core dumps.
+    pub fn protections(&self) -> PageProtections {
+        self.pages.provider().protections()
+    }
+}
+
+impl<P: PageProvider> Drop for SecRegion<P> {
+    fn drop(&mut self) {
+        // only the locked pages can have been used; the other pages of a
+        // reservation were never accessed, so wiping them would only populate them
+        let locked_len = self.pages.locked_len();
+        if locked_len == 0 {
+            return;
+        }
+        mem::check_access(self.pages.as_ptr(), locked_len, true);
+        // SAFETY: the pages are allocated by the provider, locked, valid for writes
+        // of `locked_len` bytes and no longer used; they are released afterwards
+        // when `self.pages` is dropped
+        unsafe {
+            self.wipe.wipe(
+                self.pages.provider(),
+                self.pages.as_ptr_mut(),
+                locked_len,
+                true,
+            );
+        }
+    }
+}
+
+impl<P: PageProvider> Deref for SecRegion<P> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<P: PageProvider> DerefMut for SecRegion<P> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl<P: PageProvider> fmt::Debug for SecRegion<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the contents are secret
+        f.debug_struct("SecRegion")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_page_region() {
+        let page_size = mem::page_size();
+        let len = 3 * page_size + 5;
+        let mut region = SecRegion::new(len).expect("region creation failed");
+        assert_eq!(region.len(), len);
+        assert_eq!(region.pages.page_size(), 4 * page_size);
+        assert!(region.iter().all(|&b| b == 0));
+        region.fill(0xAF);
+        assert_eq!(region[len - 1], 0xAF);
+        assert_eq!(region.protections(), OsPageProvider.protections());
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&region);
+        assert_eq!(
+            std::format!("{region:?}"),
+            std::format!("SecRegion {{ len: {len}, .. }}")
+        );
+    }
+
+    #[test]
+    fn empty_region() {
+        let region = SecRegion::new(0)
+            .expect("region creation failed")
+            .with_cache_flush(true);
+        assert!(region.is_empty());
+        assert_eq!(region.as_slice(), &[]);
+    }
+
+    #[test]
+    fn reserved_region_grows() {
+        let page_size = mem::page_size();
+        let mut region = SecRegion::reserve(64 * page_size).expect("region creation failed");
+        assert!(region.is_empty());
+        assert_eq!(region.capacity(), 64 * page_size);
+        assert_eq!(region.pages.locked_len(), 0);
+        region.resize(page_size + 5).expect("resize failed");
+        assert_eq!(region.pages.locked_len(), 2 * page_size);
+        assert!(region.iter().all(|&b| b == 0));
+        region.fill(0xAF);
+        region.resize(10).expect("resize failed");
+        // the pages stay locked, and the removed bytes are zeroized
+        assert_eq!(region.pages.locked_len(), 2 * page_size);
+        region.resize(page_size).expect("resize failed");
+        assert_eq!(region[..10], [0xAF; 10]);
+        assert!(region[10..].iter().all(|&b| b == 0));
+        region.resize(64 * page_size).expect("resize failed");
+        assert_eq!(region.pages.locked_len(), 64 * page_size);
+    }
+
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn reserved_region_locked_on_fault() {
+        let page_size = mem::page_size();
+        let mut region = SecRegion::reserve(64 * page_size).expect("region creation failed");
+        region.resize(3 * page_size).expect("resize failed");
+        let start = region.as_ptr().addr();
+        assert!(mem::smaps_vm_flags(start).iter().any(|f| f == "lo"));
+        assert!(
+            !mem::smaps_vm_flags(start + 63 * page_size)
+                .iter()
+                .any(|f| f == "lo")
+        );
+    }
+
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn region_guard_pages() {
+        let page_size = mem::page_size();
+        for region in [
+            SecRegion::new(2 * page_size).expect("region creation failed"),
+            SecRegion::reserve(2 * page_size).expect("region creation failed"),
+        ] {
+            let start = region.pages.as_ptr().addr();
+            for guard in [start - page_size, start + 2 * page_size] {
+                let flags = mem::smaps_vm_flags(guard);
+                assert!(!flags.iter().any(|f| f == "rd" || f == "wr"), "{flags:?}");
+            }
+            let flags = mem::smaps_vm_flags(start);
+            assert!(flags.iter().any(|f| f == "rd"), "{flags:?}");
+            assert!(flags.iter().any(|f| f == "dd"), "{flags:?}");
+        }
+    }
+
+    #[test]
+    fn page_discard_opt_in() {
+        let region = SecRegion::new(1 << 18).expect("region creation failed");
+        assert!(!region.wipe.discard_pages);
+        let mut region = region.with_page_discard(true);
+        assert!(region.wipe.discard_pages);
+        region.fill(0xAF);
+        // drop `region`, discarding the pages
+    }
+
+    #[test]
+    fn prewarm_reserved_region() {
+        let page_size = mem::page_size();
+        let mut region = SecRegion::reserve(16 * page_size).expect("region creation failed");
+        region.resize(10).expect("resize failed");
+        region.fill(0xAF);
+        region.prewarm(3 * page_size + 1).expect("prewarm failed");
+        assert_eq!(region.pages.locked_len(), 4 * page_size);
+        assert_eq!(region.len(), 10);
+        assert_eq!(region[..], [0xAF; 10]);
+        region.prewarm(usize::MAX).expect("prewarm failed");
+        assert_eq!(region.pages.locked_len(), 16 * page_size);
+    }
+
+    #[test]
+    fn populate_at_creation() {
+        let page_size = mem::page_size();
+        let mut region = SecRegion::new(3 * page_size + 5)
+            .expect("region creation failed")
+            .with_populate(true);
+        assert!(region.iter().all(|&b| b == 0));
+        region.fill(0xAF);
+        assert_eq!(region.pages.locked_len(), 4 * page_size);
+        // only the locked pages of a reserved buffer are populated
+        let region = SecRegion::reserve(16 * page_size)
+            .expect("region creation failed")
+            .with_populate(true);
+        assert_eq!(region.pages.locked_len(), 0);
+    }
+
+    #[test]
+    fn populate_unaligned_range() {
+        let mut buf: std::vec::Vec<u8> = (0..=255).cycle().take(3 * mem::page_size()).collect();
+        let expected = buf.clone();
+        let ptr = NonNull::from(&mut buf[7..]).cast::<u8>();
+        // SAFETY: the range lies in `buf`, which is not accessed concurrently
+        unsafe { mem::populate_pages(ptr, buf.len() - 20) };
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    #[should_panic = "new length exceeds the capacity of the region"]
+    fn resize_beyond_capacity() {
+        let mut region = SecRegion::reserve(1).expect("region creation failed");
+        let _ = region.resize(region.capacity() + 1);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn new_failures_release_pages() {
+        use crate::testing::{self, PageOp};
+
+        // expected (map, protect, lock, unmap) calls when the first `op` call fails
+        let cases = [
+            (PageOp::Map, (1, 0, 0, 0)),
+            (PageOp::Protect, (1, 1, 0, 1)),
+            (PageOp::Lock, (1, 2, 1, 1)),
+        ];
+        for (op, expected) in cases {
+            testing::reset();
+            testing::fail_nth(op, 1, 12);
+            assert!(SecRegion::new(3 * mem::page_size()).is_err());
+            let counts = testing::call_counts();
+            assert_eq!(
+                (counts.map, counts.protect, counts.lock, counts.unmap),
+                expected,
+                "{op:?}"
+            );
+        }
+        testing::reset();
+        testing::fail_nth(PageOp::Map, 1, 12);
+        assert!(SecRegion::reserve(3 * mem::page_size()).is_err());
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.unmap), (1, 0));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn resize_lock_failure() {
+        use crate::testing::{self, PageOp};
+
+        let page_size = mem::page_size();
+        testing::reset();
+        let mut region = SecRegion::reserve(4 * page_size).expect("region creation failed");
+        testing::fail_nth(PageOp::Lock, 1, 12);
+        assert!(region.resize(page_size + 1).is_err());
+        // the region is unchanged
+        assert!(region.is_empty());
+        assert_eq!(region.pages.locked_len(), 0);
+        region.resize(page_size + 1).expect("resize failed");
+        assert_eq!(region.pages.locked_len(), 2 * page_size);
+        drop(region);
+        let counts = testing::call_counts();
+        assert_eq!((counts.map, counts.lock, counts.unmap), (1, 2, 1));
+    }
+
+    /// Page provider handing out a leaked buffer, which is not zeroized on
+    /// release.
+    struct LeakedBufferProvider(core::ptr::NonNull<u8>);
+
+    // SAFETY: the buffer is 8 byte aligned, zero initialised and can be allocated
+    // only once per test
+    unsafe impl PageProvider for LeakedBufferProvider {
+        type Error = core::convert::Infallible;
+
+        fn page_size(&self) -> usize {
+            64
+        }
+
+        fn allocate(&self, len: usize) -> Result<core::ptr::NonNull<u8>, Self::Error> {
+            // a page and the guard pages
+            assert_eq!(len, 3 * 64);
+            Ok(self.0)
+        }
+
+        unsafe fn lock(
+            &self,
+            _ptr: core::ptr::NonNull<u8>,
+            _len: usize,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        unsafe fn protect(
+            &self,
+            _ptr: core::ptr::NonNull<u8>,
+            _len: usize,
+            _protection: crate::page_provider::Protection,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        unsafe fn release(&self, _ptr: core::ptr::NonNull<u8>, _len: usize) {}
+    }
+
+    #[test]
+    fn region_wiped_on_drop() {
+        let buf: &'static mut [u64; 24] = std::boxed::Box::leak(std::boxed::Box::new([0; 24]));
+        let ptr = core::ptr::NonNull::from(buf).cast::<u8>();
+        let mut region =
+            SecRegion::new_with_provider(40, LeakedBufferProvider(ptr)).expect("region creation");
+        // the buffer starts after the first guard page
+        assert_eq!(region.as_ptr(), ptr.as_ptr().wrapping_add(64).cast_const());
+        region.fill(0xAF);
+        drop(region);
+        // SAFETY: the leaked buffer is still valid, and no longer used by the region
+        assert_eq!(unsafe { ptr.cast::<[u64; 24]>().read() }, [0; 24]);
+    }
+}
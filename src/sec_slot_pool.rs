@@ -0,0 +1,387 @@
+//! Pool of fixed-size slots in locked memory, for secrets of a constant size.
+//!
+//! Most secrets (e.g. symmetric keys) have a fixed size of 32 or 64 bytes. A
+//! [`SecSlotPool<N>`](SecSlotPool) packs such secrets into locked memory pages
+//! without any padding or fragmentation: the pages are divided into slots of
+//! exactly `N` bytes, and a bitmap of free slots allows allocating and freeing
+//! slots in constant time, in any order. Slots are handed out as zeroed
+//! [`SecSlot<'_, [u8; N]>`](SecSlot)s, which zeroize their slot when dropped.
+
+use crate::internals::mem;
+use crate::page_provider::{OsPageProvider, PageProtections, PageProvider};
+use crate::zeroize::{WipeOptions, zeroize_mem};
+use alloc::alloc::handle_alloc_error;
+use allocator_api2::alloc::AllocError;
+use core::alloc::Layout;
+use core::cell::Cell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// Number of bits in a bitmap word.
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Returns a word with the lowest `n` bits set, for `n <= 64`.
+fn low_bits(n: usize) -> u64 {
+    if n >= WORD_BITS {
+        u64::MAX
+    } else {
+        (1 << n) - 1
+    }
+}
+
+/// Two level bitmap of the free slots of a [`SecSlotPool`].
+struct FreeSlots {
+    /// Bitmap of free slots: bit `i % 64` of word `i / 64` is set iff slot `i`
+    /// is free.
+    words: [Cell<u64>; WORD_BITS],
+    /// Bit `i` is set iff word `i` of `words` is non-zero.
+    summary: Cell<u64>,
+    /// Number of allocated slots.
+    len: Cell<usize>,
+}
+
+impl FreeSlots {
+    /// Create a bitmap in which the slots `0 .. capacity` are free, for
+    /// `capacity <= WORD_BITS * WORD_BITS`.
+    fn new(capacity: usize) -> Self {
+        let words = core::array::from_fn(|word| {
+            let slots_in_word = capacity.saturating_sub(word * WORD_BITS).min(WORD_BITS);
+            Cell::new(low_bits(slots_in_word))
+        });
+        Self {
+            words,
+            summary: Cell::new(low_bits(capacity.div_ceil(WORD_BITS))),
+            len: Cell::new(0),
+        }
+    }
+
+    /// Mark the lowest free slot as allocated and return it, or returns `None`
+    /// if all slots are allocated.
+    fn take(&self) -> Option<usize> {
+        let summary = self.summary.get();
+        if summary == 0 {
+            return None;
+        }
+        let word = summary.trailing_zeros() as usize;
+        let bits = self.words[word].get();
+        let bit = bits.trailing_zeros() as usize;
+        let bits = bits & !(1 << bit);
+        self.words[word].set(bits);
+        if bits == 0 {
+            self.summary.set(summary & !(1 << word));
+        }
+        self.len.set(self.len.get() + 1);
+        Some(word * WORD_BITS + bit)
+    }
+
+    /// Mark the allocated slot `slot` as free.
+    fn release(&self, slot: usize) {
+        let (word, bit) = (slot / WORD_BITS, slot % WORD_BITS);
+        debug_assert!(self.words[word].get() & (1 << bit) == 0);
+        self.words[word].set(self.words[word].get() | (1 << bit));
+        self.summary.set(self.summary.get() | (1 << word));
+        self.len.set(self.len.get() - 1);
+    }
+}
+
+/// A pool of slots of `N` bytes in locked memory pages.
+///
+/// The pool has a fixed [capacity](Self::capacity), at most
+/// [`Self::MAX_SLOTS`]. The free slots are tracked in a two level bitmap, so
+/// [`alloc`](Self::alloc) and dropping a [`SecSlot`] take constant time. On
+/// drop, the memory pages are wiped and released.
+///
+/// # Examples
+/// ```
+/// use secmem_alloc::sec_slot_pool::SecSlotPool;
+///
+/// let pool: SecSlotPool<32> = SecSlotPool::new(100).expect("could not map locked memory");
+/// let mut key = pool.alloc();
+/// key.copy_from_slice(&[0xAF; 32]);
+/// assert_eq!(pool.len(), 1);
+/// // the slot is zeroized and freed on drop
+/// drop(key);
+/// assert!(pool.is_empty());
+/// ```
+pub struct SecSlotPool<const N: usize, P: PageProvider = OsPageProvider> {
+    /// The locked memory pages holding the slots.
+    pages: mem::Page<P>,
+    /// The free slots.
+    // SAFETY INVARIANT: only slots less than `capacity` are free
+    free: FreeSlots,
+    /// Number of slots.
+    // SAFETY INVARIANT: `capacity * N` is at most the length of `pages`
+    capacity: usize,
+    /// How to wipe the memory on drop.
+    wipe: WipeOptions,
+}
+
+#[cfg(any(unix, windows))]
+impl<const N: usize> SecSlotPool<N> {
+    /// Create a new pool with room for at least `slots` slots (at most
+    /// [`Self::MAX_SLOTS`]), backed by locked memory pages from the operating
+    /// system. See [`Self::new_with_provider`].
+    ///
+    /// # Errors
+    /// The function returns an `PageAllocError` if the pages could not be
+    /// mapped or locked.
+    ///
+    /// # Panics
+    /// Panics if `N` is zero, or if `slots` exceeds [`Self::MAX_SLOTS`].
+    pub fn new(slots: usize) -> Result<Self, mem::PageAllocError> {
+        Self::new_with_provider(slots, OsPageProvider)
+    }
+}
+
+impl<const N: usize, P: PageProvider> SecSlotPool<N, P> {
+    /// Maximal number of slots of a pool.
+    pub const MAX_SLOTS: usize = WORD_BITS * WORD_BITS;
+
+    /// Create a new pool with room for at least `slots` slots, backed by
+    /// locked memory pages from `provider`.
+    ///
+    /// The memory for `slots` slots is rounded up to a multiple of the page
+    /// size (at least one page), and the pool uses all of it, up to
+    /// [`Self::MAX_SLOTS`] slots.
+    ///
+    /// # Errors
+    /// The function returns the error of the provider if the pages could not
+    /// be allocated or locked.
+    ///
+    /// # Panics
+    /// Panics if `N` is zero, or if `slots` exceeds [`Self::MAX_SLOTS`].
+    pub fn new_with_provider(slots: usize, provider: P) -> Result<Self, P::Error> {
+        assert!(N != 0, "slots must not be zero sized");
+        assert!(
+            slots <= Self::MAX_SLOTS,
+            "number of slots exceeds `MAX_SLOTS`"
+        );
+        let page_size = provider.page_size();
+        let pages_len = slots
+            .checked_mul(N)
+            .and_then(|len| len.max(1).checked_next_multiple_of(page_size))
+            .expect("capacity overflow");
+        let pages = mem::Page::alloc_new_lock_len_in(provider, pages_len, false)?;
+        let capacity = (pages_len / N).min(Self::MAX_SLOTS);
+        Ok(Self {
+            pages,
+            free: FreeSlots::new(capacity),
+            capacity,
+            wipe: WipeOptions {
+                discard_pages: false,
+                flush_cache: false,
+            },
+        })
+    }
+
+    /// Enable or disable flushing of the CPU caches after the memory is wiped
+    /// on drop, like
+    /// [`SecStackSinglePageAlloc::with_cache_flush`](crate::sec_alloc::SecStackSinglePageAlloc::with_cache_flush).
+    /// Slots are always zeroized without flushing when they are freed.
+    /// Disabled by default.
+    pub fn with_cache_flush(mut self, flush_cache: bool) -> Self {
+        self.wipe.flush_cache = flush_cache;
+        self
+    }
+
+    /// Returns the number of slots of the pool.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of allocated slots.
+    pub fn len(&self) -> usize {
+        self.free.len.get()
+    }
+
+    /// Returns `true` iff no slots are allocated.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a reference to the page provider of the pool.
+    pub fn provider(&self) -> &P {
+        self.pages.provider()
+    }
+
+    /// Returns the security properties of the memory pages of the pool, e.g.
+    /// whether they are locked and excluded from kernel same-page merging.
+    pub fn protections(&self) -> PageProtections {
+        self.pages.provider().protections()
+    }
+
+    /// Allocate a zeroed slot.
+    ///
+    /// # Errors
+    /// Returns an error if all slots are allocated.
+    pub fn try_alloc(&self) -> Result<SecSlot<'_, [u8; N]>, AllocError> {
+        let slot = self.free.take().ok_or(AllocError)?;
+        // SAFETY: only slots less than `self.capacity` are free, so the slot lies in
+        // the memory pages
+        let ptr = unsafe { NonNull::new_unchecked(self.pages.as_ptr_mut().add(slot * N)) };
+        mem::check_access(ptr.as_ptr(), N, true);
+        Ok(SecSlot {
+            ptr: ptr.cast(),
+            slot,
+            free: &self.free,
+        })
+    }
+
+    /// Allocate a zeroed slot.
+    ///
+    /// # Panics
+    /// Aborts using [`handle_alloc_error`] if all slots are allocated.
+    pub fn alloc(&self) -> SecSlot<'_, [u8; N]> {
+        match self.try_alloc() {
+            Ok(slot) => slot,
+            Err(AllocError) => handle_alloc_error(Layout::new::<[u8; N]>()),
+        }
+    }
+}
+
+impl<const N: usize, P: PageProvider> Drop for SecSlotPool<N, P> {
+    fn drop(&mut self) {
+        // all slots borrow the pool, so they are zeroized already; wipe anyway, so
+        // no secrets remain even if a slot was leaked
+        mem::check_access(self.pages.as_ptr(), self.pages.page_size(), true);
+        // SAFETY: the pages are allocated by the provider, locked, valid for writes
+        // of their whole length and no longer used; they are released afterwards
+        // when `self.pages` is dropped
+        unsafe {
+            self.wipe.wipe(
+                self.pages.provider(),
+                self.pages.as_ptr_mut(),
+                self.pages.page_size(),
+                true,
+            );
+        }
+    }
+}
+
+impl<const N: usize, P: PageProvider> fmt::Debug for SecSlotPool<N, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecSlotPool")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A slot of a [`SecSlotPool`] holding a `T`, created by
+/// [`SecSlotPool::alloc`].
+///
+/// A `SecSlotPool<N>` hands out `SecSlot<'_, [u8; N]>`s, which dereference to
+/// a `[u8; N]` array that is zero initialised. Dropping the `SecSlot` zeroizes
+/// the slot and frees it.
+pub struct SecSlot<'a, T> {
+    /// Pointer to the slot.
+    ptr: NonNull<T>,
+    /// Index of the slot.
+    slot: usize,
+    /// Free slots of the pool the slot is allocated in.
+    free: &'a FreeSlots,
+}
+
+impl<T> Drop for SecSlot<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.ptr` points to the slot uniquely owned by `self`, which is
+        // valid for writes of `size_of::<T>()` bytes; it isn't used afterwards
+        unsafe { zeroize_mem(self.ptr.as_ptr().cast(), size_of::<T>()) };
+        self.free.release(self.slot);
+    }
+}
+
+impl<T> Deref for SecSlot<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: `self.ptr` points to the slot owned by `self`, which is
+        // initialised
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SecSlot<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: `self.ptr` points to the slot uniquely owned by `self`, which is
+        // initialised
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> fmt::Debug for SecSlot<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the contents are secret
+        f.debug_struct("SecSlot").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn alloc_free_any_order() {
+        let pool: SecSlotPool<32> = SecSlotPool::new(10).expect("pool creation failed");
+        assert_eq!(pool.capacity(), mem::page_size() / 32);
+        let mut slots: Vec<SecSlot<'_, [u8; 32]>> = (0..5).map(|_| pool.alloc()).collect();
+        for (slot, byte) in slots.iter_mut().zip(1..) {
+            assert_eq!(**slot, [0; 32]);
+            slot.fill(byte);
+        }
+        assert_eq!(pool.len(), 5);
+        let ptr = slots[1].as_ptr();
+        drop(slots.remove(1));
+        // the lowest free slot is reused, zeroized
+        let slot = pool.alloc();
+        assert_eq!(slot.as_ptr(), ptr);
+        assert_eq!(*slot, [0; 32]);
+        assert_eq!(*slots[1], [3; 32]);
+        assert_eq!(std::format!("{slot:?}"), "SecSlot { .. }");
+        drop(slot);
+        drop(slots);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn exhaust_pool() {
+        // 4096 byte pages hold more than `MAX_SLOTS` slots of 1 byte
+        let pool: SecSlotPool<1> = SecSlotPool::new(0).expect("pool creation failed");
+        assert_eq!(pool.capacity(), SecSlotPool::<1>::MAX_SLOTS);
+        let slots: Vec<_> = (0..pool.capacity()).map(|_| pool.alloc()).collect();
+        assert!(pool.try_alloc().is_err());
+        let addrs: std::collections::BTreeSet<usize> =
+            slots.iter().map(|slot| slot.as_ptr().addr()).collect();
+        assert_eq!(addrs.len(), pool.capacity());
+        drop(slots);
+        assert!(pool.try_alloc().is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn new_failures_release_pages() {
+        use crate::testing::{self, PageOp};
+
+        for (op, unmapped) in [(PageOp::Map, 0), (PageOp::Lock, 1)] {
+            testing::reset();
+            testing::fail_nth(op, 1, 12);
+            assert!(SecSlotPool::<32>::new(100).is_err());
+            let counts = testing::call_counts();
+            assert_eq!((counts.map, counts.unmap), (1, unmapped), "{op:?}");
+        }
+    }
+
+    #[test]
+    fn odd_slot_size() {
+        let page_size = mem::page_size();
+        let pool: SecSlotPool<100> = SecSlotPool::new(100).expect("pool creation failed");
+        let pages_len = (100 * 100_usize).next_multiple_of(page_size);
+        assert_eq!(pool.capacity(), pages_len / 100);
+        let slots: Vec<_> = (0..pool.capacity()).map(|_| pool.alloc()).collect();
+        let last = slots.last().expect("non-empty pool");
+        assert!(last.as_ptr().addr() + 100 <= pool.pages.as_ptr().addr() + pages_len);
+        assert!(pool.try_alloc().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! Owned, reference counted handles to allocators.
+//!
+//! Allocating with a reference `&A` to an allocator ties every allocation to
+//! the lifetime of that reference. This makes it impossible to return e.g. a
+//! `Box<T, &SecStackSinglePageAlloc>` from a function or to store it in a
+//! long-lived struct without threading lifetimes everywhere.
+//!
+//! The handles in this module own the allocator through a reference count
+//! instead. They are cheap to clone and implement [`Allocator`], so containers
+//! using them are `'static` (if their contents are). Since every container
+//! holds a clone of the handle, the allocator (and hence e.g. its locked memory
+//! page) is only dropped when the last handle *and* the last allocation are
+//! gone. In particular, the leak check of
+//! [`SecStackSinglePageAlloc`](crate::sec_alloc::SecStackSinglePageAlloc) in
+//! debug mode runs when the last handle drops.
+//!
+//! [`RcAlloc`] is the single threaded handle. [`ArcAlloc`] can be shared
+//! between threads; it serialises all allocator calls using a spin lock, so it
+//! can be used with allocators which are not [`Sync`].
+//!
+//! # Examples
+//! ```
+//! # #![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+//! use secmem_alloc::allocator_api::Box;
+//! use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+//! use secmem_alloc::shared_alloc::RcAlloc;
+//!
+//! fn make_key(alloc: &RcAlloc<SecStackSinglePageAlloc>) -> Box<[u8; 32], RcAlloc<SecStackSinglePageAlloc>> {
+//!     Box::new_in([0xAF; 32], alloc.clone())
+//! }
+//!
+//! let alloc = RcAlloc::new(SecStackSinglePageAlloc::new().unwrap());
+//! let key = make_key(&alloc);
+//! // the allocator is kept alive by `key`
+//! drop(alloc);
+//! assert_eq!(*key, [0xAF; 32]);
+//! ```
+
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use allocator_api2::alloc::{AllocError, Allocator};
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Single threaded, reference counted handle owning an allocator of type `A`.
+///
+/// See the [module level documentation](self).
+#[derive(Debug)]
+pub struct RcAlloc<A> {
+    /// The shared allocator.
+    inner: Rc<A>,
+}
+
+impl<A> RcAlloc<A> {
+    /// Create a new handle owning the allocator `alloc`.
+    pub fn new(alloc: A) -> Self {
+        Self {
+            inner: Rc::new(alloc),
+        }
+    }
+
+    /// Returns the number of handles to the allocator, including `self`.
+    ///
+    /// Containers allocated with the handle each own a handle.
+    pub fn handle_count(&self) -> usize {
+        Rc::strong_count(&self.inner)
+    }
+
+    /// Returns the allocator if `self` is the only handle to it, and `self`
+    /// otherwise.
+    ///
+    /// # Errors
+    /// Returns `Err(self)` if there are other handles to the allocator.
+    pub fn try_unwrap(self) -> Result<A, Self> {
+        Rc::try_unwrap(self.inner).map_err(|inner| Self { inner })
+    }
+}
+
+impl<A> Clone for RcAlloc<A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<A> Deref for RcAlloc<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+// SAFETY: all calls are forwarded to the same allocator instance, which lives
+// as long as any handle (clone) lives; so memory allocated through one handle
+// can be deallocated through any clone of it
+unsafe impl<A: Allocator> Allocator for RcAlloc<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate(layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.inner.deallocate(ptr, layout) }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.inner.grow(ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.inner.grow_zeroed(ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.inner.shrink(ptr, old_layout, new_layout) }
+    }
+}
+
+/// Allocator protected by a spin lock.
+struct SpinLocked<A> {
+    /// `true` iff the lock is held.
+    locked: AtomicBool,
+    /// The protected allocator.
+    alloc: UnsafeCell<A>,
+}
+
+// SAFETY: the allocator is only accessed while holding the lock, so it is
+// accessed by one thread at a time; it is moved between threads so must be
+// `Send`
+unsafe impl<A: Send> Sync for SpinLocked<A> {}
+
+impl<A> SpinLocked<A> {
+    /// Run `f` on the allocator while holding the lock.
+    fn with<R>(&self, f: impl FnOnce(&A) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        /// Releases the lock on drop, also when `f` panics.
+        struct Unlock<'a>(&'a AtomicBool);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+        let _unlock = Unlock(&self.locked);
+        // SAFETY: we hold the lock, so no other thread accesses the allocator; `f`
+        // gets only a shared reference which can't escape the closure
+        f(unsafe { &*self.alloc.get() })
+    }
+}
+
+/// Thread safe, reference counted handle owning an allocator of type `A`.
+///
+/// All allocator calls are serialised using a spin lock, so `A` need not be
+/// [`Sync`]. The handle is [`Send`] and [`Sync`] if `A` is [`Send`]. See the
+/// [module level documentation](self).
+pub struct ArcAlloc<A> {
+    /// The shared allocator.
+    inner: Arc<SpinLocked<A>>,
+}
+
+impl<A> ArcAlloc<A> {
+    /// Create a new handle owning the allocator `alloc`.
+    pub fn new(alloc: A) -> Self {
+        Self {
+            inner: Arc::new(SpinLocked {
+                locked: AtomicBool::new(false),
+                alloc: UnsafeCell::new(alloc),
+            }),
+        }
+    }
+
+    /// Returns the number of handles to the allocator, including `self`.
+    ///
+    /// Containers allocated with the handle each own a handle.
+    pub fn handle_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /// Returns the allocator if `self` is the only handle to it, and `self`
+    /// otherwise.
+    ///
+    /// # Errors
+    /// Returns `Err(self)` if there are other handles to the allocator.
+    pub fn try_unwrap(self) -> Result<A, Self> {
+        Arc::try_unwrap(self.inner)
+            .map(|locked| locked.alloc.into_inner())
+            .map_err(|inner| Self { inner })
+    }
+}
+
+impl<A> Clone for ArcAlloc<A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<A> core::fmt::Debug for ArcAlloc<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // the allocator can't be accessed without taking the lock
+        f.debug_struct("ArcAlloc").finish_non_exhaustive()
+    }
+}
+
+// SAFETY: all calls are forwarded to the same allocator instance, which lives
+// as long as any handle (clone) lives; so memory allocated through one handle
+// can be deallocated through any clone of it
+unsafe impl<A: Allocator> Allocator for ArcAlloc<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.with(|alloc| alloc.allocate(layout))
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.with(|alloc| alloc.allocate_zeroed(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // SAFETY: the caller must uphold the safety contract
+        self.inner
+            .with(|alloc| unsafe { alloc.deallocate(ptr, layout) })
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        self.inner
+            .with(|alloc| unsafe { alloc.grow(ptr, old_layout, new_layout) })
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        self.inner
+            .with(|alloc| unsafe { alloc.grow_zeroed(ptr, old_layout, new_layout) })
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // SAFETY: the caller must uphold the safety contract
+        self.inner
+            .with(|alloc| unsafe { alloc.shrink(ptr, old_layout, new_layout) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator_api::{Box, Vec};
+    use crate::containers::SecVec;
+    use crate::sec_alloc::SecStackSinglePageAlloc;
+    use crate::zeroizing_alloc::ZeroizeAlloc;
+
+    fn make_key(
+        alloc: &RcAlloc<SecStackSinglePageAlloc>,
+    ) -> Box<[u8; 32], RcAlloc<SecStackSinglePageAlloc>> {
+        Box::new_in([0xAF; 32], alloc.clone())
+    }
+
+    #[test]
+    fn rc_alloc_outlives_handle() {
+        let alloc =
+            RcAlloc::new(SecStackSinglePageAlloc::new().expect("allocator creation failed"));
+        let key = make_key(&alloc);
+        assert_eq!(alloc.handle_count(), 2);
+        drop(alloc);
+        assert_eq!(*key, [0xAF; 32]);
+        // the allocator (and its leak check) drops with `key`
+    }
+
+    #[test]
+    fn rc_alloc_try_unwrap() {
+        let alloc =
+            RcAlloc::new(SecStackSinglePageAlloc::new().expect("allocator creation failed"));
+        let mut vec = SecVec::new_in(alloc.clone());
+        vec.extend_from_slice(&[1u64; 17]);
+        let Err(alloc) = alloc.try_unwrap() else {
+            panic!("`vec` still holds a handle");
+        };
+        drop(vec);
+        assert!(alloc.try_unwrap().is_ok());
+    }
+
+    #[test]
+    fn arc_alloc_threads() {
+        let alloc = ArcAlloc::new(ZeroizeAlloc::new(std::alloc::System));
+        let threads: std::vec::Vec<_> = (0..4)
+            .map(|i| {
+                let alloc = alloc.clone();
+                std::thread::spawn(move || {
+                    let mut vec = Vec::new_in(alloc);
+                    vec.extend_from_slice(&[i; 100]);
+                    vec
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let vec = thread.join().expect("thread panicked");
+            assert_eq!(vec[..], [i; 100]);
+        }
+    }
+
+    #[test]
+    fn arc_alloc_sec_stack() {
+        let alloc =
+            ArcAlloc::new(SecStackSinglePageAlloc::new().expect("allocator creation failed"));
+        {
+            let mut vec = Vec::new_in(alloc.clone());
+            vec.extend_from_slice(&[0xAF_u8; 100]);
+            let key = Box::new_in(37_u64, alloc.clone());
+            assert_eq!(alloc.handle_count(), 3);
+            assert_eq!(*key, 37);
+        }
+        assert_eq!(alloc.handle_count(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Fault injection into the OS memory page backend, for testing failure
+//! paths. Requires the `testing` feature.
+//!
+//! With the `testing` feature enabled, every call of the OS page backend (used
+//! by e.g. [`OsPageProvider`](crate::page_provider::OsPageProvider) and
+//! [`SecStackSinglePageAlloc::new`](crate::sec_alloc::SecStackSinglePageAlloc::new))
+//! to map, lock, protect or unmap memory pages is counted, and can be
+//! programmed to fail using [`fail_nth`]. This allows to test how code reacts
+//! to e.g. [`PageAllocError::Mmap`](crate::page_provider::PageAllocError) or
+//! `Mlock` without manipulating rlimits.
+//!
+//! Faults and counters are tracked per thread, so tests running in parallel
+//! don't interfere. Note that pages are unmapped on the thread which drops
+//! them.
+//!
+//! ```
+//! use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+//! use secmem_alloc::testing::{self, PageOp};
+//!
+//! // fail the next `mlock` call with `ENOMEM`
+//! testing::fail_nth(PageOp::Lock, 1, 12);
+//! assert!(SecStackSinglePageAlloc::new().is_err());
+//! // the mapped page was unmapped again
+//! let counts = testing::call_counts();
+//! assert_eq!((counts.map, counts.lock, counts.unmap), (1, 1, 1));
+//! ```
+//!
+//! This feature is meant for tests only and should not be enabled in
+//! production builds.
+
+use core::cell::Cell;
+
+/// An operation of the OS page backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PageOp {
+    /// Mapping memory pages (`mmap`, `VirtualAlloc`).
+    Map,
+    /// Locking memory pages (`mlock`, `VirtualLock`).
+    Lock,
+    /// Changing the protection of memory pages (`mprotect`,
+    /// `VirtualProtect`).
+    Protect,
+    /// Unmapping memory pages (`munmap`, `VirtualFree`).
+    Unmap,
+}
+
+impl PageOp {
+    /// Index of the operation in the per thread state.
+    const fn index(self) -> usize {
+        match self {
+            Self::Map => 0,
+            Self::Lock => 1,
+            Self::Protect => 2,
+            Self::Unmap => 3,
+        }
+    }
+}
+
+/// Number of calls of the OS page backend made by the current thread, see
+/// [`call_counts`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallCounts {
+    /// Number of map calls.
+    pub map: usize,
+    /// Number of lock calls.
+    pub lock: usize,
+    /// Number of protect calls.
+    pub protect: usize,
+    /// Number of unmap calls.
+    pub unmap: usize,
+}
+
+std::thread_local! {
+    /// Number of calls per operation, indexed by `PageOp::index`.
+    static COUNTS: [Cell<usize>; 4] = const { [const { Cell::new(0) }; 4] };
+    /// Pending fault per operation: the call count at which it fails, and the
+    /// error code.
+    static FAULTS: [Cell<Option<(usize, i32)>>; 4] = const { [const { Cell::new(None) }; 4] };
+}
+
+/// Make the `n`th next call of `op` on the current thread fail with the raw
+/// OS error code `errno` (e.g. `ENOMEM`, which is 12 on Linux).
+///
+/// `n` starts at 1 for the next call. This replaces a pending fault for `op`.
+/// A failing unmap call panics, just like a failing `munmap` in the real
+/// backend (but the pages are released anyway, so tests don't leak).
+/// Platforms whose errors carry no error code ignore `errno`.
+///
+/// # Panics
+/// Panics if `n` is zero.
+pub fn fail_nth(op: PageOp, n: usize, errno: i32) {
+    assert!(n > 0, "the first call is `n = 1`");
+    let at = COUNTS.with(|counts| counts[op.index()].get()) + n;
+    FAULTS.with(|faults| faults[op.index()].set(Some((at, errno))));
+}
+
+/// Remove all pending faults of the current thread.
+pub fn clear_faults() {
+    FAULTS.with(|faults| faults.iter().for_each(|fault| fault.set(None)));
+}
+
+/// Returns the number of calls of the OS page backend made by the current
+/// thread since it started, or since the last call to [`reset`].
+pub fn call_counts() -> CallCounts {
+    COUNTS.with(|counts| CallCounts {
+        map: counts[PageOp::Map.index()].get(),
+        lock: counts[PageOp::Lock.index()].get(),
+        protect: counts[PageOp::Protect.index()].get(),
+        unmap: counts[PageOp::Unmap.index()].get(),
+    })
+}
+
+/// Reset the call counters and remove all pending faults of the current
+/// thread.
+pub fn reset() {
+    clear_faults();
+    COUNTS.with(|counts| counts.iter().for_each(|count| count.set(0)));
+}
+
+/// Set the budget of lockable memory in bytes of the miri page shim (which
+/// models `RLIMIT_MEMLOCK`), or `None` to restore the default of 8 MiB.
+///
+/// Locking pages fails if the total number of locked bytes would exceed the
+/// budget. The budget is process wide. This has no effect when not running
+/// under miri.
+pub fn set_miri_lock_budget(bytes: Option<usize>) {
+    crate::internals::mem::set_lock_budget(bytes);
+}
+
+/// Count a call of `op`, and return the error code to fail it with if a fault
+/// is due.
+pub(crate) fn on_call(op: PageOp) -> Option<i32> {
+    let count = COUNTS.with(|counts| {
+        let count = &counts[op.index()];
+        count.set(count.get() + 1);
+        count.get()
+    });
+    FAULTS.with(|faults| {
+        let fault = &faults[op.index()];
+        match fault.get() {
+            Some((at, errno)) if at == count => {
+                fault.set(None);
+                Some(errno)
+            },
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_faults() {
+        reset();
+        assert_eq!(on_call(PageOp::Map), None);
+        fail_nth(PageOp::Map, 2, 12);
+        assert_eq!(on_call(PageOp::Map), None);
+        assert_eq!(on_call(PageOp::Map), Some(12));
+        assert_eq!(on_call(PageOp::Map), None);
+        fail_nth(PageOp::Unmap, 1, 22);
+        clear_faults();
+        assert_eq!(on_call(PageOp::Unmap), None);
+        assert_eq!(
+            call_counts(),
+            CallCounts {
+                map: 4,
+                lock: 0,
+                protect: 0,
+                unmap: 1,
+            }
+        );
+        reset();
+        assert_eq!(call_counts(), CallCounts::default());
+    }
+}
@@ -51,7 +51,7 @@ pub(crate) unsafe fn align_up_ptr_mut(ptr: *mut u8, align: usize) -> *mut u8 {
 /// Might panic if `align` is not a power of two.
 pub(crate) fn is_aligned_ptr(ptr: *const u8, align: usize) -> bool {
     debug_checked_precondition!(align.is_power_of_two());
-    ptr.addr() % align == 0
+    ptr.addr().is_multiple_of(align)
 }
 
 /// Returns the offset in bytes of `ptr` relative to `base`. Must not wrap.
@@ -0,0 +1,142 @@
+//! aarch64 specific zeroizers.
+
+/// Returns the size in bytes of the blocks zeroed by `dc zva`, or `None` if
+/// `dc zva` is prohibited.
+///
+/// This reads the `DCZID_EL0` register, which is accessible from user space.
+#[cfg(not(miri))]
+pub fn zva_block_size() -> Option<usize> {
+    let dczid: u64;
+    // SAFETY: `DCZID_EL0` is readable at all exception levels
+    unsafe {
+        core::arch::asm!(
+            "mrs {0}, dczid_el0",
+            out(reg) dczid,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    // bit 4 (DZP) set means `dc zva` is prohibited; bits 0..4 contain the log2 of
+    // the block size in (4 byte) words
+    if dczid & 0b1_0000 != 0 {
+        None
+    } else {
+        Some(4 << (dczid & 0b1111))
+    }
+}
+
+/// Zeroizer using the `dc zva` (data cache zero by virtual address)
+/// instruction.
+#[cfg(not(miri))]
+pub mod dc_zva {
+    use super::super::asm_barier::barier;
+    use super::super::portable_zeroize_mem;
+    use super::zva_block_size;
+    use crate::macros::precondition_memory_range;
+    use crate::util::align_up_usize;
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes.
+    ///
+    /// This is guarantied to be not elided by the compiler.
+    ///
+    /// # Safety
+    /// `dc zva` must not be prohibited, see [`zva_block_size`]. The caller
+    /// *must* ensure that `ptr` is valid for writes of `len` bytes, see the
+    /// [`std::ptr`] documentation. In particular this function is not atomic.
+    pub unsafe fn zeroize_mem(ptr: *mut u8, len: usize) {
+        precondition_memory_range!(ptr, len);
+        let Some(block) = zva_block_size() else {
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { portable_zeroize_mem(ptr, len) };
+            return;
+        };
+
+        // if aligning wraps the address space the head length is huge and the region
+        // contains no whole block
+        let head_len = align_up_usize(ptr.addr(), block).wrapping_sub(ptr.addr());
+        let body_len = len.saturating_sub(head_len) & !(block - 1);
+        if body_len == 0 {
+            // too small for a single aligned block
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { portable_zeroize_mem(ptr, len) };
+            return;
+        }
+        // SAFETY: `head_len + body_len <= len` so these point into (or one byte past)
+        // the memory region
+        let body_ptr: *mut u8 = unsafe { ptr.add(head_len) };
+        let tail_ptr: *mut u8 = unsafe { body_ptr.add(body_len) };
+
+        // SAFETY: the head lies in the memory region, which is valid for writes
+        unsafe { ptr.write_bytes(0, head_len) };
+        let mut block_ptr = body_ptr;
+        for _i in 0..body_len / block {
+            // SAFETY: `block_ptr` is `block` byte aligned and the block lies in the body
+            // of the memory region, which is valid for writes
+            // the asm block is opaque to the compiler, so the writes can not be elided
+            unsafe {
+                core::arch::asm!(
+                    "dc zva, {0}",
+                    in(reg) block_ptr,
+                    options(nostack, preserves_flags),
+                );
+                block_ptr = block_ptr.add(block);
+            }
+        }
+        // SAFETY: the tail lies in the memory region, which is valid for writes
+        unsafe { tail_ptr.write_bytes(0, len - head_len - body_len) };
+        // Optimisation barier, so the writes can not be optimised out
+        barier(ptr);
+    }
+}
+
+/// Returns the size in bytes of the smallest data cache line.
+///
+/// This reads the `CTR_EL0` register, which is accessible from user space on
+/// Linux and macOS.
+#[cfg(not(miri))]
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    // SAFETY: `CTR_EL0` is readable from user space
+    unsafe {
+        core::arch::asm!(
+            "mrs {0}, ctr_el0",
+            out(reg) ctr,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    // bits 16..20 (DminLine) contain the log2 of the line size in (4 byte) words
+    4 << ((ctr >> 16) & 0b1111)
+}
+
+/// Clean and invalidate the cache lines containing the memory region
+/// `ptr .. ptr + len` from all data caches, writing them back to main memory.
+///
+/// Uses `dc civac` for every cache line followed by a `dsb sy` barrier.
+///
+/// # Safety
+/// The memory region must be mapped (valid for reads).
+#[cfg(not(miri))]
+pub unsafe fn flush_cache(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let line_size = dcache_line_size();
+    // start at the cache line containing `ptr`
+    let mut line = ptr.map_addr(|addr| addr & !(line_size - 1));
+    // `ptr + len` doesn't wrap the address space, so neither does the subtraction
+    let last = ptr.wrapping_add(len - 1);
+    while line <= last {
+        // SAFETY: `line` points into a cache line overlapping the memory region,
+        // which is mapped by the safety contract
+        unsafe {
+            core::arch::asm!(
+                "dc civac, {0}",
+                in(reg) line,
+                options(nostack, preserves_flags),
+            );
+        }
+        line = line.wrapping_add(line_size);
+    }
+    // wait for the cache maintenance to complete
+    // SAFETY: a barrier has no safety requirements
+    unsafe { core::arch::asm!("dsb sy", options(nostack, preserves_flags)) };
+}
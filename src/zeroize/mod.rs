@@ -7,19 +7,480 @@
 //! deallocation, because the memory is unused and the memory needs not contain
 //! a value of a certain type than.
 //!
+//! The [`Zeroable`] trait and the functions [`zeroize_val`], [`zeroize_slice`]
+//! and [`zeroize_uninit`] provide a safe, typed layer on top of
+//! [`zeroize_mem`] for types which have all zeros as a valid value.
+//!
 //! For good general purpose memory wiping use the [`zeroize`](https://crates.io/crates/zeroize)
 //! crate.
 
+use crate::internals::mem;
 use crate::macros::precondition_memory_range;
+use crate::page_provider::PageProvider;
+use crate::util::align_up_usize;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 cfg_if::cfg_if! {
     if #[cfg(miri)] {
         // when running miri we chose a pure rust zeroizer
-        pub use fallback::zeroize_mem;
+        use fallback::zeroize_mem as portable_zeroize_mem;
     } else if #[cfg(feature = "nightly_core_intrinsics")] {
-        pub use nightly::zeroize_mem;
+        use nightly::zeroize_mem as portable_zeroize_mem;
     } else {
-        pub use asm_barier::zeroize_mem;
+        use asm_barier::zeroize_mem as portable_zeroize_mem;
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+mod zeroable;
+
+pub use zeroable::{Zeroable, zeroize_slice, zeroize_uninit, zeroize_val};
+
+/// Zeroize the memory pointed to by `ptr` and of size `len` bytes.
+///
+/// This is guarantied to be not elided by the compiler. The zeroizer used is
+/// the one returned by [`Zeroizer::detect`], i.e. the fastest zeroizer
+/// available on the running CPU.
+///
+/// # Safety
+/// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+/// see the [`std::ptr`] documentation. In particular this function is
+/// not atomic.
+pub unsafe fn zeroize_mem(ptr: *mut u8, len: usize) {
+    precondition_memory_range!(ptr, len);
+    let zeroizer = Zeroizer::detect();
+    // SAFETY: `zeroizer` is available on the running CPU by `Zeroizer::detect`
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { zeroizer.zeroize_mem_unchecked(ptr, len) }
+}
+
+/// A memory zeroization technique.
+///
+/// Some zeroizers are specific to an architecture or require certain CPU
+/// features. Use [`Zeroizer::is_available`] to check whether a zeroizer can be
+/// used on the running CPU. [`zeroize_mem`] uses the zeroizer returned by
+/// [`Zeroizer::detect`], which is selected at runtime using CPU feature
+/// detection when the `std` feature is enabled, and based on the compile time
+/// target features otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum Zeroizer {
+    /// Portable zeroizer, available on all platforms.
+    ///
+    /// This uses the volatile memset intrinsic with the
+    /// `nightly_core_intrinsics` feature, and otherwise a memset followed by
+    /// an optimisation barrier.
+    Portable = 1,
+    /// x86_64 zeroizer using `rep stosb`. Selected by [`Zeroizer::detect`]
+    /// only when the CPU supports enhanced `rep movsb/stosb` (ERMS).
+    RepStosb = 2,
+    /// x86_64 zeroizer using AVX2 non-temporal stores, which bypass the CPU
+    /// caches. Memory regions smaller than a page are zeroized using a cached
+    /// zeroizer instead.
+    Avx2 = 3,
+    /// x86_64 zeroizer using AVX-512 non-temporal stores, which bypass the
+    /// CPU caches. Memory regions smaller than a page are zeroized using a
+    /// cached zeroizer instead.
+    Avx512 = 4,
+    /// aarch64 zeroizer using the `dc zva` instruction to zero whole cache
+    /// blocks at once.
+    DcZva = 5,
+}
+
+/// Zeroizer selected by [`Zeroizer::detect`], or [`UNDETECTED`] when no
+/// detection has been performed yet.
+static DETECTED: AtomicU8 = AtomicU8::new(UNDETECTED);
+/// Fastest zeroizer not using non-temporal stores, or [`UNDETECTED`] when no
+/// detection has been performed yet.
+static DETECTED_CACHED: AtomicU8 = AtomicU8::new(UNDETECTED);
+/// Value of [`DETECTED`] and [`DETECTED_CACHED`] before detection.
+const UNDETECTED: u8 = 0;
+
+/// Minimal size in bytes of a memory region for which the non-temporal
+/// zeroizers are used.
+///
+/// Non-temporal stores bypass the CPU caches and need a store fence
+/// afterwards, which only pays off for large memory regions. Small regions are
+/// likely cached anyway, so writing zeros to the cache is much faster.
+const NON_TEMPORAL_THRESHOLD: usize = 4096;
+
+impl Zeroizer {
+    /// All zeroizers, ordered from least to most preferred.
+    pub const ALL: [Self; 5] = [
+        Self::Portable,
+        Self::RepStosb,
+        Self::DcZva,
+        Self::Avx2,
+        Self::Avx512,
+    ];
+
+    /// Returns `true` iff the zeroizer can be used on the running CPU.
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::Portable => true,
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Self::RepStosb => true,
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Self::Avx2 => x86_64::has_avx2(),
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            Self::Avx512 => x86_64::has_avx512f(),
+            #[cfg(all(target_arch = "aarch64", not(miri)))]
+            Self::DcZva => aarch64::zva_block_size().is_some(),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Iterator over all zeroizers available on the running CPU.
+    pub fn available() -> impl Iterator<Item = Self> {
+        Self::ALL.into_iter().filter(|z| z.is_available())
+    }
+
+    /// Returns the fastest zeroizer available on the running CPU.
+    ///
+    /// The result of the detection is cached, so this is cheap to call.
+    pub fn detect() -> Self {
+        Self::detect_cached_in(&DETECTED, Self::available)
+    }
+
+    /// Returns the fastest zeroizer available on the running CPU which does not
+    /// use non-temporal stores.
+    fn detect_cached() -> Self {
+        Self::detect_cached_in(&DETECTED_CACHED, || {
+            Self::available().filter(|z| !z.is_non_temporal())
+        })
+    }
+
+    /// Returns the zeroizer stored in `cache`, or select the fastest of
+    /// `candidates` and store it in `cache` when no detection has been performed
+    /// yet.
+    fn detect_cached_in<I: Iterator<Item = Self>>(
+        cache: &AtomicU8,
+        candidates: impl FnOnce() -> I,
+    ) -> Self {
+        match Self::from_u8(cache.load(Ordering::Relaxed)) {
+            Some(zeroizer) => zeroizer,
+            None => {
+                let zeroizer = Self::fastest(candidates());
+                cache.store(zeroizer as u8, Ordering::Relaxed);
+                zeroizer
+            },
+        }
+    }
+
+    /// Returns the fastest zeroizer of `candidates`, which are ordered from
+    /// least to most preferred.
+    fn fastest(candidates: impl Iterator<Item = Self>) -> Self {
+        let preferred = candidates
+            .last()
+            .expect("portable zeroizer is always available");
+        // `rep stosb` is only fast on CPUs with enhanced `rep movsb/stosb`
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        if preferred == Self::RepStosb && !x86_64::has_ermsb() {
+            return Self::Portable;
+        }
+        preferred
+    }
+
+    fn from_u8(val: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|&z| z as u8 == val)
+    }
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes using
+    /// this zeroizer.
+    ///
+    /// This is guarantied to be not elided by the compiler.
+    ///
+    /// # Panics
+    /// Panics if the zeroizer is not available on the running CPU, see
+    /// [`Zeroizer::is_available`].
+    ///
+    /// # Safety
+    /// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+    /// see the [`std::ptr`] documentation. In particular this function is
+    /// not atomic.
+    pub unsafe fn zeroize_mem(self, ptr: *mut u8, len: usize) {
+        assert!(
+            self.is_available(),
+            "zeroizer {self:?} not available on this CPU"
+        );
+        // SAFETY: we just checked that `self` is available
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { self.zeroize_mem_unchecked(ptr, len) }
+    }
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes using
+    /// this zeroizer, without checking its availability.
+    ///
+    /// # Safety
+    /// `self` must be available on the running CPU and `ptr` must be valid for
+    /// writes of `len` bytes.
+    unsafe fn zeroize_mem_unchecked(self, ptr: *mut u8, len: usize) {
+        let zeroizer = if self.is_non_temporal() && len < NON_TEMPORAL_THRESHOLD {
+            Self::detect_cached()
+        } else {
+            self
+        };
+        // SAFETY: `zeroizer` is either `self` or returned by `detect_cached`, so it's
+        // available on the running CPU
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { (zeroizer.zeroize_fn())(ptr, len) }
+    }
+
+    /// Returns `true` iff the zeroizer uses non-temporal stores.
+    fn is_non_temporal(self) -> bool {
+        matches!(self, Self::Avx2 | Self::Avx512)
+    }
+
+    /// The zeroization function of this zeroizer. Calling it is only sound
+    /// when the zeroizer is available on the running CPU.
+    fn zeroize_fn(self) -> unsafe fn(*mut u8, usize) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Self::RepStosb => x86_64::rep_stosb::zeroize_mem,
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => x86_64::avx2::zeroize_mem,
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx512 => x86_64::avx512::zeroize_mem,
+            #[cfg(all(target_arch = "aarch64", not(miri)))]
+            Self::DcZva => aarch64::dc_zva::zeroize_mem,
+            _ => portable_zeroize_mem,
+        }
+    }
+}
+
+/// Zeroize the memory pointed to by `ptr` and of size `len` bytes, and flush
+/// the zeroized memory from the CPU caches to main memory.
+///
+/// [`zeroize_mem`] guaranties that the zeroization is not elided, but the
+/// zeros might stay in the CPU caches for a while, while the old contents
+/// remain in main memory (DRAM). This function writes the zeroized cache lines
+/// back to main memory, which matters for cold boot attacks.
+///
+/// On x86_64 this uses `clflushopt` (or `clflush` when not available) followed
+/// by a memory fence, and on aarch64 `dc civac` followed by a `dsb` barrier. On
+/// other architectures (and under miri) there is no portable way to flush CPU
+/// caches from user space, so this is identical to [`zeroize_mem`].
+///
+/// # Safety
+/// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+/// see the [`std::ptr`] documentation. In particular this function is
+/// not atomic.
+pub unsafe fn zeroize_mem_flush(ptr: *mut u8, len: usize) {
+    precondition_memory_range!(ptr, len);
+    // SAFETY: the caller must uphold the safety contract
+    unsafe { zeroize_mem(ptr, len) };
+    // SAFETY: `ptr` is valid for writes of `len` bytes, so the memory is mapped
+    unsafe { flush_cache(ptr, len) };
+}
+
+/// Flush the memory region `ptr .. ptr + len` from the CPU caches to main
+/// memory.
+///
+/// # Safety
+/// The memory region must be mapped (valid for reads).
+unsafe fn flush_cache(ptr: *const u8, len: usize) {
+    cfg_if::cfg_if! {
+        if #[cfg(all(target_arch = "x86_64", not(miri)))] {
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { x86_64::flush_cache(ptr, len) }
+        } else if #[cfg(all(target_arch = "aarch64", not(miri)))] {
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { aarch64::flush_cache(ptr, len) }
+        } else {
+            // flushing caches requires architecture specific instructions, which we do
+            // not implement for other architectures (and which miri can't execute)
+            let _ = (ptr, len);
+        }
+    }
+}
+
+/// How the allocators wipe deallocated memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct WipeOptions {
+    /// Discard whole memory pages of large memory regions instead of writing
+    /// zeros to them, see [`zeroize_mem_discard`].
+    pub(crate) discard_pages: bool,
+    /// Flush the zeroized memory from the CPU caches, see
+    /// [`zeroize_mem_flush`].
+    ///
+    /// This takes precedence over `discard_pages`: discarded pages are released
+    /// to the kernel with their old contents still in main memory, so
+    /// discarding is not used when flushing caches is requested.
+    pub(crate) flush_cache: bool,
+}
+
+impl WipeOptions {
+    /// Securely wipe the memory pointed to by `ptr` and of size `len` bytes.
+    ///
+    /// `locked` must be `true` iff the memory region is locked (`mlock`ed).
+    ///
+    /// Pages are discarded using `provider`.
+    ///
+    /// # Safety
+    /// The caller *must* ensure that `ptr` is valid for writes of `len` bytes.
+    /// If `self.discard_pages` is set, the memory region must be allocated by
+    /// `provider`, see [`PageProvider::discard`].
+    pub(crate) unsafe fn wipe<P: PageProvider>(
+        self,
+        provider: &P,
+        ptr: *mut u8,
+        len: usize,
+        locked: bool,
+    ) {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe {
+            if self.flush_cache {
+                zeroize_mem_flush(ptr, len);
+            } else if self.discard_pages {
+                zeroize_mem_discard(provider, ptr, len, locked);
+            } else {
+                zeroize_mem(ptr, len);
+            }
+        }
+    }
+
+    /// Securely wipe the unlocked memory pointed to by `ptr` and of size `len`
+    /// bytes, like [`Self::wipe`], but discard pages using
+    /// [`zeroize_mem_anonymous_discard`] instead of a page provider.
+    ///
+    /// # Safety
+    /// The caller *must* ensure that `ptr` is valid for writes of `len` bytes.
+    /// If `self.discard_pages` is set, the memory region must be unlocked and
+    /// lie in a *private anonymous* memory mapping.
+    pub(crate) unsafe fn wipe_anonymous(self, ptr: *mut u8, len: usize) {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe {
+            if self.flush_cache {
+                zeroize_mem_flush(ptr, len);
+            } else if self.discard_pages {
+                zeroize_mem_anonymous_discard(ptr, len);
+            } else {
+                zeroize_mem(ptr, len);
+            }
+        }
+    }
+}
+
+/// Minimal size in bytes of a memory region for which
+/// [`zeroize_mem_discard`] tries to discard memory pages rather than to write
+/// zeros to them.
+///
+/// Discarding pages requires a syscall and causes a page fault (and zeroing by
+/// the kernel) when the memory is accessed again, so it only pays off for large
+/// regions. This is the default mmap threshold of glibc's `malloc`, above which
+/// allocations are returned to the OS on deallocation anyway.
+const DISCARD_THRESHOLD: usize = 128 * 1024;
+
+/// Zeroize the memory pointed to by `ptr` and of size `len` bytes, discarding
+/// whole memory pages where possible.
+///
+/// For large memory regions, the unaligned head and tail of the region are
+/// zeroized using [`zeroize_mem`], while the memory pages in between are
+/// discarded using [`PageProvider::discard`]. The provider guaranties that
+/// discarded pages are zero-filled on the next access. When discarding is not
+/// supported or fails, the whole region is zeroized using [`zeroize_mem`].
+///
+/// `locked` must be `true` iff the memory region is locked (`mlock`ed). The
+/// memory region then stays locked.
+///
+/// # Safety
+/// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+/// see the [`std::ptr`] documentation. In addition, the memory region must be
+/// allocated by `provider`, see [`PageProvider::discard`].
+pub(crate) unsafe fn zeroize_mem_discard<P: PageProvider>(
+    provider: &P,
+    ptr: *mut u8,
+    len: usize,
+    locked: bool,
+) {
+    // SAFETY: the caller must uphold the safety contract; the pages passed to
+    // `discard` lie in the memory region, which is allocated by `provider`
+    unsafe {
+        zeroize_mem_discard_with(ptr, len, provider.page_size(), |pages_ptr, pages_len| {
+            provider.discard(pages_ptr, pages_len, locked)
+        });
+    }
+}
+
+/// Zeroize the unlocked memory pointed to by `ptr` and of size `len` bytes,
+/// discarding whole memory pages where possible, like
+/// [`zeroize_mem_discard`]. Pages are discarded using
+/// `madvise(MADV_DONTNEED)` directly, so this works for memory of any
+/// allocator which hands out private anonymous memory mappings.
+///
+/// # Safety
+/// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+/// see the [`std::ptr`] documentation. In addition, the memory region must be
+/// unlocked and lie in a *private anonymous* memory mapping; only for those
+/// the kernel guaranties that discarded pages read as zeros.
+pub(crate) unsafe fn zeroize_mem_anonymous_discard(ptr: *mut u8, len: usize) {
+    // SAFETY: the caller must uphold the safety contract; the pages passed to
+    // `discard` are page aligned and lie in the memory region, which is an unlocked
+    // private anonymous mapping
+    unsafe {
+        zeroize_mem_discard_with(ptr, len, mem::page_size(), |pages_ptr, pages_len| {
+            mem::discard_pages(pages_ptr.as_ptr(), pages_len, false)
+        });
+    }
+}
+
+/// Zeroize the memory pointed to by `ptr` and of size `len` bytes, discarding
+/// the whole pages of `page_size` bytes in it using `discard` if the region is
+/// large enough. `discard` is called at most once, with a page aligned range
+/// in the memory region, and returns `true` iff the pages were discarded.
+///
+/// # Safety
+/// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+/// that `page_size` is a power of two, and that `discard` only returns `true`
+/// if the pages now read as zeros.
+unsafe fn zeroize_mem_discard_with(
+    ptr: *mut u8,
+    len: usize,
+    page_size: usize,
+    discard: impl FnOnce(NonNull<u8>, usize) -> bool,
+) {
+    precondition_memory_range!(ptr, len);
+    if len < DISCARD_THRESHOLD {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { zeroize_mem(ptr, len) };
+        return;
+    }
+
+    // number of bytes before the first page boundary in the region; if aligning
+    // wraps the address space this is huge, and the region contains no whole page
+    let head_len = align_up_usize(ptr.addr(), page_size).wrapping_sub(ptr.addr());
+    // number of bytes in whole pages; zero if the region contains no whole page
+    let pages_len = len.saturating_sub(head_len) & !(page_size - 1);
+    if pages_len == 0 {
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { zeroize_mem(ptr, len) };
+        return;
+    }
+    let tail_len = len - head_len - pages_len;
+
+    // SAFETY: `head_len + pages_len <= len` so the results point into (or one
+    // byte past) the memory region
+    let pages_ptr: *mut u8 = unsafe { ptr.add(head_len) };
+    let tail_ptr: *mut u8 = unsafe { pages_ptr.add(pages_len) };
+    // SAFETY: `ptr .. pages_ptr` and `tail_ptr .. tail_ptr + tail_len` lie in the
+    // memory region, which the caller guaranties is valid for writes
+    unsafe {
+        zeroize_mem(ptr, head_len);
+        zeroize_mem(tail_ptr, tail_len);
+    }
+    // SAFETY: `pages_ptr` lies in the memory region, so it is non-null
+    let pages = unsafe { NonNull::new_unchecked(pages_ptr) };
+    // `pages_ptr` is page aligned, `pages_len` a multiple of the page size and the
+    // range lies in the memory region
+    if !discard(pages, pages_len) {
+        // SAFETY: `pages_ptr .. pages_ptr + pages_len` lies in the memory region
+        unsafe { zeroize_mem(pages_ptr, pages_len) };
     }
 }
 
@@ -71,6 +532,14 @@ mod asm_barier {
         // SAFETY: the caller must uphold the safety contract of `write_bytes`
         unsafe { ptr.write_bytes(0, len) };
         // Optimisation barier, so the writes can not be optimised out
+        barier(ptr);
+    }
+
+    /// Optimisation barier: an empty asm block which the compiler must assume
+    /// reads the memory pointed to by `ptr`, so writes to it can not be
+    /// optimised out.
+    pub fn barier(ptr: *mut u8) {
+        // SAFETY: the asm block is empty
         unsafe {
             core::arch::asm!(
                 "/* {0} */",
@@ -87,6 +556,7 @@ mod asm_barier {
 /// This zeroizer uses a volatile write per byte. This zeroization technique
 /// is pure Rust and available for all target platforms on stable, but very
 /// slow.
+#[cfg(any(miri, test))]
 mod fallback {
     use super::*;
 
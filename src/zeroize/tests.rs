@@ -1,4 +1,6 @@
 use super::*;
+use crate::internals::mem;
+use crate::page_provider::OsPageProvider;
 
 fn test_b127_zeroizer(z: unsafe fn(*mut u8, usize)) {
     let mut array: [u8; 127] = [0xAF; 127];
@@ -27,6 +29,56 @@ fn test_b239_lowalign_zeroizer(z: unsafe fn(*mut u8, usize)) {
     assert_eq!(&array[..], &expected[..]);
 }
 
+fn test_b4099_lowalign_zeroizer(z: unsafe fn(*mut u8, usize)) {
+    // ensure we get 64 byte aligned memory
+    #[repr(align(64))]
+    struct Aligned([u8; 4160]);
+    let mut array = Aligned([0xAF; 4160]);
+
+    // zeroize 4099 bytes starting from an odd offset, so the memory region has an
+    // unaligned head, a body of many aligned blocks and an unaligned tail
+    let ptr: *mut u8 = unsafe { array.0.as_mut_ptr().add(13) };
+    unsafe { z(ptr, 4099) };
+
+    assert!(array.0[..13].iter().all(|&b| b == 0xAF));
+    assert!(array.0[13..13 + 4099].iter().all(|&b| b == 0));
+    assert!(array.0[13 + 4099..].iter().all(|&b| b == 0xAF));
+}
+
+#[test]
+fn test_b127_available_zeroizers() {
+    for zeroizer in Zeroizer::available() {
+        test_b127_zeroizer(zeroizer.zeroize_fn());
+    }
+}
+
+#[test]
+fn test_b239_lowalign_available_zeroizers() {
+    for zeroizer in Zeroizer::available() {
+        test_b239_lowalign_zeroizer(zeroizer.zeroize_fn());
+    }
+}
+
+#[test]
+fn test_b4099_lowalign_available_zeroizers() {
+    for zeroizer in Zeroizer::available() {
+        test_b4099_lowalign_zeroizer(zeroizer.zeroize_fn());
+    }
+}
+
+#[test]
+fn test_small_regions_zeroized_cached() {
+    let zeroizer = Zeroizer::detect_cached();
+    assert!(zeroizer.is_available());
+    assert!(!zeroizer.is_non_temporal());
+}
+
+#[test]
+fn test_b4099_lowalign_detected_zeroizer() {
+    assert!(Zeroizer::detect().is_available());
+    test_b4099_lowalign_zeroizer(zeroize_mem);
+}
+
 #[cfg(feature = "nightly_core_intrinsics")]
 #[test]
 fn test_b127_nightly_zeroizer() {
@@ -60,3 +112,69 @@ fn test_b239_lowalign_asm_barier_zeroizer() {
 fn test_b239_lowalign_fallback_zeroizer() {
     test_b239_lowalign_zeroizer(fallback::zeroize_mem);
 }
+
+fn test_discard_zeroizer(len: usize, offset: usize, z: unsafe fn(*mut u8, usize)) {
+    let page_size = OsPageProvider.page_size();
+    let alloc_len = align_up_usize(len + offset, page_size);
+    let pages = OsPageProvider
+        .allocate(alloc_len)
+        .expect("page allocation failed");
+    let mem = unsafe { core::slice::from_raw_parts_mut(pages.as_ptr(), alloc_len) };
+    mem.fill(0xAF);
+    unsafe { z(mem[offset..].as_mut_ptr(), len) };
+
+    assert!(mem[..offset].iter().all(|&b| b == 0xAF));
+    assert!(mem[offset..offset + len].iter().all(|&b| b == 0));
+    assert!(mem[offset + len..].iter().all(|&b| b == 0xAF));
+    unsafe { OsPageProvider.release(pages, alloc_len) };
+}
+
+unsafe fn zeroize_mem_os_discard(ptr: *mut u8, len: usize) {
+    unsafe { zeroize_mem_discard(&OsPageProvider, ptr, len, false) };
+}
+
+#[test]
+fn test_small_discard_zeroizer() {
+    test_discard_zeroizer(4000, 3, zeroize_mem_os_discard);
+}
+
+#[test]
+fn test_large_discard_zeroizer() {
+    test_discard_zeroizer(1 << 20, 0, zeroize_mem_os_discard);
+}
+
+#[test]
+fn test_large_lowalign_discard_zeroizer() {
+    test_discard_zeroizer((1 << 20) + 37, 1, zeroize_mem_os_discard);
+}
+
+#[test]
+fn test_large_lowalign_anonymous_discard_zeroizer() {
+    test_discard_zeroizer((1 << 20) + 37, 1, zeroize_mem_anonymous_discard);
+}
+
+#[test]
+fn test_locked_page_discard() {
+    let page = mem::Page::alloc_new_lock_in(OsPageProvider).expect("page allocation failed");
+    let ptr = page.as_ptr_mut();
+    unsafe { ptr.write_bytes(0xAF, page.page_size()) };
+    // if discarding is not supported, the page is left untouched
+    if unsafe { mem::discard_pages(ptr, page.page_size(), true) } {
+        for offset in 0..page.page_size() {
+            assert_eq!(unsafe { ptr.add(offset).read() }, 0);
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(miri)))]
+#[test]
+fn test_flush_info_cached() {
+    let (line_size, clflushopt) = x86_64::flush_info();
+    assert!(line_size.is_power_of_two() && line_size >= 8);
+    assert_eq!(x86_64::flush_info(), (line_size, clflushopt));
+}
+
+#[test]
+fn test_b4099_lowalign_flush_zeroizer() {
+    test_b4099_lowalign_zeroizer(zeroize_mem_flush);
+}
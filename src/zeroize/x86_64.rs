@@ -0,0 +1,331 @@
+//! x86_64 specific zeroizers.
+//!
+//! The AVX zeroizers use non-temporal stores, which bypass the CPU caches and
+//! write directly to memory. This avoids polluting the caches with the zeroed
+//! memory, and makes sure the zeros actually reach main memory rather than
+//! only the caches.
+
+use super::asm_barier::barier;
+use super::portable_zeroize_mem;
+use crate::macros::precondition_memory_range;
+use crate::util::align_up_usize;
+#[cfg(not(miri))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Returns `true` iff the running CPU supports AVX2.
+///
+/// Without the `std` feature we cannot detect CPU features at runtime, so we
+/// rely on the compile time target features.
+#[cfg(not(miri))]
+pub fn has_avx2() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "std")] {
+            std::is_x86_feature_detected!("avx2")
+        } else {
+            cfg!(target_feature = "avx2")
+        }
+    }
+}
+
+/// Returns `true` iff the running CPU supports AVX-512F.
+///
+/// Without the `std` feature we cannot detect CPU features at runtime, so we
+/// rely on the compile time target features.
+#[cfg(not(miri))]
+pub fn has_avx512f() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "std")] {
+            std::is_x86_feature_detected!("avx512f")
+        } else {
+            cfg!(target_feature = "avx512f")
+        }
+    }
+}
+
+/// Returns `true` iff the running CPU supports enhanced `rep movsb/stosb`
+/// (ERMS), i.e. `rep stosb` is fast.
+#[cfg(not(miri))]
+pub fn has_ermsb() -> bool {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    // `cpuid` is always available on x86_64; ERMS is reported in leaf 7
+    #[allow(unused_unsafe)]
+    // SAFETY: `cpuid` is available on all x86_64 CPUs
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return false;
+    }
+    #[allow(unused_unsafe)]
+    // SAFETY: `cpuid` is available on all x86_64 CPUs, and leaf 7 is supported
+    let ebx = unsafe { __cpuid_count(7, 0) }.ebx;
+    ebx & (1 << 9) != 0
+}
+
+/// Split the memory region `ptr .. ptr + len` into an unaligned head, a body
+/// of whole `BLOCK` byte aligned blocks and an unaligned tail. Returns the
+/// lengths of the head and body in bytes, or `None` if the region is too small
+/// to contain a block.
+fn split_blocks<const BLOCK: usize>(ptr: *mut u8, len: usize) -> Option<(usize, usize)> {
+    // if aligning wraps the address space the head length is huge and `None` is
+    // returned
+    let head_len = align_up_usize(ptr.addr(), BLOCK).wrapping_sub(ptr.addr());
+    let body_len = len.checked_sub(head_len)? & !(BLOCK - 1);
+    if body_len == 0 {
+        None
+    } else {
+        Some((head_len, body_len))
+    }
+}
+
+/// Zeroizer using `rep stosb`.
+pub mod rep_stosb {
+    use super::*;
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes.
+    ///
+    /// This is guarantied to be not elided by the compiler.
+    ///
+    /// # Safety
+    /// The caller *must* ensure that `ptr` is valid for writes of `len` bytes,
+    /// see the [`std::ptr`] documentation. In particular this function is
+    /// not atomic.
+    pub unsafe fn zeroize_mem(ptr: *mut u8, len: usize) {
+        precondition_memory_range!(ptr, len);
+        // SAFETY: the caller must uphold the safety contract; the direction flag is
+        // guarantied to be cleared on entry of an asm block
+        // the asm block is opaque to the compiler, so the writes can not be elided
+        unsafe {
+            core::arch::asm!(
+                "rep stosb",
+                inout("rcx") len => _,
+                inout("rdi") ptr => _,
+                in("al") 0u8,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+}
+
+/// Zeroizer using AVX2 non-temporal stores.
+pub mod avx2 {
+    use super::*;
+    use core::arch::x86_64::{__m256i, _mm_sfence, _mm256_setzero_si256, _mm256_stream_si256};
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes.
+    ///
+    /// This is guarantied to be not elided by the compiler.
+    ///
+    /// # Safety
+    /// The CPU must support AVX2. The caller *must* ensure that `ptr` is valid
+    /// for writes of `len` bytes, see the [`std::ptr`] documentation. In
+    /// particular this function is not atomic.
+    pub unsafe fn zeroize_mem(ptr: *mut u8, len: usize) {
+        precondition_memory_range!(ptr, len);
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { zeroize_mem_avx2(ptr, len) }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn zeroize_mem_avx2(ptr: *mut u8, len: usize) {
+        const BLOCK: usize = core::mem::size_of::<__m256i>();
+
+        let Some((head_len, body_len)) = split_blocks::<BLOCK>(ptr, len) else {
+            // too small for a single aligned block
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { portable_zeroize_mem(ptr, len) };
+            return;
+        };
+        // SAFETY: `head_len + body_len <= len` so these point into (or one byte past)
+        // the memory region
+        let body_ptr: *mut u8 = unsafe { ptr.add(head_len) };
+        let tail_ptr: *mut u8 = unsafe { body_ptr.add(body_len) };
+
+        // SAFETY: the head lies in the memory region, which is valid for writes
+        unsafe { ptr.write_bytes(0, head_len) };
+        let zero = _mm256_setzero_si256();
+        let mut block_ptr = body_ptr.cast::<__m256i>();
+        for _i in 0..body_len / BLOCK {
+            // SAFETY: `block_ptr` is `BLOCK` byte aligned and lies in the body of the
+            // memory region, which is valid for writes
+            unsafe {
+                _mm256_stream_si256(block_ptr, zero);
+                block_ptr = block_ptr.add(1);
+            }
+        }
+        // order the non-temporal stores before subsequent stores
+        _mm_sfence();
+        // SAFETY: the tail lies in the memory region, which is valid for writes
+        unsafe { tail_ptr.write_bytes(0, len - head_len - body_len) };
+        // Optimisation barier, so the writes can not be optimised out
+        barier(ptr);
+    }
+}
+
+/// Zeroizer using AVX-512 non-temporal stores.
+pub mod avx512 {
+    use super::*;
+    use core::arch::x86_64::{__m512i, _mm_sfence, _mm512_setzero_si512, _mm512_stream_si512};
+
+    /// Zeroize the memory pointed to by `ptr` and of size `len` bytes.
+    ///
+    /// This is guarantied to be not elided by the compiler.
+    ///
+    /// # Safety
+    /// The CPU must support AVX-512F. The caller *must* ensure that `ptr` is
+    /// valid for writes of `len` bytes, see the [`std::ptr`] documentation. In
+    /// particular this function is not atomic.
+    pub unsafe fn zeroize_mem(ptr: *mut u8, len: usize) {
+        precondition_memory_range!(ptr, len);
+        // SAFETY: the caller must uphold the safety contract
+        unsafe { zeroize_mem_avx512(ptr, len) }
+    }
+
+    #[target_feature(enable = "avx512f")]
+    unsafe fn zeroize_mem_avx512(ptr: *mut u8, len: usize) {
+        const BLOCK: usize = core::mem::size_of::<__m512i>();
+
+        let Some((head_len, body_len)) = split_blocks::<BLOCK>(ptr, len) else {
+            // too small for a single aligned block
+            // SAFETY: the caller must uphold the safety contract
+            unsafe { portable_zeroize_mem(ptr, len) };
+            return;
+        };
+        // SAFETY: `head_len + body_len <= len` so these point into (or one byte past)
+        // the memory region
+        let body_ptr: *mut u8 = unsafe { ptr.add(head_len) };
+        let tail_ptr: *mut u8 = unsafe { body_ptr.add(body_len) };
+
+        // SAFETY: the head lies in the memory region, which is valid for writes
+        unsafe { ptr.write_bytes(0, head_len) };
+        let zero = _mm512_setzero_si512();
+        let mut block_ptr = body_ptr.cast::<__m512i>();
+        for _i in 0..body_len / BLOCK {
+            // SAFETY: `block_ptr` is `BLOCK` byte aligned and lies in the body of the
+            // memory region, which is valid for writes
+            unsafe {
+                _mm512_stream_si512(block_ptr.cast(), zero);
+                block_ptr = block_ptr.add(1);
+            }
+        }
+        // order the non-temporal stores before subsequent stores
+        _mm_sfence();
+        // SAFETY: the tail lies in the memory region, which is valid for writes
+        unsafe { tail_ptr.write_bytes(0, len - head_len - body_len) };
+        // Optimisation barier, so the writes can not be optimised out
+        barier(ptr);
+    }
+}
+
+/// Returns the size in bytes of the cache lines flushed by `clflush`, which is
+/// a power of two of at least 8.
+#[cfg(not(miri))]
+fn clflush_line_size() -> usize {
+    use core::arch::x86_64::__cpuid;
+
+    #[allow(unused_unsafe)]
+    // SAFETY: `cpuid` is available on all x86_64 CPUs
+    let ebx = unsafe { __cpuid(1) }.ebx;
+    // bits 8..16 contain the line size in 8 byte quadwords
+    let line_size = ((ebx >> 8) & 0xFF) as usize * 8;
+    // the line size is a power of two; be defensive about bogus values
+    if line_size.is_power_of_two() {
+        line_size
+    } else {
+        64
+    }
+}
+
+/// Returns `true` iff the running CPU supports the `clflushopt` instruction.
+#[cfg(not(miri))]
+fn has_clflushopt() -> bool {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    #[allow(unused_unsafe)]
+    // SAFETY: `cpuid` is available on all x86_64 CPUs
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 {
+        return false;
+    }
+    #[allow(unused_unsafe)]
+    // SAFETY: `cpuid` is available on all x86_64 CPUs, and leaf 7 is supported
+    let ebx = unsafe { __cpuid_count(7, 0) }.ebx;
+    ebx & (1 << 23) != 0
+}
+
+/// Cache line size in bytes for `clflush`, or-ed with [`FLUSH_CLFLUSHOPT`]
+/// when `clflushopt` is supported, or [`FLUSH_UNDETECTED`] when no detection
+/// has been performed yet.
+#[cfg(not(miri))]
+static FLUSH_INFO: AtomicUsize = AtomicUsize::new(FLUSH_UNDETECTED);
+/// Value of [`FLUSH_INFO`] before detection.
+#[cfg(not(miri))]
+const FLUSH_UNDETECTED: usize = 0;
+/// Bit of [`FLUSH_INFO`] set when `clflushopt` is supported. The line size is
+/// a power of two of at least 8, so this bit is free.
+#[cfg(not(miri))]
+const FLUSH_CLFLUSHOPT: usize = 1;
+
+/// Returns the `clflush` cache line size in bytes and whether `clflushopt` is
+/// supported. The CPU features are detected once and cached in
+/// [`FLUSH_INFO`].
+#[cfg(not(miri))]
+pub(super) fn flush_info() -> (usize, bool) {
+    let mut info = FLUSH_INFO.load(Ordering::Relaxed);
+    if info == FLUSH_UNDETECTED {
+        info = clflush_line_size();
+        if has_clflushopt() {
+            info |= FLUSH_CLFLUSHOPT;
+        }
+        FLUSH_INFO.store(info, Ordering::Relaxed);
+    }
+    (info & !FLUSH_CLFLUSHOPT, info & FLUSH_CLFLUSHOPT != 0)
+}
+
+/// Flush the cache lines containing the memory region `ptr .. ptr + len`
+/// from all CPU caches, writing them back to main memory.
+///
+/// Uses `clflushopt` followed by `sfence` when available, and `clflush`
+/// followed by `mfence` otherwise.
+///
+/// # Safety
+/// The memory region must be mapped (valid for reads).
+#[cfg(not(miri))]
+pub unsafe fn flush_cache(ptr: *const u8, len: usize) {
+    use core::arch::x86_64::{_mm_clflush, _mm_mfence, _mm_sfence};
+
+    if len == 0 {
+        return;
+    }
+    let (line_size, clflushopt) = flush_info();
+    // start at the cache line containing `ptr`
+    let mut line = ptr.map_addr(|addr| addr & !(line_size - 1));
+    // `ptr + len` doesn't wrap the address space, so neither does the subtraction
+    let last = ptr.wrapping_add(len - 1);
+    if clflushopt {
+        while line <= last {
+            // SAFETY: `line` points into a cache line overlapping the memory region,
+            // which is mapped by the safety contract
+            unsafe {
+                core::arch::asm!(
+                    "clflushopt [{0}]",
+                    in(reg) line,
+                    options(nostack, preserves_flags),
+                );
+            }
+            line = line.wrapping_add(line_size);
+        }
+        // order the flushes before subsequent stores
+        // SAFETY: `sfence` is available on all x86_64 CPUs (SSE)
+        unsafe { _mm_sfence() };
+    } else {
+        while line <= last {
+            // SAFETY: `line` points into a cache line overlapping the memory region,
+            // which is mapped by the safety contract; `clflush` is available on all
+            // x86_64 CPUs (SSE2)
+            unsafe { _mm_clflush(line) };
+            line = line.wrapping_add(line_size);
+        }
+        // SAFETY: `mfence` is available on all x86_64 CPUs (SSE2)
+        unsafe { _mm_mfence() };
+    }
+}
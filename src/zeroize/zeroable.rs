@@ -0,0 +1,151 @@
+//! Safe, typed zeroization on top of [`zeroize_mem`].
+
+use super::zeroize_mem;
+use core::mem::MaybeUninit;
+
+/// Marker trait for types for which the all zeros bit pattern is a valid
+/// value.
+///
+/// Values of such types can be safely zeroized in place, using
+/// [`zeroize_val`] and [`zeroize_slice`]. With the `derive` feature, this trait
+/// can be derived for structs and unions of which all fields are `Zeroable`.
+///
+/// # Safety
+/// The all zeros bit pattern must be a valid value of the type, and safe code
+/// must be able to use it like any other value of the type (i.e. it must
+/// satisfy the safety invariants of the type).
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            // SAFETY: the all zeros bit pattern is a valid value of the type
+            unsafe impl Zeroable for $ty {}
+        )*
+    };
+}
+
+impl_zeroable!(
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    bool,
+    char,
+    (),
+);
+
+macro_rules! impl_zeroable_option_nonzero {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            // SAFETY: `None` is represented by the all zeros bit pattern
+            unsafe impl Zeroable for Option<$ty> {}
+        )*
+    };
+}
+
+impl_zeroable_option_nonzero!(
+    core::num::NonZeroU8,
+    core::num::NonZeroU16,
+    core::num::NonZeroU32,
+    core::num::NonZeroU64,
+    core::num::NonZeroU128,
+    core::num::NonZeroUsize,
+    core::num::NonZeroI8,
+    core::num::NonZeroI16,
+    core::num::NonZeroI32,
+    core::num::NonZeroI64,
+    core::num::NonZeroI128,
+    core::num::NonZeroIsize,
+);
+
+// SAFETY: an array of all zeros is an array of all zero elements, which are
+// valid
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+// SAFETY: `MaybeUninit` doesn't have any validity requirements
+unsafe impl<T> Zeroable for MaybeUninit<T> {}
+// SAFETY: `Wrapping<T>` is `repr(transparent)` over `T`
+unsafe impl<T: Zeroable> Zeroable for core::num::Wrapping<T> {}
+// SAFETY: `PhantomData` is zero sized
+unsafe impl<T: ?Sized> Zeroable for core::marker::PhantomData<T> {}
+// SAFETY: the null pointer is a valid raw pointer
+unsafe impl<T> Zeroable for *const T {}
+// SAFETY: the null pointer is a valid raw pointer
+unsafe impl<T> Zeroable for *mut T {}
+
+/// Zeroize the value `val` in place.
+///
+/// The old value is overwritten without being dropped. This is guarantied to
+/// be not elided by the compiler.
+pub fn zeroize_val<T: Zeroable>(val: &mut T) {
+    let ptr: *mut u8 = (val as *mut T).cast();
+    // SAFETY: `ptr` is valid for writes of `size_of::<T>()` bytes since it is
+    // derived from a mutable reference; the all zeros bit pattern is a valid value
+    // of `T` since `T: Zeroable`
+    unsafe { zeroize_mem(ptr, size_of::<T>()) };
+}
+
+/// Zeroize all elements of the slice `slice` in place.
+///
+/// The old values are overwritten without being dropped. This is guarantied to
+/// be not elided by the compiler.
+pub fn zeroize_slice<T: Zeroable>(slice: &mut [T]) {
+    let len = size_of_val(slice);
+    let ptr: *mut u8 = slice.as_mut_ptr().cast();
+    // SAFETY: `ptr` is valid for writes of `len` bytes since it is derived from a
+    // mutable reference; the all zeros bit pattern is a valid value of `T` since
+    // `T: Zeroable`
+    unsafe { zeroize_mem(ptr, len) };
+}
+
+/// Zeroize the (possibly uninitialised) byte buffer `buf`.
+///
+/// This is guarantied to be not elided by the compiler.
+pub fn zeroize_uninit(buf: &mut [MaybeUninit<u8>]) {
+    zeroize_slice(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zeroize_val_array() {
+        let mut array = [0xAF_u8; 37];
+        zeroize_val(&mut array);
+        assert_eq!(array, [0; 37]);
+    }
+
+    #[test]
+    fn zeroize_val_option_nonzero() {
+        let mut val = core::num::NonZeroU32::new(37);
+        zeroize_val(&mut val);
+        assert_eq!(val, None);
+    }
+
+    #[test]
+    fn zeroize_slice_u64() {
+        let mut array = [u64::MAX; 17];
+        zeroize_slice(&mut array[3..]);
+        assert_eq!(array[..3], [u64::MAX; 3]);
+        assert_eq!(array[3..], [0; 14]);
+    }
+
+    #[test]
+    fn zeroize_uninit_buf() {
+        let mut buf = [MaybeUninit::<u8>::uninit(); 29];
+        zeroize_uninit(&mut buf);
+        // SAFETY: `buf` is zeroized, so fully initialised
+        assert!(buf.iter().all(|b| unsafe { b.assume_init() } == 0));
+    }
+}
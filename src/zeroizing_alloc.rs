@@ -8,12 +8,16 @@
 //! allocator approach also zeroizes old memory when the object is only moved
 //! in memory but not dropped. This can happen for example when resizing
 //! [`Vec`]s.
+//!
+//! Large deallocations can optionally be zeroized by discarding whole memory
+//! pages instead of writing zeros to them, see
+//! [`ZeroizeAlloc::with_page_discard`].
 
 use crate::macros::{
     debug_handleallocerror_precondition, debug_handleallocerror_precondition_valid_layout,
     precondition_memory_range,
 };
-use crate::zeroize::zeroize_mem;
+use crate::zeroize::WipeOptions;
 use alloc::alloc::handle_alloc_error;
 use allocator_api2::alloc::{AllocError, Allocator};
 use core::alloc::{GlobalAlloc, Layout};
@@ -24,17 +28,70 @@ use core::ptr::NonNull;
 ///
 /// If debug assertions are enabled, *some* of the safety requirement for using
 /// an allocator are checked.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct ZeroizeAlloc<A> {
     /// Allocator used for the actual allocations.
     backend_alloc: A,
+    /// How to wipe deallocated memory.
+    wipe: WipeOptions,
 }
 
 impl<A> ZeroizeAlloc<A> {
     /// Create a zeroizing allocator using `backend_alloc` for allocations and
     /// `zeroizer` to zeroize memory upon deallocation.
     pub const fn new(backend_alloc: A) -> Self {
-        Self { backend_alloc }
+        Self {
+            backend_alloc,
+            wipe: WipeOptions {
+                discard_pages: false,
+                flush_cache: false,
+            },
+        }
+    }
+
+    /// Enable or disable discarding of memory pages on large deallocations.
+    ///
+    /// When enabled, the whole memory pages of large deallocations are
+    /// discarded using `madvise(MADV_DONTNEED)` instead of overwritten with
+    /// zeros; the unaligned head and tail of the allocation are still zeroized
+    /// normally. The kernel guaranties discarded pages to be zero-filled on the
+    /// next access, so this is much faster for large deallocations which the
+    /// backend allocator returns to the OS anyway. This is currently only
+    /// implemented on Linux; on other platforms this setting has no effect.
+    ///
+    /// Disabled by default.
+    ///
+    /// # Safety
+    /// If `discard_pages` is `true`, the backend allocator must hand out memory
+    /// from unlocked *private anonymous* memory mappings only (like the system
+    /// allocator does). For shared or file backed mappings, discarded pages do
+    /// not read as zeros afterwards, so the memory would not be zeroized.
+    pub const unsafe fn with_page_discard(mut self, discard_pages: bool) -> Self {
+        self.wipe.discard_pages = discard_pages;
+        self
+    }
+
+    /// Enable or disable flushing of the CPU caches after zeroization.
+    ///
+    /// When enabled, deallocated memory is zeroized using
+    /// [`zeroize_mem_flush`](crate::zeroize_mem_flush), which writes the zeros
+    /// back to main memory, so the old contents do not linger in DRAM. Pages are
+    /// never discarded when this is enabled, since discarded pages are released
+    /// with their old contents still in main memory. Disabled by default.
+    pub const fn with_cache_flush(mut self, flush_cache: bool) -> Self {
+        self.wipe.flush_cache = flush_cache;
+        self
+    }
+
+    /// Securely wipe the memory region of `len` bytes pointed to by `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` must be valid for writes of `len` bytes.
+    unsafe fn zeroize(&self, ptr: *mut u8, len: usize) {
+        // SAFETY: `ptr` is valid for writes of `len` bytes by the safety contract, and
+        // if pages are discarded the memory lies in an unlocked private anonymous
+        // mapping by the safety contract of `with_page_discard`
+        unsafe { self.wipe.wipe_anonymous(ptr, len) };
     }
 }
 
@@ -76,7 +133,7 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for ZeroizeAlloc<A> {
         // and not yet deallocated SAFETY: `ptr` is at least `layout.align()`
         // byte aligned and this is a power of two
         unsafe {
-            zeroize_mem(ptr, layout.size());
+            self.zeroize(ptr, layout.size());
         }
         // SAFETY: caller must uphold the safety contract of `GlobalAlloc::dealloc`.
         unsafe { self.backend_alloc.dealloc(ptr, layout) }
@@ -131,7 +188,7 @@ unsafe impl<A: Allocator> Allocator for ZeroizeAlloc<A> {
         // SAFETY: `ptr` is at least `layout.align()` byte aligned and this is a power
         // of two
         unsafe {
-            zeroize_mem(ptr.as_ptr(), layout.size());
+            self.zeroize(ptr.as_ptr(), layout.size());
         }
         // SAFETY: caller must uphold the safety contract of `Allocator::deallocate`
         unsafe { self.backend_alloc.deallocate(ptr, layout) }
@@ -205,6 +262,54 @@ mod tests {
         // drop `allocator`
     }
 
+    /// Backend allocator which never deallocates, so tests can inspect the
+    /// memory after deallocation.
+    struct LeakingAlloc;
+
+    unsafe impl Allocator for LeakingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            System.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+    }
+
+    #[test]
+    fn vec_allocation_page_discard() {
+        // SAFETY: `LeakingAlloc` uses the system allocator, which hands out private
+        // anonymous memory for large allocations
+        let allocator = unsafe { ZeroizeAlloc::new(LeakingAlloc).with_page_discard(true) };
+
+        let mut heap_mem = Vec::<u8, _>::with_capacity_in(1 << 20, &allocator);
+        heap_mem.resize(1 << 20, 0xAF);
+        let old_ptr = heap_mem.as_ptr();
+        heap_mem.truncate(9);
+        heap_mem.shrink_to_fit();
+        assert_eq!(heap_mem.as_slice(), &[0xAF; 9]);
+        // SAFETY: the old buffer was leaked by `LeakingAlloc`, so it's still valid
+        let old = unsafe { core::slice::from_raw_parts(old_ptr, 1 << 20) };
+        assert!(old.iter().all(|&b| b == 0));
+        // SAFETY: the old buffer was allocated by `System` with this layout
+        unsafe { System.deallocate(NonNull::from(old).cast(), Layout::new::<[u8; 1 << 20]>()) };
+        let new_ptr = NonNull::from(heap_mem.as_slice()).cast();
+        drop(heap_mem);
+        // SAFETY: the new buffer was allocated by `System` with this layout, and
+        // leaked by `LeakingAlloc`
+        unsafe { System.deallocate(new_ptr, Layout::new::<[u8; 9]>()) };
+        // drop `allocator`
+    }
+
+    #[test]
+    fn vec_allocation_cache_flush() {
+        let allocator = ZeroizeAlloc::new(System).with_cache_flush(true);
+
+        let mut heap_mem = Vec::<u8, _>::with_capacity_in(9, &allocator);
+        heap_mem.extend_from_slice(&[0xAF; 300]);
+        heap_mem.shrink_to_fit();
+        // drop `heap_mem`
+        // drop `allocator`
+    }
+
     #[test]
     fn allocate_zeroed() {
         let allocator = ZeroizeAlloc::new(System);
@@ -0,0 +1,41 @@
+#![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+
+use secmem_alloc::containers::SecBox;
+use secmem_alloc::registry;
+use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+
+// the registry is process wide, so this is the only test in this binary
+#[test]
+fn emergency_wipe_registered_pages() {
+    registry::enable();
+    let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    let allocator2 = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    assert_eq!(registry::registered_count(), 2);
+    let key = SecBox::new_in([0xAF_u8; 32], &allocator);
+    let key2 = SecBox::new_in([0xAE_u8; 32], &allocator2);
+
+    // SAFETY: the contents of `key` and `key2` are not relied upon afterwards
+    let wiped = unsafe { registry::emergency_wipe_all() };
+    assert_eq!(wiped, 2);
+    assert_eq!(*key, [0; 32]);
+    assert_eq!(*key2, [0; 32]);
+
+    drop(key);
+    drop(key2);
+
+    // quarantined blocks are zero instead of poisoned after an emergency wipe
+    let allocator3 = SecStackSinglePageAlloc::new()
+        .expect("allocator creation failed")
+        .with_quarantine(2);
+    drop(SecBox::new_in([0xAD_u8; 32], &allocator3));
+    // SAFETY: no secrets are in use
+    assert_eq!(unsafe { registry::emergency_wipe_all() }, 3);
+    allocator3.flush_quarantine();
+    drop(allocator3);
+
+    drop(allocator);
+    assert_eq!(registry::registered_count(), 1);
+    drop(allocator2);
+    assert_eq!(registry::registered_count(), 0);
+    assert!(!registry::overflowed());
+}
@@ -0,0 +1,44 @@
+#![cfg(all(feature = "std", target_os = "linux", not(miri)))]
+#![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+
+use secmem_alloc::containers::SecBox;
+use secmem_alloc::locked_ref::LockedRef;
+use secmem_alloc::registry;
+use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+
+/// Returns `true` iff the memory mapping containing `addr` is locked, according
+/// to `/proc/self/smaps`.
+fn is_locked(addr: usize) -> bool {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").expect("could not read smaps");
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if let Some((start, end)) = first.split_once('-')
+            && let (Ok(start), Ok(end)) = (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            )
+        {
+            in_mapping = (start..end).contains(&addr);
+        } else if let Some(flags) = line.strip_prefix("VmFlags:")
+            && in_mapping
+        {
+            return flags.split_whitespace().any(|flag| flag == "lo");
+        }
+    }
+    panic!("mapping not found in smaps");
+}
+
+// the registry is process wide, so this is the only test in this binary
+#[test]
+fn secure_page_stays_locked() {
+    registry::enable();
+    let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    let mut key = SecBox::new_in([0xAF_u8; 32], &allocator);
+    let addr = key.as_ptr().addr();
+    assert!(is_locked(addr));
+    drop(LockedRef::new(&mut key[..]).expect("locking failed"));
+    // the page of the allocator is registered, so it was not unlocked
+    assert!(is_locked(addr));
+    assert_eq!(*key, [0; 32]);
+}
@@ -0,0 +1,87 @@
+#![cfg(all(target_os = "linux", not(miri)))]
+
+use secmem_alloc::page_provider::{OsPageProvider, PageProvider};
+
+/// Makes all new memory of the process mergeable by KSM until dropped,
+/// using `prctl(PR_SET_MEMORY_MERGE)`.
+struct MemoryMergeGuard {
+    /// The setting before enabling memory merging.
+    previous: libc::c_int,
+}
+
+impl MemoryMergeGuard {
+    /// Enable memory merging, or returns `None` if this is not supported by the
+    /// kernel (or not permitted).
+    fn enable() -> Option<Self> {
+        // SAFETY: `PR_GET_MEMORY_MERGE` takes no pointer arguments
+        let previous = unsafe { libc::prctl(libc::PR_GET_MEMORY_MERGE, 0, 0, 0, 0) };
+        // SAFETY: `PR_SET_MEMORY_MERGE` takes no pointer arguments
+        let ret = unsafe { libc::prctl(libc::PR_SET_MEMORY_MERGE, 1, 0, 0, 0) };
+        if previous < 0 || ret != 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                // kernel without KSM or `PR_SET_MEMORY_MERGE` (before Linux 6.4), or
+                // without `CAP_SYS_RESOURCE`
+                Some(libc::EINVAL | libc::EPERM) => return None,
+                _ => panic!("enabling memory merging failed: {err}"),
+            }
+        }
+        Some(Self { previous })
+    }
+}
+
+impl Drop for MemoryMergeGuard {
+    fn drop(&mut self) {
+        // SAFETY: `PR_SET_MEMORY_MERGE` takes no pointer arguments
+        let ret = unsafe { libc::prctl(libc::PR_SET_MEMORY_MERGE, self.previous, 0, 0, 0) };
+        assert_eq!(ret, 0, "restoring memory merging failed");
+    }
+}
+
+/// Returns the `VmFlags` of the memory mapping containing `addr`, from
+/// `/proc/self/smaps`.
+fn vm_flags(addr: usize) -> Vec<String> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").expect("could not read smaps");
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if let Some((start, end)) = first.split_once('-')
+            && let (Ok(start), Ok(end)) = (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            )
+        {
+            in_mapping = (start..end).contains(&addr);
+        } else if let Some(flags) = line.strip_prefix("VmFlags:")
+            && in_mapping
+        {
+            return flags.split_whitespace().map(String::from).collect();
+        }
+    }
+    panic!("mapping not found in smaps");
+}
+
+// memory merging is a process wide setting, so this is the only test in this
+// binary
+#[test]
+fn os_provider_unmergeable() {
+    let provider = OsPageProvider;
+    let protections = provider.protections();
+    assert!(protections.locked);
+    assert!(protections.unmergeable);
+    // make all new memory mergeable, so the provider has to opt out
+    let Some(_merge) = MemoryMergeGuard::enable() else {
+        // memory merging not supported
+        return;
+    };
+    let len = provider.page_size();
+    let ptr = provider.allocate(len).expect("page allocation failed");
+    // SAFETY: `ptr` is allocated with `len` bytes by `provider`
+    unsafe { provider.lock(ptr, len) }.expect("page lock failed");
+    let flags = vm_flags(ptr.as_ptr().addr());
+    // SAFETY: `ptr` is allocated with `len` bytes by `provider`
+    unsafe { provider.release(ptr, len) };
+    // locked, and not mergeable
+    assert!(flags.iter().any(|flag| flag == "lo"), "{flags:?}");
+    assert!(!flags.iter().any(|flag| flag == "mg"), "{flags:?}");
+}
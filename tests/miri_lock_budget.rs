@@ -0,0 +1,22 @@
+#![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+
+// the locked memory budget is process wide, so this is the only test in this binary
+#[cfg(feature = "testing")]
+#[cfg_attr(
+    not(miri),
+    ignore = "the locked memory budget is only modelled under miri"
+)]
+#[test]
+fn lock_budget_exceeded() {
+    use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+    use secmem_alloc::testing;
+
+    // the miri shims use 4 KiB pages
+    testing::set_miri_lock_budget(Some(4096));
+    let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    assert!(SecStackSinglePageAlloc::new().is_err());
+    drop(allocator);
+    let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    drop(allocator);
+    testing::set_miri_lock_budget(None);
+}
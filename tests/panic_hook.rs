@@ -0,0 +1,35 @@
+#![cfg(feature = "std")]
+#![cfg_attr(feature = "nightly_allocator_api", feature(allocator_api))]
+
+use secmem_alloc::containers::SecBox;
+use secmem_alloc::panic_hook::PanicHook;
+use secmem_alloc::sec_alloc::SecStackSinglePageAlloc;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+// the panic hook is process wide, so this is the only test in this binary
+#[test]
+fn panic_wipes_thread_arenas() {
+    // SAFETY: the secrets are arrays of integers, which are valid when zeroized
+    unsafe {
+        PanicHook::new()
+            .wipe_all(false)
+            .wipe_thread_arenas(true)
+            .install();
+    }
+    let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+    let key = SecBox::new_in([0xAF_u8; 32], &allocator);
+
+    std::thread::spawn(|| {
+        let allocator = SecStackSinglePageAlloc::new().expect("allocator creation failed");
+        let key = SecBox::new_in([0xAE_u8; 32], &allocator);
+        let res = catch_unwind(AssertUnwindSafe(|| panic!("tampering detected")));
+        assert!(res.is_err());
+        // wiped by the panic hook
+        assert_eq!(*key, [0; 32]);
+    })
+    .join()
+    .expect("thread panicked");
+
+    // not created by the panicking thread
+    assert_eq!(*key, [0xAF; 32]);
+}
@@ -0,0 +1,56 @@
+#![cfg(feature = "derive")]
+
+use secmem_alloc::{Zeroable, zeroize_slice, zeroize_val};
+use std::marker::PhantomData;
+
+#[derive(Debug, PartialEq, Zeroable)]
+struct Key {
+    bytes: [u8; 32],
+    id: u64,
+}
+
+#[derive(Debug, PartialEq, Zeroable)]
+struct Tuple(u16, [i32; 3]);
+
+#[derive(Debug, PartialEq, Zeroable)]
+struct Generic<T, U> {
+    val: T,
+    _marker: PhantomData<U>,
+}
+
+#[derive(Debug, PartialEq, Zeroable)]
+struct Unit;
+
+#[test]
+fn zeroize_derived_struct() {
+    let mut key = Key {
+        bytes: [0xAF; 32],
+        id: 37,
+    };
+    zeroize_val(&mut key);
+    assert_eq!(
+        key,
+        Key {
+            bytes: [0; 32],
+            id: 0
+        }
+    );
+}
+
+#[test]
+fn zeroize_derived_tuple_struct_slice() {
+    let mut tuples = [Tuple(1, [2, 3, 4]), Tuple(5, [6, 7, 8])];
+    zeroize_slice(&mut tuples);
+    assert_eq!(tuples, [Tuple(0, [0; 3]), Tuple(0, [0; 3])]);
+}
+
+#[test]
+fn zeroize_derived_generic_struct() {
+    let mut val: Generic<u32, String> = Generic {
+        val: 37,
+        _marker: PhantomData,
+    };
+    zeroize_val(&mut val);
+    assert_eq!(val.val, 0);
+    zeroize_val(&mut Unit);
+}
@@ -11,6 +11,7 @@ fn box_9b() {
 }
 
 #[test]
+#[allow(clippy::manual_repeat_n)]
 fn vec_grow_shrink() {
     let mut vec = vec![1_u8; 109];
     vec.extend(std::iter::repeat(37).take(141));